@@ -0,0 +1,77 @@
+//! What the native and the wasm player can decode and what they report
+//! about a stream, shared since both name their codec features the same.
+
+use {
+    std::time::Duration,
+    symphonia::core::codecs::{CodecParameters, CodecRegistry},
+};
+
+/// File extensions this build can decode.
+pub fn supported_formats() -> Vec<&'static str> {
+    let mut formats = vec!["flac", "mp3", "wav"];
+    if cfg!(feature = "aac") {
+        formats.push("aac");
+    }
+    if cfg!(any(feature = "aac", feature = "alac")) {
+        formats.extend(["m4a", "mp4"]);
+    }
+    if cfg!(any(feature = "vorbis", feature = "opus")) {
+        formats.extend(["ogg", "oga"]);
+    }
+    if cfg!(feature = "opus") {
+        formats.push("opus");
+    }
+    formats
+}
+
+/// What is being decoded, as reported by the container and codec.
+#[derive(Clone, Copy)]
+pub struct StreamInfo {
+    pub codec: &'static str,
+    pub sample_rate: Option<u32>,
+    pub bit_depth: Option<u32>,
+    pub channels: Option<u16>,
+    // Averaged over the whole file, in bits per second.
+    pub bitrate: Option<u32>,
+    pub duration: Option<Duration>,
+}
+
+impl StreamInfo {
+    /// The codec is named after its decoder in `codecs`, the bitrate
+    /// needs the size of the file.
+    pub fn new(
+        params: &CodecParameters,
+        codecs: &CodecRegistry,
+        duration: Option<Duration>,
+        byte_len: Option<u64>,
+    ) -> Self {
+        let bitrate = match (byte_len, duration) {
+            (Some(len), Some(duration)) if !duration.is_zero() => {
+                Some((len as f64 * 8. / duration.as_secs_f64()) as u32)
+            }
+            _ => None,
+        };
+
+        Self {
+            codec: codecs
+                .get_codec(params.codec)
+                .map(|codec| codec.short_name)
+                .unwrap_or("unknown"),
+            sample_rate: params.sample_rate,
+            bit_depth: params.bits_per_sample,
+            channels: params.channels.map(|channels| channels.count() as u16),
+            bitrate,
+            duration,
+        }
+    }
+}
+
+/// The length the container declares, encoder delay and padding included.
+#[inline]
+pub fn duration(params: &CodecParameters) -> Option<Duration> {
+    params
+        .time_base
+        .zip(params.n_frames)
+        .map(|(base, frames)| base.calc_time(frames))
+        .map(|time| Duration::from_secs_f64(time.seconds as f64 + time.frac))
+}
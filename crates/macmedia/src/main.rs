@@ -1,136 +1,264 @@
 use {
+    protocol::{decode, encode, Direction, Event, Request, Response, VERSION},
     souvlaki::{
         MediaControlEvent, MediaControls, MediaMetadata, MediaPlayback, MediaPosition,
-        PlatformConfig,
+        PlatformConfig, SeekDirection,
     },
-    std::{io::stdin, thread, time::Duration},
-    winit::{
-        application::ApplicationHandler,
-        event::WindowEvent,
-        event_loop::{ActiveEventLoop, ControlFlow, EventLoop},
-        window::WindowId,
+    std::{
+        io::{stdin, stdout, BufRead, Write},
+        process, thread,
+        time::Duration,
     },
 };
 
 #[derive(Debug)]
 enum CustomEvent {
     Media(MediaControlEvent),
-    Metadata(String),
-    Playing(String),
+    Request(Request),
 }
 
 struct App {
     controls: MediaControls,
 }
 
-impl ApplicationHandler<CustomEvent> for App {
-    fn resumed(&mut self, _: &ActiveEventLoop) {}
+// The media controls of macOS only work on the main thread with a run loop.
+#[cfg(target_os = "macos")]
+impl winit::application::ApplicationHandler<CustomEvent> for App {
+    fn resumed(&mut self, _: &winit::event_loop::ActiveEventLoop) {}
 
-    fn window_event(&mut self, _: &ActiveEventLoop, _: WindowId, _: WindowEvent) {}
+    fn window_event(
+        &mut self,
+        _: &winit::event_loop::ActiveEventLoop,
+        _: winit::window::WindowId,
+        _: winit::event::WindowEvent,
+    ) {
+    }
+
+    fn user_event(&mut self, _: &winit::event_loop::ActiveEventLoop, event: CustomEvent) {
+        self.handle(event)
+    }
+}
 
-    fn user_event(&mut self, _: &ActiveEventLoop, event: CustomEvent) {
-        match event {
+impl App {
+    fn handle(&mut self, event: CustomEvent) {
+        let res = match event {
             CustomEvent::Media(event) => {
-                let type_ = match event {
-                    MediaControlEvent::Play => 0,
-                    MediaControlEvent::Pause => 1,
-                    MediaControlEvent::Toggle => 2,
-                    MediaControlEvent::Next => 3,
-                    MediaControlEvent::Previous => 4,
-                    MediaControlEvent::Stop => 5,
-                    _ => return,
+                return reply(&Response::Event {
+                    event: to_event(event),
+                })
+            }
+
+            CustomEvent::Request(Request::Metadata {
+                title,
+                album,
+                artist,
+                cover_url,
+                duration,
+            }) => {
+                let duration = match duration.map(Duration::try_from_secs_f64).transpose() {
+                    Ok(duration) => duration,
+                    Err(err) => return reply(&invalid("duration", err)),
                 };
-                println!("{type_}");
+                self.controls.set_metadata(MediaMetadata {
+                    title: Some(&title),
+                    album: album.as_deref(),
+                    artist: artist.as_deref(),
+                    cover_url: cover_url.as_deref(),
+                    duration,
+                })
             }
 
-            CustomEvent::Metadata(event) => {
-                let mut metadata = MediaMetadata::default();
-
-                for string in event.split('\t') {
-                    let (key, value) = string.split_once(':').unwrap();
-                    match key {
-                        "title" => metadata.title = Some(value),
-                        "album" => metadata.album = Some(value),
-                        "artist" => metadata.artist = Some(value),
-                        "cover_url" => metadata.cover_url = Some(value),
-                        "duration" => {
-                            metadata.duration =
-                                Some(Duration::from_secs_f64(value.parse().unwrap()))
-                        }
-                        _ => (),
-                    }
-                }
+            CustomEvent::Request(Request::Playback { playing, position }) => {
+                let position = match Duration::try_from_secs_f64(position) {
+                    Ok(position) => position,
+                    Err(err) => return reply(&invalid("position", err)),
+                };
+                let progress = Some(MediaPosition(position));
+                self.controls.set_playback(match playing {
+                    true => MediaPlayback::Playing { progress },
+                    false => MediaPlayback::Paused { progress },
+                })
+            }
+
+            // Answered from the thread that applies requests, so a stuck
+            // event loop misses heartbeats too.
+            CustomEvent::Request(Request::Ping) => return reply(&Response::Pong),
 
-                self.controls.set_metadata(metadata).unwrap();
+            CustomEvent::Request(Request::Hello { .. }) => {
+                return reply(&Response::Error {
+                    message: "Unexpected hello".to_owned(),
+                })
             }
+        };
+
+        if let Err(err) = res {
+            reply(&Response::Error {
+                message: format!("{:?}", err),
+            });
+        }
+    }
+}
+
+// Values off the wire are checked while decoding, this only guards the
+// conversion.
+#[inline]
+fn invalid(name: &str, err: impl std::fmt::Display) -> Response {
+    Response::Error {
+        message: format!("Invalid {name}: {err}"),
+    }
+}
+
+fn to_event(event: MediaControlEvent) -> Event {
+    let direction = |direction: SeekDirection| match direction {
+        SeekDirection::Forward => Direction::Forward,
+        SeekDirection::Backward => Direction::Backward,
+    };
+
+    match event {
+        MediaControlEvent::Play => Event::Play,
+        MediaControlEvent::Pause => Event::Pause,
+        MediaControlEvent::Toggle => Event::Toggle,
+        MediaControlEvent::Next => Event::Next,
+        MediaControlEvent::Previous => Event::Previous,
+        MediaControlEvent::Stop => Event::Stop,
+        MediaControlEvent::Seek(dir) => Event::Seek {
+            direction: direction(dir),
+        },
+        MediaControlEvent::SeekBy(dir, offset) => Event::SeekBy {
+            direction: direction(dir),
+            offset: offset.as_secs_f64(),
+        },
+        MediaControlEvent::SetPosition(MediaPosition(position)) => Event::SetPosition {
+            position: position.as_secs_f64(),
+        },
+        MediaControlEvent::SetVolume(volume) => Event::SetVolume { volume },
+        MediaControlEvent::OpenUri(uri) => Event::OpenUri { uri },
+        MediaControlEvent::Raise => Event::Raise,
+        MediaControlEvent::Quit => Event::Quit,
+    }
+}
+
+/// Writes one line to the host, which may already be gone.
+fn reply(response: &Response) {
+    if let Ok(line) = encode(response) {
+        let mut stdout = stdout().lock();
+        let _ = stdout.write_all(line.as_bytes());
+        let _ = stdout.flush();
+    }
+}
+
+// The same file as the host, so the two sides can not drift apart.
+#[path = "../../native/src/protocol.rs"]
+mod protocol;
+
+/// Checks the handshake, then hands requests to the event loop until the
+/// host closes stdin.
+fn read_requests(send: impl Fn(CustomEvent) -> bool) {
+    let mut lines = stdin().lock().lines().map_while(Result::ok);
+
+    match lines.next().map(|line| decode::<Request>(&line)) {
+        Some(Ok(Request::Hello { version: VERSION })) => {
+            reply(&Response::Hello { version: VERSION })
+        }
+        Some(Ok(Request::Hello { version })) => {
+            reply(&Response::Error {
+                message: format!("Unsupported protocol version {version}, expected {VERSION}"),
+            });
+            process::exit(1);
+        }
+        Some(_) => {
+            reply(&Response::Error {
+                message: "Expected hello".to_owned(),
+            });
+            process::exit(1);
+        }
+        None => process::exit(0),
+    }
 
-            CustomEvent::Playing(event) => {
-                let (playing, position) = event.split_once(',').unwrap();
-
-                let progress = Some(MediaPosition(Duration::from_secs_f64(
-                    position.parse().unwrap(),
-                )));
-                self.controls
-                    .set_playback(match playing.parse().unwrap() {
-                        true => MediaPlayback::Playing { progress },
-                        false => MediaPlayback::Paused { progress },
-                    })
-                    .unwrap();
+    for line in lines {
+        match decode(&line) {
+            Ok(request) => {
+                if !send(CustomEvent::Request(request)) {
+                    break;
+                }
             }
+            Err(err) => reply(&Response::Error {
+                message: format!("{err}: {line}"),
+            }),
         }
     }
+    process::exit(0);
 }
 
 const TITLE: &str = "Cloudmusic VSCode";
 
-fn main() {
-    let mut event_loop = EventLoop::<CustomEvent>::with_user_event();
-
-    #[cfg(target_os = "macos")]
-    use winit::platform::macos::{ActivationPolicy, EventLoopBuilderExtMacOS};
-    #[cfg(target_os = "macos")]
-    event_loop.with_activation_policy(ActivationPolicy::Prohibited);
+/// The host passes one unique to its process.
+fn dbus_name() -> String {
+    std::env::args()
+        .nth(1)
+        .unwrap_or_else(|| "cloudmusic-vscode".to_owned())
+}
 
-    let event_loop = event_loop.build().unwrap();
-    let mut controls = MediaControls::new(PlatformConfig {
-        dbus_name: "cloudmusic-vscode",
+fn new_controls(send: impl Fn(CustomEvent) -> bool + Send + 'static) -> MediaControls {
+    let name = dbus_name();
+    let controls = MediaControls::new(PlatformConfig {
+        dbus_name: &name,
         display_name: TITLE,
         hwnd: None,
     })
-    .unwrap();
-
-    let event_loop_proxy = event_loop.create_proxy();
-    controls
-        .attach(move |event: MediaControlEvent| {
-            event_loop_proxy
-                .send_event(CustomEvent::Media(event))
-                .unwrap();
-        })
-        .unwrap();
-
-    controls.set_playback(MediaPlayback::Stopped).unwrap();
-
-    let event_loop_proxy = event_loop.create_proxy();
-    thread::spawn(move || loop {
-        let mut buffer = String::new();
-        if stdin().read_line(&mut buffer).unwrap() != 0 {
-            buffer.remove(buffer.len() - 1);
-            let type_ = buffer.remove(buffer.len() - 1);
-            match type_ {
-                '0' => event_loop_proxy
-                    .send_event(CustomEvent::Metadata(buffer))
-                    .unwrap(),
-                '1' => event_loop_proxy
-                    .send_event(CustomEvent::Playing(buffer))
-                    .unwrap(),
-                _ => (),
-            }
-        } else {
-            break;
-        }
+    .and_then(|mut controls| {
+        controls.attach(move |event: MediaControlEvent| {
+            send(CustomEvent::Media(event));
+        })?;
+        controls.set_playback(MediaPlayback::Stopped)?;
+        Ok(controls)
     });
 
+    // The host restarts the helper after a while.
+    controls.unwrap_or_else(|err| {
+        reply(&Response::Error {
+            message: format!("Failed to create media controls: {:?}", err),
+        });
+        process::exit(1);
+    })
+}
+
+#[cfg(target_os = "macos")]
+fn main() {
+    use winit::{
+        event_loop::{ControlFlow, EventLoop},
+        platform::macos::{ActivationPolicy, EventLoopBuilderExtMacOS},
+    };
+
+    let mut event_loop = EventLoop::<CustomEvent>::with_user_event();
+    event_loop.with_activation_policy(ActivationPolicy::Prohibited);
+    let event_loop = event_loop.build().unwrap();
+
+    let proxy = event_loop.create_proxy();
+    let controls = new_controls(move |event| proxy.send_event(event).is_ok());
+
+    let proxy = event_loop.create_proxy();
+    thread::spawn(move || read_requests(move |event| proxy.send_event(event).is_ok()));
+
     event_loop.set_control_flow(ControlFlow::Wait);
     let mut app = App { controls };
     event_loop.run_app(&mut app).unwrap();
 }
+
+// D-Bus needs no window system, a channel is enough.
+#[cfg(not(target_os = "macos"))]
+fn main() {
+    use std::sync::mpsc::channel;
+
+    let (sender, receiver) = channel();
+
+    let media = sender.clone();
+    let controls = new_controls(move |event| media.send(event).is_ok());
+
+    thread::spawn(move || read_requests(move |event| sender.send(event).is_ok()));
+
+    let mut app = App { controls };
+    for event in receiver {
+        app.handle(event);
+    }
+}
@@ -0,0 +1,120 @@
+//! Picks the one instance whose media controls are shown on Linux, so
+//! media keys are not answered by every one that runs.
+//!
+//! All instances queue for the same well-known name, the bus hands it to
+//! the next one in line once the owner releases it or exits.
+
+use {
+    crate::error::lock,
+    std::{
+        sync::{
+            atomic::{AtomicU64, Ordering},
+            Arc, Mutex,
+        },
+        thread,
+    },
+    zbus::{
+        blocking::{fdo::DBusProxy, Connection},
+        fdo::RequestNameReply,
+    },
+};
+
+const NAME: &str = "org.cloudmusic.MediaKeys";
+
+type Listener = Box<dyn Fn(bool) + Send>;
+
+/// The connection and the thread watching the name, one per process, so
+/// recreated sessions do not pile them up.
+static WATCHER: Mutex<Option<Arc<Watcher>>> = Mutex::new(None);
+
+struct Watcher {
+    connection: Connection,
+    // Of the latest arbiter, with its id.
+    listener: Mutex<Option<(u64, Listener)>>,
+    ids: AtomicU64,
+}
+
+impl Watcher {
+    /// Connects again after the bus went away.
+    fn get() -> zbus::Result<Arc<Self>> {
+        let mut current = lock(&WATCHER);
+        if let Some(watcher) = current.as_ref() {
+            return Ok(watcher.clone());
+        }
+
+        let connection = Connection::session()?;
+        // Subscribed before any request, so no change is missed.
+        let changes =
+            DBusProxy::new(&connection)?.receive_name_owner_changed_with_args(&[(0, NAME)])?;
+        let unique = connection.unique_name().map(|name| name.to_string());
+        let watcher = Arc::new(Self {
+            connection,
+            listener: Mutex::new(None),
+            ids: AtomicU64::new(0),
+        });
+
+        let this = watcher.clone();
+        thread::Builder::new()
+            .name("cloudmusic-media-arbiter".to_owned())
+            .spawn(move || {
+                for signal in changes {
+                    if let Ok(args) = signal.args() {
+                        let owner = args.new_owner().as_ref().map(|name| name.to_string());
+                        if let Some((_, listener)) = lock(&this.listener).as_ref() {
+                            listener(owner.is_some() && owner == unique);
+                        }
+                    }
+                }
+
+                let mut current = lock(&WATCHER);
+                if current
+                    .as_ref()
+                    .is_some_and(|watcher| Arc::ptr_eq(watcher, &this))
+                {
+                    current.take();
+                }
+            })?;
+
+        *current = Some(watcher.clone());
+        Ok(watcher)
+    }
+}
+
+/// Gives up the name when dropped.
+pub struct Arbiter {
+    watcher: Arc<Watcher>,
+    id: u64,
+}
+
+impl Arbiter {
+    /// `on_change` is called with whether this instance is the owner, once
+    /// right away and then on every change, from another thread. It
+    /// replaces the one of any previous arbiter.
+    pub fn new(on_change: impl Fn(bool) + Send + 'static) -> zbus::Result<Self> {
+        let watcher = Watcher::get()?;
+
+        // Held while asking, so the answer is reported before any change.
+        let mut listener = lock(&watcher.listener);
+        let reply = watcher
+            .connection
+            .request_name_with_flags(NAME, Default::default())?;
+        on_change(reply == RequestNameReply::PrimaryOwner);
+
+        let id = watcher.ids.fetch_add(1, Ordering::Relaxed);
+        *listener = Some((id, Box::new(on_change)));
+        drop(listener);
+
+        Ok(Self { watcher, id })
+    }
+}
+
+impl Drop for Arbiter {
+    // A newer arbiter of this process keeps the name.
+    fn drop(&mut self) {
+        let mut listener = lock(&self.watcher.listener);
+        if listener.as_ref().is_some_and(|(id, _)| *id == self.id) {
+            listener.take();
+            let _ = self.watcher.connection.release_name(NAME);
+        }
+    }
+}
@@ -0,0 +1,463 @@
+use {
+    crate::{
+        gain::ReplayGain,
+        growing::GrowingFile,
+        http::{is_remote, HttpSource},
+    },
+    formats::StreamInfo,
+    rodio::{source::SeekError, Source},
+    std::{fs::File, path::Path, sync::OnceLock, time::Duration},
+    symphonia::{
+        core::{
+            audio::{Channels, SampleBuffer, SignalSpec},
+            codecs::{
+                CodecRegistry, Decoder, DecoderOptions, CODEC_TYPE_AAC, CODEC_TYPE_ALAC,
+                CODEC_TYPE_NULL,
+            },
+            errors::Error,
+            formats::{FormatOptions, FormatReader, SeekMode, SeekTo},
+            io::{MediaSource, MediaSourceStream},
+            meta::MetadataRevision,
+            probe::Hint,
+            units::{Time, TimeBase},
+        },
+        default::{get_probe, register_enabled_codecs},
+    },
+};
+
+// Decode errors are not fatal, but more than 3 consecutive ones are.
+const MAX_DECODE_RETRIES: usize = 3;
+
+/// The codecs of symphonia, plus Opus from libopus.
+fn codecs() -> &'static CodecRegistry {
+    static CODECS: OnceLock<CodecRegistry> = OnceLock::new();
+    CODECS.get_or_init(|| {
+        let mut codecs = CodecRegistry::new();
+        register_enabled_codecs(&mut codecs);
+        #[cfg(feature = "opus")]
+        codecs.register_all::<crate::opus::OpusDecoder>();
+        codecs
+    })
+}
+
+/// Encoder delay and padding that symphonia does not trim by itself, in
+/// frames.
+///
+/// MP3 (LAME/Xing), Vorbis and Opus are trimmed by the decoders when
+/// gapless mode is enabled, AAC and ALAC rely on the `iTunSMPB` tag instead.
+#[derive(Clone, Copy, Default)]
+struct Gapless {
+    delay: u64,
+    frames: Option<u64>,
+}
+
+impl Gapless {
+    /// ` 00000000 00000840 000001CA 00000000003F31F6 ...`
+    /// (reserved, delay, padding, valid frames) in hex.
+    fn from_itunsmpb(value: &str) -> Option<Self> {
+        let mut fields = value
+            .split_whitespace()
+            .skip(1)
+            .map(|f| u64::from_str_radix(f, 16).ok());
+        let delay = fields.next()??;
+        let _padding = fields.next()??;
+        let frames = fields.next()??;
+
+        Some(Self {
+            delay,
+            frames: (frames != 0).then_some(frames),
+        })
+    }
+
+    fn from_metadata(revision: &MetadataRevision) -> Option<Self> {
+        revision
+            .tags()
+            .iter()
+            .find(|tag| tag.key.to_ascii_lowercase().ends_with("itunsmpb"))
+            .and_then(|tag| Self::from_itunsmpb(&tag.value.to_string()))
+    }
+
+    /// The valid part of a packet starting at frame `ts`, relative to it.
+    #[inline]
+    fn range(&self, from: u64, ts: u64, len: u64) -> (u64, u64) {
+        let start = self.delay.max(from).saturating_sub(ts).min(len);
+        let end = match self.frames {
+            Some(frames) => (self.delay + frames).saturating_sub(ts).min(len),
+            None => len,
+        };
+        (start, end.max(start))
+    }
+
+    #[inline]
+    fn ended(&self, ts: u64) -> bool {
+        self.frames
+            .map(|frames| ts >= self.delay + frames)
+            .unwrap_or(false)
+    }
+}
+
+/// The frame at timestamp `ts`. They are only the same when the time base
+/// is `1 / rate`, which MP4 files often do not use.
+#[inline]
+fn frame_of(base: Option<TimeBase>, rate: u32, ts: u64) -> u64 {
+    match base {
+        Some(base) => {
+            let time = base.calc_time(ts);
+            ((time.seconds as f64 + time.frac) * rate as f64).round() as u64
+        }
+        None => ts,
+    }
+}
+
+#[inline]
+fn ts_of(base: Option<TimeBase>, rate: u32, frame: u64) -> u64 {
+    match base {
+        Some(base) => {
+            let seconds = frame as f64 / rate as f64;
+            base.calc_timestamp(Time::new(seconds.trunc() as u64, seconds.fract()))
+        }
+        None => frame,
+    }
+}
+
+/// The extension of `path`, without the query of URLs.
+fn hint(path: &str) -> Hint {
+    let mut hint = Hint::new();
+    let name = match is_remote(path) {
+        true => path.split(['?', '#']).next().unwrap_or(path),
+        false => path,
+    };
+    if let Some(ext) = Path::new(name).extension().and_then(|e| e.to_str()) {
+        hint.with_extension(ext);
+    }
+    hint
+}
+
+/// A `Source` that drives symphonia directly instead of going through
+/// `rodio::Decoder`, so the player has access to the format reader.
+pub struct SymphoniaSource {
+    format: Box<dyn FormatReader>,
+    decoder: Box<dyn Decoder>,
+    track_id: u32,
+    spec: SignalSpec,
+    // Of the packet timestamps, frames when there is none.
+    time_base: Option<TimeBase>,
+    buffer: Option<SampleBuffer<f32>>,
+    offset: usize,
+    end: usize,
+    gapless: Gapless,
+    // Frames before this one are dropped, used to refine seeking.
+    from: u64,
+    total_duration: Option<Duration>,
+    replay_gain: ReplayGain,
+    info: StreamInfo,
+}
+
+impl SymphoniaSource {
+    /// Opens a local file, which may still be downloading, or streams
+    /// an HTTP(S) URL.
+    pub fn open(path: &str) -> Result<Self, Error> {
+        if is_remote(path) {
+            return Self::stream(HttpSource::new(path)?, path);
+        }
+        if let Some(file) = GrowingFile::open(path) {
+            return Self::new(Box::new(file?), hint(path));
+        }
+        let file = File::open(path)?;
+        Self::new(Box::new(file), hint(path))
+    }
+
+    /// Streams `url` from a download that may already be under way.
+    #[inline]
+    pub fn stream(source: HttpSource, url: &str) -> Result<Self, Error> {
+        Self::new(Box::new(source), hint(url))
+    }
+
+    pub fn new(source: Box<dyn MediaSource>, hint: Hint) -> Result<Self, Error> {
+        let byte_len = source.byte_len();
+        let mss = MediaSourceStream::new(source, Default::default());
+        let format_opts = FormatOptions {
+            enable_gapless: true,
+            ..Default::default()
+        };
+        let mut probed = get_probe().format(&hint, mss, &format_opts, &Default::default())?;
+
+        let track = probed
+            .format
+            .tracks()
+            .iter()
+            .find(|t| t.codec_params.codec != CODEC_TYPE_NULL)
+            .ok_or(Error::Unsupported("no track with supported codec"))?;
+        let track_id = track.id;
+        let params = track.codec_params.clone();
+        let decoder = codecs().make(&params, &DecoderOptions::default())?;
+
+        let gapless = match params.codec {
+            CODEC_TYPE_AAC | CODEC_TYPE_ALAC => probed
+                .format
+                .metadata()
+                .current()
+                .and_then(Gapless::from_metadata)
+                .or_else(|| {
+                    probed
+                        .metadata
+                        .get()
+                        .and_then(|m| m.current().and_then(Gapless::from_metadata))
+                })
+                .unwrap_or_default(),
+            _ => Gapless::default(),
+        };
+
+        let mut replay_gain = probed
+            .format
+            .metadata()
+            .current()
+            .map(ReplayGain::from_metadata)
+            .unwrap_or_default();
+        if let Some(metadata) = probed.metadata.get() {
+            if let Some(revision) = metadata.current() {
+                replay_gain = replay_gain.or(ReplayGain::from_metadata(revision));
+            }
+        }
+
+        let total_duration = match (gapless.frames, params.sample_rate) {
+            (Some(frames), Some(rate)) => {
+                Some(Duration::from_secs_f64(frames as f64 / rate as f64))
+            }
+            _ => formats::duration(&params),
+        };
+
+        let info = StreamInfo::new(&params, codecs(), total_duration, byte_len);
+
+        let mut source = Self {
+            format: probed.format,
+            decoder,
+            track_id,
+            spec: SignalSpec::new(
+                params.sample_rate.unwrap_or(44100),
+                params
+                    .channels
+                    .unwrap_or(Channels::FRONT_LEFT | Channels::FRONT_RIGHT),
+            ),
+            time_base: params.time_base,
+            buffer: None,
+            offset: 0,
+            end: 0,
+            gapless,
+            from: 0,
+            total_duration,
+            replay_gain,
+            info,
+        };
+        source.refill()?;
+
+        Ok(source)
+    }
+
+    #[inline]
+    pub fn replay_gain(&self) -> ReplayGain {
+        self.replay_gain
+    }
+
+    #[inline]
+    pub fn info(&self) -> StreamInfo {
+        self.info
+    }
+
+    #[inline]
+    fn frame_of(&self, ts: u64) -> u64 {
+        frame_of(self.time_base, self.spec.rate, ts)
+    }
+
+    #[inline]
+    fn ts_of(&self, frame: u64) -> u64 {
+        ts_of(self.time_base, self.spec.rate, frame)
+    }
+
+    /// Decodes packets until there are samples to hand out.
+    fn refill(&mut self) -> Result<(), Error> {
+        let mut errors = 0;
+
+        loop {
+            let packet = self.format.next_packet()?;
+            if packet.track_id() != self.track_id {
+                continue;
+            }
+            let ts = self.frame_of(packet.ts());
+            if self.gapless.ended(ts) {
+                return Err(Error::IoError(std::io::ErrorKind::UnexpectedEof.into()));
+            }
+
+            let decoded = match self.decoder.decode(&packet) {
+                Ok(decoded) => decoded,
+                Err(Error::DecodeError(_)) if errors < MAX_DECODE_RETRIES => {
+                    errors += 1;
+                    continue;
+                }
+                Err(err) => return Err(err),
+            };
+            errors = 0;
+
+            self.spec = *decoded.spec();
+            let channels = self.spec.channels.count();
+            let frames = decoded.frames() as u64;
+            if !matches!(self.buffer, Some(ref b) if b.capacity() >= decoded.capacity() * channels)
+            {
+                self.buffer = Some(SampleBuffer::new(decoded.capacity() as u64, self.spec));
+            }
+            let buffer = self.buffer.as_mut().unwrap();
+            buffer.copy_interleaved_ref(decoded);
+
+            let (start, end) = self.gapless.range(self.from, ts, frames);
+            if start < end {
+                self.offset = start as usize * channels;
+                self.end = end as usize * channels;
+                return Ok(());
+            }
+        }
+    }
+}
+
+impl Iterator for SymphoniaSource {
+    type Item = f32;
+
+    #[inline]
+    fn next(&mut self) -> Option<f32> {
+        if self.offset >= self.end {
+            return None;
+        }
+
+        let sample = self.buffer.as_ref()?.samples()[self.offset];
+        self.offset += 1;
+        if self.offset >= self.end && self.refill().is_err() {
+            self.offset = 0;
+            self.end = 0;
+        }
+
+        Some(sample)
+    }
+}
+
+impl Source for SymphoniaSource {
+    #[inline]
+    fn current_frame_len(&self) -> Option<usize> {
+        Some(self.end - self.offset)
+    }
+
+    #[inline]
+    fn channels(&self) -> u16 {
+        self.spec.channels.count() as u16
+    }
+
+    #[inline]
+    fn sample_rate(&self) -> u32 {
+        self.spec.rate
+    }
+
+    #[inline]
+    fn total_duration(&self) -> Option<Duration> {
+        self.total_duration
+    }
+
+    fn try_seek(&mut self, pos: Duration) -> Result<(), SeekError> {
+        let pos = match self.total_duration {
+            Some(total) => pos.min(total),
+            None => pos,
+        };
+        let frame = self.gapless.delay + (pos.as_secs_f64() * self.spec.rate as f64) as u64;
+        let ts = self.ts_of(frame);
+
+        let seeked = self
+            .format
+            .seek(
+                SeekMode::Accurate,
+                SeekTo::TimeStamp {
+                    ts,
+                    track_id: self.track_id,
+                },
+            )
+            .map_err(|e| SeekError::Other(Box::new(e)))?;
+        self.decoder.reset();
+
+        self.from = self.frame_of(seeked.required_ts);
+        let res = self.refill();
+        self.from = 0;
+        if let Err(err) = res {
+            self.offset = 0;
+            self.end = 0;
+            return Err(SeekError::Other(Box::new(err)));
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn itunsmpb() {
+        let gapless =
+            Gapless::from_itunsmpb(" 00000000 00000840 000001CA 00000000003F31F6 00000000")
+                .unwrap();
+        assert_eq!(gapless.delay, 0x840);
+        assert_eq!(gapless.frames, Some(0x3F31F6));
+
+        let unknown = Gapless::from_itunsmpb(" 00000000 00000840 00000000 0000000000000000");
+        assert_eq!(unknown.unwrap().frames, None);
+        assert!(Gapless::from_itunsmpb(" 00000000 00000840").is_none());
+        assert!(Gapless::from_itunsmpb(" 00000000 zz 000001CA 00000000003F31F6").is_none());
+        assert!(Gapless::from_itunsmpb("").is_none());
+    }
+
+    #[test]
+    fn range() {
+        let gapless = Gapless {
+            delay: 2112,
+            frames: Some(10000),
+        };
+        // Inside the delay, then across its end.
+        assert_eq!(gapless.range(0, 0, 1024), (1024, 1024));
+        assert_eq!(gapless.range(0, 2048, 1024), (64, 1024));
+        assert_eq!(gapless.range(0, 4096, 1024), (0, 1024));
+        // Across the padding, then past it.
+        assert_eq!(gapless.range(0, 12000, 1024), (0, 112));
+        assert_eq!(gapless.range(0, 13000, 1024), (0, 0));
+        // A seek target after the delay wins.
+        assert_eq!(gapless.range(5000, 4096, 1024), (904, 1024));
+        assert_eq!(gapless.range(5000, 6000, 1024), (0, 1024));
+
+        let open = Gapless {
+            delay: 0,
+            frames: None,
+        };
+        assert_eq!(open.range(0, 1 << 40, 1024), (0, 1024));
+    }
+
+    #[test]
+    fn ended() {
+        let gapless = Gapless {
+            delay: 2112,
+            frames: Some(10000),
+        };
+        assert!(!gapless.ended(12111));
+        assert!(gapless.ended(12112));
+        assert!(!Gapless::default().ended(u64::MAX));
+    }
+
+    #[test]
+    fn timestamps() {
+        assert_eq!(frame_of(None, 44100, 1234), 1234);
+        assert_eq!(ts_of(None, 44100, 1234), 1234);
+
+        // MP4 often counts in 1 / 600 s.
+        let base = Some(TimeBase::new(1, 600));
+        assert_eq!(frame_of(base, 44100, 600), 44100);
+        assert_eq!(frame_of(base, 44100, 300), 22050);
+        assert_eq!(ts_of(base, 44100, 44100), 600);
+        assert_eq!(ts_of(base, 44100, 22050), 300);
+        assert_eq!(frame_of(base, 48000, 1), 80);
+        assert_eq!(ts_of(base, 44100, 66150), 900);
+    }
+}
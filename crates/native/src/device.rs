@@ -0,0 +1,209 @@
+use {
+    crate::error::lock,
+    rodio::{
+        cpal::{
+            self,
+            traits::{HostTrait, StreamTrait},
+            BuildStreamError, FromSample, SampleFormat, SizedSample, StreamConfig,
+        },
+        dynamic_mixer::{self, DynamicMixer, DynamicMixerController},
+        DeviceTrait, Sink, StreamError,
+    },
+    std::{
+        sync::{
+            atomic::{AtomicBool, Ordering},
+            Arc, Mutex, Weak,
+        },
+        thread,
+        time::Duration,
+    },
+};
+
+const POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+/// What to do when the device in use goes away.
+#[derive(Clone, Copy, Default, PartialEq)]
+pub enum Fallback {
+    /// Keep playing on the new default device.
+    #[default]
+    Migrate,
+    Pause,
+}
+
+impl From<&str> for Fallback {
+    #[inline]
+    fn from(value: &str) -> Self {
+        match value {
+            "pause" => Fallback::Pause,
+            _ => Fallback::Migrate,
+        }
+    }
+}
+
+#[inline]
+pub fn find_device(name: &str) -> Option<cpal::Device> {
+    cpal::default_host()
+        .output_devices()
+        .ok()?
+        .find(|device| matches!(device.name(), Ok(n) if n == name))
+}
+
+#[inline]
+pub fn default_name() -> Option<String> {
+    cpal::default_host()
+        .default_output_device()
+        .and_then(|device| device.name().ok())
+}
+
+/// An output stream that reports its errors, rodio only prints them.
+pub struct Output {
+    // Plays for as long as it is kept.
+    _stream: cpal::Stream,
+    mixer: Arc<DynamicMixerController<f32>>,
+}
+
+impl Output {
+    pub fn new(
+        device: &cpal::Device,
+        on_error: impl FnMut(cpal::StreamError) + Send + 'static,
+    ) -> Result<Self, StreamError> {
+        let config = device
+            .default_output_config()
+            .map_err(StreamError::DefaultStreamConfigError)?;
+        let (mixer, source) = dynamic_mixer::mixer(config.channels(), config.sample_rate().0);
+        let stream = match config.sample_format() {
+            SampleFormat::F32 => build::<f32>(device, &config.config(), source, on_error),
+            SampleFormat::I16 => build::<i16>(device, &config.config(), source, on_error),
+            SampleFormat::U16 => build::<u16>(device, &config.config(), source, on_error),
+            _ => Err(BuildStreamError::StreamConfigNotSupported),
+        }
+        .map_err(StreamError::BuildStreamError)?;
+        stream.play().map_err(StreamError::PlayStreamError)?;
+
+        Ok(Self {
+            _stream: stream,
+            mixer,
+        })
+    }
+
+    /// A sink that plays on this output.
+    #[inline]
+    pub fn sink(&self) -> Sink {
+        let (sink, queue) = Sink::new_idle();
+        self.mixer.add(queue);
+        sink
+    }
+}
+
+fn build<T>(
+    device: &cpal::Device,
+    config: &StreamConfig,
+    mut source: DynamicMixer<f32>,
+    on_error: impl FnMut(cpal::StreamError) + Send + 'static,
+) -> Result<cpal::Stream, BuildStreamError>
+where
+    T: SizedSample + FromSample<f32>,
+{
+    device.build_output_stream::<T, _, _>(
+        config,
+        move |data: &mut [T], _| {
+            for sample in data {
+                *sample = T::from_sample(source.next().unwrap_or(0.));
+            }
+        },
+        on_error,
+        None,
+    )
+}
+
+/// Opens the device named `name`, or the default one when it is gone.
+/// Also returns the name of the device that was opened. Stream errors
+/// mark `watch` lost.
+pub fn open_output(
+    name: Option<&str>,
+    watch: &Arc<DeviceWatch>,
+) -> Option<(Output, Option<String>)> {
+    if let Some(device) = name.and_then(find_device) {
+        if let Ok(output) = Output::new(&device, watch.on_error()) {
+            return Some((output, name.map(str::to_owned)));
+        }
+    }
+
+    let device = cpal::default_host().default_output_device()?;
+    let output = Output::new(&device, watch.on_error()).ok()?;
+    Some((output, device.name().ok()))
+}
+
+/// Polls the output devices, and hears from the stream when it fails.
+pub struct DeviceWatch {
+    // The device the stream is opened on.
+    current: Mutex<Option<String>>,
+    // The one picked by the user, default device changes are ignored
+    // while it is there and it is used again once it is back.
+    chosen: Mutex<Option<String>>,
+    lost: AtomicBool,
+}
+
+impl DeviceWatch {
+    /// Nothing is watched until `set` is called.
+    pub fn new() -> Arc<Self> {
+        let watch = Arc::new(Self {
+            current: Mutex::new(None),
+            chosen: Mutex::new(None),
+            lost: AtomicBool::new(false),
+        });
+
+        let weak = Arc::downgrade(&watch);
+        thread::spawn(move || Self::run(weak));
+
+        watch
+    }
+
+    fn run(watch: Weak<Self>) {
+        loop {
+            thread::sleep(POLL_INTERVAL);
+            match watch.upgrade() {
+                Some(watch) => watch.check(),
+                None => return,
+            }
+        }
+    }
+
+    fn check(&self) {
+        let current = lock(&self.current).clone();
+        let chosen = lock(&self.chosen).clone();
+        let lost = match chosen {
+            Some(ref chosen) if current.as_ref() == Some(chosen) => find_device(chosen).is_none(),
+            // Played on the fallback, until the chosen one is plugged in again.
+            Some(ref chosen) => find_device(chosen).is_some() || default_name() != current,
+            None => default_name() != current,
+        };
+        if lost {
+            self.lost.store(true, Ordering::Relaxed);
+        }
+    }
+
+    #[inline]
+    pub fn set(&self, current: Option<String>, chosen: Option<String>) {
+        *lock(&self.current) = current;
+        *lock(&self.chosen) = chosen;
+        self.lost.store(false, Ordering::Relaxed);
+    }
+
+    /// For `Output::new`, the player reopens the stream once it failed.
+    pub fn on_error(self: &Arc<Self>) -> impl FnMut(cpal::StreamError) + Send + 'static {
+        let watch = Arc::downgrade(self);
+        move |err| {
+            eprintln!("Output stream error: {err}");
+            if let Some(watch) = watch.upgrade() {
+                watch.lost.store(true, Ordering::Relaxed);
+            }
+        }
+    }
+
+    /// Whether the stream has to be reopened, cleared once read.
+    #[inline]
+    pub fn take_lost(&self) -> bool {
+        self.lost.swap(false, Ordering::Relaxed)
+    }
+}
@@ -0,0 +1,459 @@
+use {
+    crate::{error::lock, fade::AtomicF32, prefetch::Ready},
+    rodio::{source::SeekError, Source},
+    std::{
+        f64::consts::PI,
+        sync::{
+            atomic::{AtomicU32, Ordering},
+            Arc, Mutex,
+        },
+        time::Duration,
+    },
+};
+
+// Centers of the bands used by the presets, in Hz.
+const FREQUENCIES: [f32; 10] = [
+    31., 62., 125., 250., 500., 1000., 2000., 4000., 8000., 16000.,
+];
+
+// One octave wide.
+const PRESET_Q: f32 = 1.41;
+
+/// Gains in dB for `FREQUENCIES`.
+pub const PRESETS: &[(&str, [f32; 10])] = &[
+    ("flat", [0.; 10]),
+    ("bass", [6., 5., 4., 2., 0., 0., 0., 0., 0., 0.]),
+    ("treble", [0., 0., 0., 0., 0., 1., 2., 4., 5., 6.]),
+    ("vocal", [-2., -2., -1., 1., 3., 3., 2., 1., 0., -1.]),
+    ("rock", [5., 4., 2., -1., -2., -1., 1., 3., 4., 5.]),
+    ("pop", [-1., 0., 2., 3., 4., 3., 1., 0., -1., -1.]),
+    ("jazz", [3., 2., 1., 2., -1., -1., 0., 1., 2., 3.]),
+    ("classical", [4., 3., 2., 1., -1., -1., 0., 2., 3., 4.]),
+    ("electronic", [5., 4., 1., 0., -2., 1., 0., 1., 4., 5.]),
+    ("loudness", [6., 4., 0., 0., -2., 0., -1., -4., 4., 2.]),
+];
+
+#[derive(Clone, Copy, Default, PartialEq)]
+pub enum BandKind {
+    #[default]
+    Peaking,
+    LowShelf,
+    HighShelf,
+}
+
+impl From<&str> for BandKind {
+    #[inline]
+    fn from(value: &str) -> Self {
+        match value {
+            "lowShelf" | "LSC" | "LS" => BandKind::LowShelf,
+            "highShelf" | "HSC" | "HS" => BandKind::HighShelf,
+            _ => BandKind::Peaking,
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
+pub struct Band {
+    pub kind: BandKind,
+    pub frequency: f32,
+    pub gain: f32,
+    pub q: f32,
+}
+
+impl Band {
+    /// Coefficients from the Audio EQ Cookbook.
+    fn coefficients(&self, rate: u32) -> ([f64; 3], [f64; 2]) {
+        let rate = rate as f64;
+        let frequency = (self.frequency as f64).clamp(1., rate * 0.49);
+        let q = (self.q as f64).max(0.01);
+
+        let a = 10f64.powf(self.gain as f64 / 40.);
+        let w0 = 2. * PI * frequency / rate;
+        let (sin, cos) = w0.sin_cos();
+        let alpha = sin / (2. * q);
+        let beta = 2. * a.sqrt() * alpha;
+
+        let (b, a) = match self.kind {
+            BandKind::Peaking => (
+                [1. + alpha * a, -2. * cos, 1. - alpha * a],
+                [1. + alpha / a, -2. * cos, 1. - alpha / a],
+            ),
+            BandKind::LowShelf => (
+                [
+                    a * ((a + 1.) - (a - 1.) * cos + beta),
+                    2. * a * ((a - 1.) - (a + 1.) * cos),
+                    a * ((a + 1.) - (a - 1.) * cos - beta),
+                ],
+                [
+                    (a + 1.) + (a - 1.) * cos + beta,
+                    -2. * ((a - 1.) + (a + 1.) * cos),
+                    (a + 1.) + (a - 1.) * cos - beta,
+                ],
+            ),
+            BandKind::HighShelf => (
+                [
+                    a * ((a + 1.) + (a - 1.) * cos + beta),
+                    -2. * a * ((a - 1.) + (a + 1.) * cos),
+                    a * ((a + 1.) + (a - 1.) * cos - beta),
+                ],
+                [
+                    (a + 1.) - (a - 1.) * cos + beta,
+                    2. * ((a - 1.) - (a + 1.) * cos),
+                    (a + 1.) - (a - 1.) * cos - beta,
+                ],
+            ),
+        };
+
+        (
+            [b[0] / a[0], b[1] / a[0], b[2] / a[0]],
+            [a[1] / a[0], a[2] / a[0]],
+        )
+    }
+}
+
+/// Transposed direct form II.
+pub(crate) struct Biquad {
+    b: [f64; 3],
+    a: [f64; 2],
+    z: [f64; 2],
+}
+
+impl Biquad {
+    /// `a` is normalized and without `a0`.
+    #[inline]
+    pub(crate) fn new(b: [f64; 3], a: [f64; 2]) -> Self {
+        Self { b, a, z: [0.; 2] }
+    }
+
+    #[inline]
+    pub(crate) fn process(&mut self, x: f64) -> f64 {
+        let y = self.b[0] * x + self.z[0];
+        self.z[0] = self.b[1] * x - self.a[0] * y + self.z[1];
+        self.z[1] = self.b[2] * x - self.a[1] * y;
+        y
+    }
+}
+
+/// The bands and pre-amp (dB) shared by all the tracks of a player.
+pub struct Equalizer {
+    bands: Mutex<Vec<Band>>,
+    preamp: AtomicF32,
+    version: AtomicU32,
+}
+
+impl Equalizer {
+    #[inline]
+    pub fn new() -> Arc<Self> {
+        Arc::new(Self {
+            bands: Mutex::new(Vec::new()),
+            preamp: AtomicF32::new(0.),
+            version: AtomicU32::new(0),
+        })
+    }
+
+    /// Without `preamp`, the highest boost is taken off to avoid clipping.
+    pub fn set(&self, bands: Vec<Band>, preamp: Option<f32>) {
+        let preamp = preamp.unwrap_or_else(|| -bands.iter().fold(0., |max, b| b.gain.max(max)));
+
+        // Flat bands are kept, a gain crossing 0 dB then only changes the
+        // coefficients instead of the filters.
+        *lock(&self.bands) = bands;
+        self.preamp.store(preamp);
+        self.version.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn set_preset(&self, name: &str) -> bool {
+        let gains = match PRESETS.iter().find(|(preset, _)| *preset == name) {
+            Some((_, gains)) => gains,
+            None => return false,
+        };
+        let bands = FREQUENCIES
+            .iter()
+            .zip(gains)
+            .map(|(&frequency, &gain)| Band {
+                kind: BandKind::Peaking,
+                frequency,
+                gain,
+                q: PRESET_Q,
+            })
+            .collect();
+        self.set(bands, None);
+
+        true
+    }
+
+    /// Parses an AutoEQ `ParametricEQ.txt` profile.
+    ///
+    /// ```text
+    /// Preamp: -6.2 dB
+    /// Filter 1: ON LSC Fc 105 Hz Gain 5.5 dB Q 0.70
+    /// Filter 2: ON PK Fc 2000 Hz Gain -2.1 dB Q 1.41
+    /// ```
+    pub fn set_auto_eq(&self, profile: &str) -> bool {
+        let mut preamp = None;
+        let mut bands = Vec::new();
+
+        for line in profile.lines() {
+            let (key, value) = match line.split_once(':') {
+                Some(pair) => pair,
+                None => continue,
+            };
+            let mut fields = value.split_whitespace();
+
+            if key.trim() == "Preamp" {
+                preamp = fields.next().and_then(|v| v.parse().ok());
+                continue;
+            }
+            if !key.trim_start().starts_with("Filter") || fields.next() != Some("ON") {
+                continue;
+            }
+
+            let kind = match fields.next() {
+                Some(kind @ ("PK" | "LSC" | "LS" | "HSC" | "HS")) => BandKind::from(kind),
+                _ => continue,
+            };
+            let (mut frequency, mut gain, mut q) = (None, None, None);
+            while let Some(name) = fields.next() {
+                let value = fields.next().and_then(|v| v.parse::<f32>().ok());
+                match name {
+                    "Fc" => frequency = value,
+                    "Gain" => gain = value,
+                    "Q" => q = value,
+                    _ => {}
+                }
+                // Skip the unit.
+                if matches!(name, "Fc" | "Gain") {
+                    fields.next();
+                }
+            }
+
+            if let (Some(frequency), Some(gain)) = (frequency, gain) {
+                bands.push(Band {
+                    kind,
+                    frequency,
+                    gain,
+                    q: q.unwrap_or(0.71),
+                });
+            }
+        }
+
+        if bands.is_empty() {
+            return false;
+        }
+        self.set(bands, preamp);
+
+        true
+    }
+}
+
+pub struct Equalize<I> {
+    input: I,
+    equalizer: Arc<Equalizer>,
+    // Version, channels and sample rate the filters were built for.
+    seen: Option<(u32, u16, u32)>,
+    preamp: f64,
+    // Filters of each band, one per channel.
+    filters: Vec<Vec<Biquad>>,
+    channel: usize,
+}
+
+impl<I> Equalize<I>
+where
+    I: Source<Item = f32>,
+{
+    #[inline]
+    pub fn new(input: I, equalizer: Arc<Equalizer>) -> Self {
+        Self {
+            input,
+            equalizer,
+            seen: None,
+            preamp: 1.,
+            filters: Vec::new(),
+            channel: 0,
+        }
+    }
+
+    /// Updates the coefficients in place, so the change is click free.
+    fn update(&mut self) {
+        let channels = self.input.channels();
+        let rate = self.input.sample_rate();
+        let seen = Some((
+            self.equalizer.version.load(Ordering::Relaxed),
+            channels,
+            rate,
+        ));
+        if seen == self.seen {
+            return;
+        }
+        let layout_changed = !matches!(self.seen, Some((_, c, r)) if c == channels && r == rate);
+        self.seen = seen;
+
+        let bands = lock(&self.equalizer.bands);
+        if layout_changed || bands.len() != self.filters.len() {
+            self.filters = bands
+                .iter()
+                .map(|band| {
+                    let (b, a) = band.coefficients(rate);
+                    (0..channels).map(|_| Biquad::new(b, a)).collect()
+                })
+                .collect();
+        } else {
+            for (band, filters) in bands.iter().zip(&mut self.filters) {
+                let (b, a) = band.coefficients(rate);
+                for filter in filters {
+                    filter.b = b;
+                    filter.a = a;
+                }
+            }
+        }
+        self.preamp = match bands.is_empty() {
+            true => 1.,
+            false => 10f64.powf(self.equalizer.preamp.load() as f64 / 20.),
+        };
+    }
+}
+
+impl<I> Iterator for Equalize<I>
+where
+    I: Source<Item = f32>,
+{
+    type Item = f32;
+
+    #[inline]
+    fn next(&mut self) -> Option<f32> {
+        // Only change the filters on a frame boundary.
+        if self.channel == 0 {
+            self.update();
+        }
+
+        let sample = self.input.next()?;
+        let channel = self.channel;
+        self.channel = (channel + 1) % self.input.channels().max(1) as usize;
+        if self.filters.is_empty() {
+            return Some(sample);
+        }
+
+        let mut y = sample as f64 * self.preamp;
+        for band in &mut self.filters {
+            y = band[channel].process(y);
+        }
+
+        Some(y as f32)
+    }
+
+    #[inline]
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.input.size_hint()
+    }
+}
+
+impl<I> Ready for Equalize<I>
+where
+    I: Ready,
+{
+    #[inline]
+    fn ready(&mut self) -> bool {
+        self.input.ready()
+    }
+}
+
+impl<I> Source for Equalize<I>
+where
+    I: Source<Item = f32>,
+{
+    #[inline]
+    fn current_frame_len(&self) -> Option<usize> {
+        self.input.current_frame_len()
+    }
+
+    #[inline]
+    fn channels(&self) -> u16 {
+        self.input.channels()
+    }
+
+    #[inline]
+    fn sample_rate(&self) -> u32 {
+        self.input.sample_rate()
+    }
+
+    #[inline]
+    fn total_duration(&self) -> Option<Duration> {
+        self.input.total_duration()
+    }
+
+    #[inline]
+    fn try_seek(&mut self, pos: Duration) -> Result<(), SeekError> {
+        self.input.try_seek(pos)?;
+        self.channel = 0;
+        self.filters
+            .iter_mut()
+            .flatten()
+            .for_each(|filter| filter.z = [0.; 2]);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PROFILE: &str = "\
+# Generated by AutoEQ: Preamp: -1 dB
+Preamp: -6.2 dB
+Filter 1: ON LSC Fc 105 Hz Gain 5.5 dB Q 0.70
+Filter 2: ON PK Fc 2000 Hz Gain -2.1 dB Q 1.41
+Filter 3: OFF PK Fc 3000 Hz Gain 4.0 dB Q 1.41
+Filter 4: ON HSC Fc 10000 Hz Gain 0.0 dB
+Filter 5: ON BP Fc 500 Hz Gain 3.0 dB Q 1.00
+Filter 6: ON PK Fc 250 Hz Q 2.00
+Filter 7 ON PK Fc 400 Hz Gain 1.0 dB Q 1.00
+Filter 8: ON PK Fc abc Hz Gain 1.0 dB Q 1.00
+";
+
+    #[test]
+    fn auto_eq() {
+        let equalizer = Equalizer::new();
+        assert!(equalizer.set_auto_eq(PROFILE));
+        assert_eq!(equalizer.preamp.load(), -6.2);
+
+        let bands = equalizer.bands.lock().unwrap();
+        let parsed: Vec<_> = bands.iter().map(|b| (b.frequency, b.gain, b.q)).collect();
+        assert_eq!(
+            parsed,
+            [(105., 5.5, 0.7), (2000., -2.1, 1.41), (10000., 0., 0.71)]
+        );
+        assert!(bands[0].kind == BandKind::LowShelf);
+        assert!(bands[1].kind == BandKind::Peaking);
+        assert!(bands[2].kind == BandKind::HighShelf);
+    }
+
+    #[test]
+    fn auto_eq_without_preamp() {
+        let equalizer = Equalizer::new();
+        let profile = "Filter 1: ON PK Fc 100 Hz Gain 4.5 dB Q 1.0\nFilter 2: ON PK Fc 200 Hz Gain -3 dB Q 1.0";
+        assert!(equalizer.set_auto_eq(profile));
+        assert_eq!(equalizer.preamp.load(), -4.5);
+        assert_eq!(equalizer.bands.lock().unwrap().len(), 2);
+    }
+
+    #[test]
+    fn auto_eq_rejects_garbage() {
+        let equalizer = Equalizer::new();
+        assert!(equalizer.set_preset("rock"));
+        let version = equalizer.version.load(Ordering::Relaxed);
+
+        assert!(!equalizer.set_auto_eq(""));
+        assert!(!equalizer.set_auto_eq("Preamp: -3 dB\n# Filter 1: ON PK Fc 100 Hz Gain 1 dB"));
+        assert!(!equalizer.set_auto_eq("not a profile\nFilter: ON XX Fc 1 Hz Gain 1 dB"));
+        assert_eq!(equalizer.version.load(Ordering::Relaxed), version);
+        assert_eq!(equalizer.bands.lock().unwrap().len(), FREQUENCIES.len());
+    }
+
+    #[test]
+    fn flat_bands_are_kept() {
+        let equalizer = Equalizer::new();
+        assert!(equalizer.set_preset("flat"));
+        assert_eq!(equalizer.bands.lock().unwrap().len(), FREQUENCIES.len());
+        assert_eq!(equalizer.preamp.load(), 0.);
+    }
+}
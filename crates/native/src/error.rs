@@ -0,0 +1,221 @@
+use {
+    crate::http::StatusError,
+    neon::prelude::*,
+    rodio::StreamError,
+    std::{
+        any::Any,
+        fmt, io,
+        panic::{catch_unwind, AssertUnwindSafe},
+        sync::{Condvar, Mutex, MutexGuard, PoisonError},
+    },
+    symphonia::core::errors::Error as SymphoniaError,
+};
+
+/// Thrown to JS as an `Error` with a `kind` property.
+#[derive(Clone, Debug)]
+pub enum PlayerError {
+    NotFound(String),
+    Unsupported(String),
+    Decode(String),
+    Io(String),
+    Network(String),
+    /// The server answered with an error status.
+    Http(String),
+    NoDevice,
+    Device(String),
+    InvalidArgument(String),
+    /// Another command took over before it was done.
+    Cancelled(String),
+    Panic(String),
+}
+
+impl PlayerError {
+    #[inline]
+    pub fn kind(&self) -> &'static str {
+        match self {
+            PlayerError::NotFound(_) => "NotFound",
+            PlayerError::Unsupported(_) => "Unsupported",
+            PlayerError::Decode(_) => "Decode",
+            PlayerError::Io(_) => "Io",
+            PlayerError::Network(_) => "Network",
+            PlayerError::Http(_) => "Http",
+            PlayerError::NoDevice => "NoDevice",
+            PlayerError::Device(_) => "Device",
+            PlayerError::InvalidArgument(_) => "InvalidArgument",
+            PlayerError::Cancelled(_) => "Cancelled",
+            PlayerError::Panic(_) => "Panic",
+        }
+    }
+}
+
+impl fmt::Display for PlayerError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PlayerError::NoDevice => write!(f, "No audio output device"),
+            PlayerError::NotFound(msg)
+            | PlayerError::Unsupported(msg)
+            | PlayerError::Decode(msg)
+            | PlayerError::Io(msg)
+            | PlayerError::Network(msg)
+            | PlayerError::Http(msg)
+            | PlayerError::Device(msg)
+            | PlayerError::InvalidArgument(msg)
+            | PlayerError::Cancelled(msg)
+            | PlayerError::Panic(msg) => write!(f, "{}", msg),
+        }
+    }
+}
+
+impl From<io::Error> for PlayerError {
+    fn from(err: io::Error) -> Self {
+        use io::ErrorKind::*;
+
+        if let Some(status) = err
+            .get_ref()
+            .and_then(|err| err.downcast_ref::<StatusError>())
+        {
+            return PlayerError::Http(status.to_string());
+        }
+        match err.kind() {
+            NotFound => PlayerError::NotFound(err.to_string()),
+            Unsupported => PlayerError::Unsupported(err.to_string()),
+            TimedOut | ConnectionRefused | ConnectionReset | ConnectionAborted | NotConnected => {
+                PlayerError::Network(err.to_string())
+            }
+            _ => PlayerError::Io(err.to_string()),
+        }
+    }
+}
+
+impl From<SymphoniaError> for PlayerError {
+    fn from(err: SymphoniaError) -> Self {
+        match err {
+            SymphoniaError::IoError(err) => err.into(),
+            SymphoniaError::Unsupported(msg) => PlayerError::Unsupported(msg.to_owned()),
+            err => PlayerError::Decode(err.to_string()),
+        }
+    }
+}
+
+impl From<StreamError> for PlayerError {
+    #[inline]
+    fn from(err: StreamError) -> Self {
+        PlayerError::Device(err.to_string())
+    }
+}
+
+/// Thrown to JS by the media session exports, also with a `kind` property.
+#[derive(Clone, Debug)]
+pub enum MediaError {
+    /// There are no media controls, `mediaSessionRecreate` may bring them.
+    Unavailable(String),
+    /// The bus connection or the helper is gone.
+    Disconnected(String),
+    /// The helper speaks another protocol version, recreating does not help.
+    Incompatible(String),
+    /// The system refused the request.
+    Platform(String),
+    InvalidArgument(String),
+}
+
+impl MediaError {
+    #[inline]
+    pub fn kind(&self) -> &'static str {
+        match self {
+            MediaError::Unavailable(_) => "Unavailable",
+            MediaError::Disconnected(_) => "Disconnected",
+            MediaError::Incompatible(_) => "Incompatible",
+            MediaError::Platform(_) => "Platform",
+            MediaError::InvalidArgument(_) => "InvalidArgument",
+        }
+    }
+}
+
+impl fmt::Display for MediaError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MediaError::Unavailable(msg)
+            | MediaError::Disconnected(msg)
+            | MediaError::Incompatible(msg)
+            | MediaError::Platform(msg)
+            | MediaError::InvalidArgument(msg) => write!(f, "{}", msg),
+        }
+    }
+}
+
+impl From<io::Error> for MediaError {
+    #[inline]
+    fn from(err: io::Error) -> Self {
+        MediaError::Platform(err.to_string())
+    }
+}
+
+#[cfg(target_os = "linux")]
+impl From<zbus::Error> for MediaError {
+    fn from(err: zbus::Error) -> Self {
+        match err {
+            zbus::Error::InputOutput(err) => MediaError::Disconnected(err.to_string()),
+            err => MediaError::Platform(err.to_string()),
+        }
+    }
+}
+
+#[cfg(not(any(target_os = "macos", target_os = "linux")))]
+impl From<souvlaki::Error> for MediaError {
+    #[inline]
+    fn from(err: souvlaki::Error) -> Self {
+        MediaError::Platform(format!("{:?}", err))
+    }
+}
+
+/// Locks `mutex` even when another thread panicked while holding it, what
+/// it guards is still consistent everywhere it is used.
+#[inline]
+pub fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+/// Waits on `cond` like `lock` locks.
+#[inline]
+pub fn wait<'a, T>(cond: &Condvar, guard: MutexGuard<'a, T>) -> MutexGuard<'a, T> {
+    cond.wait(guard).unwrap_or_else(PoisonError::into_inner)
+}
+
+/// Runs `f`, a panic becomes a `PlayerError::Panic`.
+pub fn guard<T>(f: impl FnOnce() -> Result<T, PlayerError>) -> Result<T, PlayerError> {
+    catch_unwind(AssertUnwindSafe(f)).unwrap_or_else(|payload| Err(panic_error(payload)))
+}
+
+fn panic_error(payload: Box<dyn Any + Send>) -> PlayerError {
+    let msg = match payload.downcast::<String>() {
+        Ok(msg) => *msg,
+        Err(payload) => match payload.downcast::<&'static str>() {
+            Ok(msg) => msg.to_string(),
+            Err(_) => "Unknown panic".to_owned(),
+        },
+    };
+    PlayerError::Panic(msg)
+}
+
+pub trait OrThrow<T> {
+    fn or_throw<'a, C: Context<'a>>(self, cx: &mut C) -> NeonResult<T>;
+}
+
+impl<T> OrThrow<T> for Result<T, PlayerError> {
+    fn or_throw<'a, C: Context<'a>>(self, cx: &mut C) -> NeonResult<T> {
+        self.or_else(|err| throw(cx, err.to_string(), err.kind()))
+    }
+}
+
+impl<T> OrThrow<T> for Result<T, MediaError> {
+    fn or_throw<'a, C: Context<'a>>(self, cx: &mut C) -> NeonResult<T> {
+        self.or_else(|err| throw(cx, err.to_string(), err.kind()))
+    }
+}
+
+fn throw<'a, C: Context<'a>, T>(cx: &mut C, message: String, kind: &str) -> NeonResult<T> {
+    let error = cx.error(message)?;
+    let kind = cx.string(kind);
+    error.set(cx, "kind", kind)?;
+    cx.throw(error)
+}
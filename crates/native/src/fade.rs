@@ -0,0 +1,405 @@
+use {
+    crate::{error::lock, prefetch::Ready},
+    rodio::{source::SeekError, Source},
+    std::{
+        f32::consts::FRAC_PI_2,
+        sync::{
+            atomic::{AtomicBool, AtomicU32, AtomicU64, AtomicU8, AtomicUsize, Ordering},
+            Arc, Mutex,
+        },
+        time::Duration,
+    },
+};
+
+#[derive(Clone, Copy, Default, PartialEq)]
+pub enum Curve {
+    #[default]
+    Linear,
+    EqualPower,
+}
+
+impl Curve {
+    /// Maps the linear fade level to a gain.
+    #[inline]
+    fn gain(self, level: f32) -> f32 {
+        match self {
+            Curve::Linear => level,
+            Curve::EqualPower => (level * FRAC_PI_2).sin(),
+        }
+    }
+}
+
+impl From<u8> for Curve {
+    #[inline]
+    fn from(value: u8) -> Self {
+        match value {
+            1 => Curve::EqualPower,
+            _ => Curve::Linear,
+        }
+    }
+}
+
+impl From<&str> for Curve {
+    #[inline]
+    fn from(value: &str) -> Self {
+        match value {
+            "equalPower" => Curve::EqualPower,
+            _ => Curve::Linear,
+        }
+    }
+}
+
+pub(crate) struct AtomicF32(AtomicU32);
+
+impl AtomicF32 {
+    #[inline]
+    pub(crate) fn new(value: f32) -> Self {
+        Self(AtomicU32::new(value.to_bits()))
+    }
+
+    #[inline]
+    pub(crate) fn load(&self) -> f32 {
+        f32::from_bits(self.0.load(Ordering::Relaxed))
+    }
+
+    #[inline]
+    pub(crate) fn store(&self, value: f32) {
+        self.0.store(value.to_bits(), Ordering::Relaxed)
+    }
+}
+
+type TailCallback = Box<dyn FnOnce(Duration) + Send>;
+
+/// A volume envelope shared by all the sources appended to one sink.
+///
+/// Sources of a sink never play at the same time, so the envelope
+/// carries on across track boundaries.
+pub struct Fader {
+    level: AtomicF32,
+    target: AtomicF32,
+    // Level units per second.
+    speed: AtomicF32,
+    curve: AtomicU8,
+    end: AtomicBool,
+    open: AtomicBool,
+    hold: AtomicBool,
+    sources: AtomicUsize,
+    tail: Mutex<Option<(Duration, Curve, TailCallback)>>,
+    has_tail: AtomicBool,
+    // Seek once faded out, then fade back in.
+    seek: Mutex<Option<(Duration, Duration)>>,
+    has_seek: AtomicBool,
+    // Seconds into the playing source as `f64` bits, NaN before it started.
+    position: AtomicU64,
+}
+
+impl Fader {
+    #[inline]
+    pub fn new(level: f32) -> Arc<Self> {
+        Arc::new(Self {
+            level: AtomicF32::new(level),
+            target: AtomicF32::new(level),
+            speed: AtomicF32::new(0.),
+            curve: AtomicU8::new(Curve::Linear as u8),
+            end: AtomicBool::new(false),
+            open: AtomicBool::new(true),
+            hold: AtomicBool::new(false),
+            sources: AtomicUsize::new(0),
+            tail: Mutex::new(None),
+            has_tail: AtomicBool::new(false),
+            seek: Mutex::new(None),
+            has_seek: AtomicBool::new(false),
+            position: AtomicU64::new(f64::NAN.to_bits()),
+        })
+    }
+
+    /// Outputs silence without consuming the sources until `open` is called.
+    #[inline]
+    pub fn gated() -> Arc<Self> {
+        let fader = Self::new(0.);
+        fader.open.store(false, Ordering::Relaxed);
+        fader
+    }
+
+    #[inline]
+    pub fn is_open(&self) -> bool {
+        self.open.load(Ordering::Relaxed)
+    }
+
+    #[inline]
+    pub fn open(&self, duration: Duration, curve: Curve) {
+        self.fade_to(1., duration, curve);
+        self.open.store(true, Ordering::Relaxed);
+    }
+
+    #[inline]
+    pub fn fade_to(&self, target: f32, duration: Duration, curve: Curve) {
+        let distance = (target - self.level.load()).abs();
+        let speed = match duration.is_zero() {
+            true => f32::INFINITY,
+            false => distance / duration.as_secs_f32(),
+        };
+        self.speed.store(speed);
+        self.curve.store(curve as u8, Ordering::Relaxed);
+        self.target.store(target);
+    }
+
+    /// Fades out, then ends the sources so the sink becomes empty.
+    #[inline]
+    pub fn fade_out(&self, duration: Duration, curve: Curve) {
+        self.clear_tail();
+        self.end.store(true, Ordering::Relaxed);
+        self.fade_to(0., duration, curve);
+    }
+
+    /// Fades out, then outputs silence without consuming the sources.
+    #[inline]
+    pub fn hold(&self, duration: Duration) {
+        self.hold.store(true, Ordering::Relaxed);
+        self.fade_to(0., duration, Curve::Linear);
+    }
+
+    #[inline]
+    pub fn release(&self, duration: Duration) {
+        if self.hold.swap(false, Ordering::Relaxed) {
+            self.fade_to(1., duration, Curve::Linear);
+        }
+    }
+
+    #[inline]
+    pub fn is_held(&self) -> bool {
+        self.hold.load(Ordering::Relaxed)
+    }
+
+    /// Fades out, seeks the current source to `pos`, then fades back in.
+    #[inline]
+    pub fn seek(&self, pos: Duration, duration: Duration) {
+        *lock(&self.seek) = Some((pos, duration));
+        self.has_seek.store(true, Ordering::Relaxed);
+        self.fade_to(0., duration, Curve::Linear);
+    }
+
+    /// Fades out the last source `duration` before it ends, `callback`
+    /// is called with the actual remaining time when that happens.
+    #[inline]
+    pub fn set_tail(&self, duration: Duration, curve: Curve, callback: TailCallback) {
+        *lock(&self.tail) = Some((duration, curve, callback));
+        self.has_tail.store(true, Ordering::Relaxed);
+    }
+
+    #[inline]
+    pub fn clear_tail(&self) {
+        self.has_tail.store(false, Ordering::Relaxed);
+        *lock(&self.tail) = None;
+    }
+
+    /// The position of the source being played, in seconds.
+    #[inline]
+    pub fn position(&self) -> Option<f64> {
+        let position = f64::from_bits(self.position.load(Ordering::Relaxed));
+        (!position.is_nan()).then_some(position)
+    }
+
+    #[inline]
+    fn next_level(&self, samples_per_sec: f32) -> f32 {
+        let level = self.level.load();
+        let target = self.target.load();
+        if level == target {
+            return level;
+        }
+
+        let step = self.speed.load() / samples_per_sec;
+        let level = match level < target {
+            true => (level + step).min(target),
+            false => (level - step).max(target),
+        };
+        self.level.store(level);
+        level
+    }
+}
+
+pub struct Fade<I> {
+    input: I,
+    fader: Arc<Fader>,
+    played: u64,
+    total: Option<u64>,
+    silent: u64,
+}
+
+impl<I> Fade<I>
+where
+    I: Source<Item = f32> + Ready,
+{
+    #[inline]
+    pub fn new(input: I, fader: Arc<Fader>) -> Self {
+        let total = input.total_duration().map(|d| {
+            (d.as_secs_f64() * input.sample_rate() as f64) as u64 * input.channels() as u64
+        });
+        fader.sources.fetch_add(1, Ordering::Relaxed);
+
+        Self {
+            input,
+            fader,
+            played: 0,
+            total,
+            silent: 0,
+        }
+    }
+
+    /// For an input that was already seeked to `pos`.
+    #[inline]
+    pub fn starting_at(mut self, pos: Duration) -> Self {
+        self.played = self.frames_at(pos);
+        self
+    }
+
+    #[inline]
+    fn frames_at(&self, pos: Duration) -> u64 {
+        (pos.as_secs_f64() * self.input.sample_rate() as f64) as u64 * self.input.channels() as u64
+    }
+
+    #[inline]
+    fn samples_per_sec(&self) -> f32 {
+        self.input.sample_rate() as f32 * self.input.channels() as f32
+    }
+
+    fn check_tail(&mut self) {
+        let total = match self.total {
+            Some(total) => total,
+            None => return,
+        };
+        let samples_per_sec = self.samples_per_sec() as f64;
+        let remaining = total.saturating_sub(self.played) as f64 / samples_per_sec;
+
+        let mut tail = lock(&self.fader.tail);
+        if !matches!(*tail, Some((duration, ..)) if remaining <= duration.as_secs_f64()) {
+            return;
+        }
+        let (_, curve, callback) = tail.take().unwrap();
+        self.fader.has_tail.store(false, Ordering::Relaxed);
+        drop(tail);
+
+        let remaining = Duration::from_secs_f64(remaining);
+        self.fader.fade_to(0., remaining, curve);
+        callback(remaining);
+    }
+
+    fn seek_pending(&mut self) {
+        self.fader.has_seek.store(false, Ordering::Relaxed);
+        let seek = lock(&self.fader.seek).take();
+        if let Some((pos, duration)) = seek {
+            let _ = self.try_seek(pos);
+            if !self.fader.is_held() {
+                self.fader.fade_to(1., duration, Curve::Linear);
+            }
+        }
+    }
+}
+
+impl<I> Drop for Fade<I> {
+    #[inline]
+    fn drop(&mut self) {
+        // The length was unknown, start the next track right away.
+        if self.fader.sources.fetch_sub(1, Ordering::Relaxed) == 1
+            && self.fader.has_tail.load(Ordering::Relaxed)
+        {
+            let tail = lock(&self.fader.tail).take();
+            if let Some((_, _, callback)) = tail {
+                callback(Duration::ZERO);
+            }
+        }
+    }
+}
+
+impl<I> Iterator for Fade<I>
+where
+    I: Source<Item = f32> + Ready,
+{
+    type Item = f32;
+
+    #[inline]
+    fn next(&mut self) -> Option<f32> {
+        // Only leave the silence on a frame boundary.
+        if !self.fader.is_open() || self.silent % self.input.channels() as u64 != 0 {
+            self.silent += 1;
+            return Some(0.);
+        }
+
+        if self.fader.has_tail.load(Ordering::Relaxed)
+            && self.fader.sources.load(Ordering::Relaxed) == 1
+        {
+            self.check_tail();
+        }
+
+        let level = self.fader.next_level(self.samples_per_sec());
+        // Only seek or hold on a frame boundary.
+        if level <= 0. {
+            if self.fader.end.load(Ordering::Relaxed) {
+                return None;
+            }
+            if self.played % self.input.channels() as u64 == 0 {
+                if self.fader.has_seek.load(Ordering::Relaxed) {
+                    self.seek_pending();
+                } else if self.fader.is_held() {
+                    self.silent += 1;
+                    return Some(0.);
+                }
+            }
+        }
+
+        // The decoder fell behind, the position waits for it as well.
+        if !self.input.ready() {
+            self.silent += 1;
+            return Some(0.);
+        }
+
+        let sample = self.input.next()?;
+        self.played += 1;
+        if self.played % self.input.channels() as u64 == 0 {
+            let position = self.played as f64 / self.samples_per_sec() as f64;
+            self.fader
+                .position
+                .store(position.to_bits(), Ordering::Relaxed);
+        }
+        let curve = Curve::from(self.fader.curve.load(Ordering::Relaxed));
+
+        Some(sample * curve.gain(level))
+    }
+
+    #[inline]
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.input.size_hint()
+    }
+}
+
+impl<I> Source for Fade<I>
+where
+    I: Source<Item = f32> + Ready,
+{
+    #[inline]
+    fn current_frame_len(&self) -> Option<usize> {
+        self.input.current_frame_len()
+    }
+
+    #[inline]
+    fn channels(&self) -> u16 {
+        self.input.channels()
+    }
+
+    #[inline]
+    fn sample_rate(&self) -> u32 {
+        self.input.sample_rate()
+    }
+
+    #[inline]
+    fn total_duration(&self) -> Option<Duration> {
+        self.input.total_duration()
+    }
+
+    #[inline]
+    fn try_seek(&mut self, pos: Duration) -> Result<(), SeekError> {
+        self.input.try_seek(pos)?;
+        self.played = self.frames_at(pos);
+        Ok(())
+    }
+}
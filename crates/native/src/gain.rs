@@ -0,0 +1,652 @@
+use {
+    crate::{decoder::SymphoniaSource, eq::Biquad, error::lock, fade::AtomicF32, prefetch::Ready},
+    rodio::{source::SeekError, Source},
+    std::{
+        collections::VecDeque,
+        f64::consts::PI,
+        sync::{
+            atomic::{AtomicU32, AtomicU8, Ordering},
+            Arc, Mutex, Weak,
+        },
+        thread,
+        time::Duration,
+    },
+    symphonia::core::meta::{MetadataRevision, StandardTagKey},
+};
+
+// ReplayGain 2.0 reference loudness, in LUFS.
+const REFERENCE: f64 = -18.;
+
+// R128 tags are relative to -23 LUFS.
+const R128_REFERENCE: f64 = -23.;
+
+// Per-sample factor of the smoothing applied when the gain changes.
+const SMOOTHING: f32 = 0.0005;
+
+#[derive(Clone, Copy, Default, PartialEq)]
+pub enum GainMode {
+    #[default]
+    Off,
+    Track,
+    Album,
+}
+
+impl From<u8> for GainMode {
+    #[inline]
+    fn from(value: u8) -> Self {
+        match value {
+            1 => GainMode::Track,
+            2 => GainMode::Album,
+            _ => GainMode::Off,
+        }
+    }
+}
+
+impl From<&str> for GainMode {
+    #[inline]
+    fn from(value: &str) -> Self {
+        match value {
+            "track" => GainMode::Track,
+            "album" => GainMode::Album,
+            _ => GainMode::Off,
+        }
+    }
+}
+
+/// Gains are in dB, peaks are linear.
+#[derive(Clone, Copy, Default)]
+pub struct ReplayGain {
+    pub track_gain: Option<f32>,
+    pub track_peak: Option<f32>,
+    pub album_gain: Option<f32>,
+    pub album_peak: Option<f32>,
+}
+
+impl ReplayGain {
+    /// `-6.54 dB`
+    #[inline]
+    fn parse_gain(value: &str) -> Option<f32> {
+        value
+            .trim()
+            .trim_end_matches(|c: char| c.is_ascii_alphabetic())
+            .trim()
+            .parse()
+            .ok()
+    }
+
+    #[inline]
+    fn parse_peak(value: &str) -> Option<f32> {
+        value.trim().parse().ok().filter(|peak: &f32| *peak > 0.)
+    }
+
+    /// A Q7.8 number, converted to the ReplayGain reference.
+    #[inline]
+    fn parse_r128(value: &str) -> Option<f32> {
+        let gain = value.trim().parse::<i16>().ok()?;
+        Some(gain as f32 / 256. + (REFERENCE - R128_REFERENCE) as f32)
+    }
+
+    pub fn from_metadata(revision: &MetadataRevision) -> Self {
+        let mut gain = Self::default();
+
+        for tag in revision.tags() {
+            let value = tag.value.to_string();
+            // `TXXX:REPLAYGAIN_TRACK_GAIN`, `----:com.apple.iTunes:replaygain_track_gain`...
+            let key = tag.key.to_ascii_uppercase();
+            let key = key.rsplit(':').next().unwrap_or_default();
+
+            match (tag.std_key, key) {
+                (Some(StandardTagKey::ReplayGainTrackGain), _) | (_, "REPLAYGAIN_TRACK_GAIN") => {
+                    gain.track_gain = Self::parse_gain(&value)
+                }
+                (Some(StandardTagKey::ReplayGainTrackPeak), _) | (_, "REPLAYGAIN_TRACK_PEAK") => {
+                    gain.track_peak = Self::parse_peak(&value)
+                }
+                (Some(StandardTagKey::ReplayGainAlbumGain), _) | (_, "REPLAYGAIN_ALBUM_GAIN") => {
+                    gain.album_gain = Self::parse_gain(&value)
+                }
+                (Some(StandardTagKey::ReplayGainAlbumPeak), _) | (_, "REPLAYGAIN_ALBUM_PEAK") => {
+                    gain.album_peak = Self::parse_peak(&value)
+                }
+                (_, "R128_TRACK_GAIN") if gain.track_gain.is_none() => {
+                    gain.track_gain = Self::parse_r128(&value)
+                }
+                (_, "R128_ALBUM_GAIN") if gain.album_gain.is_none() => {
+                    gain.album_gain = Self::parse_r128(&value)
+                }
+                _ => {}
+            }
+        }
+
+        gain
+    }
+
+    #[inline]
+    pub fn or(self, other: Self) -> Self {
+        Self {
+            track_gain: self.track_gain.or(other.track_gain),
+            track_peak: self.track_peak.or(other.track_peak),
+            album_gain: self.album_gain.or(other.album_gain),
+            album_peak: self.album_peak.or(other.album_peak),
+        }
+    }
+
+    /// The linear factor, limited by the peak so it never clips. Without
+    /// a peak it only lowers the volume. Album mode falls back to the
+    /// track gain.
+    fn factor(&self, mode: GainMode, preamp: f32) -> f32 {
+        let (gain, peak) = match mode {
+            GainMode::Off => return 1.,
+            GainMode::Album if self.album_gain.is_some() => (self.album_gain, self.album_peak),
+            _ => (self.track_gain, self.track_peak),
+        };
+        let gain = match gain {
+            Some(gain) => gain,
+            None => return 1.,
+        };
+
+        let factor = 10f32.powf((gain + preamp) / 20.);
+        match peak {
+            Some(peak) => factor.min(1. / peak),
+            None => factor.min(1.),
+        }
+    }
+}
+
+/// The mode and pre-amp (dB) shared by all the tracks of a player.
+pub struct Normalizer {
+    mode: AtomicU8,
+    preamp: AtomicF32,
+    version: AtomicU32,
+    pending: Mutex<Pending>,
+}
+
+/// Tracks without tags, measured one at a time while normalizing is on.
+#[derive(Default)]
+struct Pending {
+    tracks: VecDeque<(Weak<TrackGain>, String)>,
+    // Whether the worker runs.
+    busy: bool,
+}
+
+impl Normalizer {
+    #[inline]
+    pub fn new() -> Arc<Self> {
+        Arc::new(Self {
+            mode: AtomicU8::new(GainMode::Off as u8),
+            preamp: AtomicF32::new(0.),
+            version: AtomicU32::new(0),
+            pending: Mutex::new(Pending::default()),
+        })
+    }
+
+    #[inline]
+    pub fn mode(&self) -> GainMode {
+        GainMode::from(self.mode.load(Ordering::Relaxed))
+    }
+
+    /// Also measures the tracks loaded while it was off.
+    #[inline]
+    pub fn set(self: &Arc<Self>, mode: GainMode, preamp: f32) {
+        self.mode.store(mode as u8, Ordering::Relaxed);
+        self.preamp.store(preamp);
+        self.version.fetch_add(1, Ordering::Relaxed);
+        self.schedule();
+    }
+
+    /// Measures `track` from `path` once normalizing is on, unless it is
+    /// dropped first.
+    pub fn analyze(self: &Arc<Self>, track: &Arc<TrackGain>, path: String) {
+        let mut pending = lock(&self.pending);
+        pending.tracks.retain(|(track, _)| track.strong_count() > 0);
+        pending.tracks.push_back((Arc::downgrade(track), path));
+        drop(pending);
+
+        self.schedule();
+    }
+
+    /// Starts the worker unless it runs or has nothing to do.
+    fn schedule(self: &Arc<Self>) {
+        if self.mode() == GainMode::Off {
+            return;
+        }
+        let mut pending = lock(&self.pending);
+        if pending.busy || pending.tracks.is_empty() {
+            return;
+        }
+        pending.busy = true;
+        drop(pending);
+
+        let normalizer = self.clone();
+        let spawned = thread::Builder::new()
+            .name("cloudmusic-gain".to_owned())
+            .spawn(move || normalizer.work());
+        if spawned.is_err() {
+            lock(&self.pending).busy = false;
+        }
+    }
+
+    fn work(&self) {
+        loop {
+            let (track, path) = {
+                let mut pending = lock(&self.pending);
+                // The mode is read under the lock, so `set` can not miss
+                // a worker that is about to stop.
+                let next = match self.mode() {
+                    // Kept for when it is turned on again.
+                    GainMode::Off => None,
+                    _ => pending.tracks.pop_front(),
+                };
+                match next {
+                    Some(next) => next,
+                    None => {
+                        pending.busy = false;
+                        return;
+                    }
+                }
+            };
+
+            if track.strong_count() == 0 {
+                continue;
+            }
+            let source = match SymphoniaSource::open(&path) {
+                Ok(source) => source,
+                Err(_) => continue,
+            };
+            // Given up once the track is skipped.
+            let measured = measure(source, || track.strong_count() > 0);
+            if let (Some((gain, peak)), Some(track)) = (measured, track.upgrade()) {
+                track.measured(gain, peak);
+            }
+        }
+    }
+}
+
+/// The loudness of one track, filled in later when it has to be measured.
+pub struct TrackGain {
+    info: Mutex<ReplayGain>,
+    version: AtomicU32,
+}
+
+impl TrackGain {
+    #[inline]
+    pub fn new(info: ReplayGain) -> Arc<Self> {
+        Arc::new(Self {
+            info: Mutex::new(info),
+            version: AtomicU32::new(0),
+        })
+    }
+
+    fn measured(&self, gain: f32, peak: f32) {
+        let mut info = lock(&self.info);
+        info.track_gain = Some(gain);
+        info.track_peak = Some(peak).filter(|peak| *peak > 0.);
+        drop(info);
+        self.version.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+pub struct Gain<I> {
+    input: I,
+    track: Arc<TrackGain>,
+    normalizer: Arc<Normalizer>,
+    // Versions of `normalizer` and `track` the target was computed from.
+    seen: Option<(u32, u32)>,
+    target: f32,
+    factor: f32,
+}
+
+impl<I> Gain<I>
+where
+    I: Source<Item = f32>,
+{
+    #[inline]
+    pub fn new(input: I, track: Arc<TrackGain>, normalizer: Arc<Normalizer>) -> Self {
+        let mut gain = Self {
+            input,
+            track,
+            normalizer,
+            seen: None,
+            target: 1.,
+            factor: 1.,
+        };
+        gain.update();
+        gain.factor = gain.target;
+
+        gain
+    }
+
+    #[inline]
+    fn update(&mut self) {
+        let seen = Some((
+            self.normalizer.version.load(Ordering::Relaxed),
+            self.track.version.load(Ordering::Relaxed),
+        ));
+        if seen != self.seen {
+            self.seen = seen;
+            self.target = lock(&self.track.info)
+                .factor(self.normalizer.mode(), self.normalizer.preamp.load());
+        }
+    }
+}
+
+impl<I> Iterator for Gain<I>
+where
+    I: Source<Item = f32>,
+{
+    type Item = f32;
+
+    #[inline]
+    fn next(&mut self) -> Option<f32> {
+        self.update();
+        // Glide to the new gain instead of jumping.
+        if self.factor != self.target {
+            self.factor += (self.target - self.factor) * SMOOTHING;
+            if (self.target - self.factor).abs() < 1e-4 {
+                self.factor = self.target;
+            }
+        }
+
+        self.input.next().map(|sample| sample * self.factor)
+    }
+
+    #[inline]
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.input.size_hint()
+    }
+}
+
+impl<I> Ready for Gain<I>
+where
+    I: Ready,
+{
+    #[inline]
+    fn ready(&mut self) -> bool {
+        self.input.ready()
+    }
+}
+
+impl<I> Source for Gain<I>
+where
+    I: Source<Item = f32>,
+{
+    #[inline]
+    fn current_frame_len(&self) -> Option<usize> {
+        self.input.current_frame_len()
+    }
+
+    #[inline]
+    fn channels(&self) -> u16 {
+        self.input.channels()
+    }
+
+    #[inline]
+    fn sample_rate(&self) -> u32 {
+        self.input.sample_rate()
+    }
+
+    #[inline]
+    fn total_duration(&self) -> Option<Duration> {
+        self.input.total_duration()
+    }
+
+    #[inline]
+    fn try_seek(&mut self, pos: Duration) -> Result<(), SeekError> {
+        self.input.try_seek(pos)
+    }
+}
+
+/// The K-weighting filter of ITU-R BS.1770, for any sample rate.
+fn k_weighting(rate: u32) -> [Biquad; 2] {
+    let rate = rate as f64;
+
+    // High shelf.
+    let (f0, g, q) = (1681.974450955533, 3.999843853973347, 0.7071752369554196);
+    let k = (PI * f0 / rate).tan();
+    let vh = 10f64.powf(g / 20.);
+    let vb = vh.powf(0.4996667741545416);
+    let a0 = 1. + k / q + k * k;
+    let shelf = Biquad::new(
+        [
+            (vh + vb * k / q + k * k) / a0,
+            2. * (k * k - vh) / a0,
+            (vh - vb * k / q + k * k) / a0,
+        ],
+        [2. * (k * k - 1.) / a0, (1. - k / q + k * k) / a0],
+    );
+
+    // High pass.
+    let (f0, q) = (38.13547087602444, 0.5003270373238773);
+    let k = (PI * f0 / rate).tan();
+    let a0 = 1. + k / q + k * k;
+    let high_pass = Biquad::new(
+        [1., -2., 1.],
+        [2. * (k * k - 1.) / a0, (1. - k / q + k * k) / a0],
+    );
+
+    [shelf, high_pass]
+}
+
+/// Integrated loudness of EBU R128: 400ms blocks every 100ms, gated
+/// at -70 LUFS and then 10 LU below the ungated loudness.
+struct Meter {
+    filters: Vec<[Biquad; 2]>,
+    weights: Vec<f64>,
+    // Samples per channel in 100ms.
+    step: usize,
+    count: usize,
+    sums: Vec<f64>,
+    recent: VecDeque<f64>,
+    blocks: Vec<f64>,
+    peak: f32,
+}
+
+impl Meter {
+    fn new(channels: usize, rate: u32) -> Self {
+        // Surround channels of 5.1 are weighted, LFE is left out.
+        let weights = (0..channels)
+            .map(|c| match (channels, c) {
+                (6, 3) => 0.,
+                (6, 4 | 5) => 1.41,
+                _ => 1.,
+            })
+            .collect();
+
+        Self {
+            filters: (0..channels).map(|_| k_weighting(rate)).collect(),
+            weights,
+            step: (rate as usize / 10).max(1),
+            count: 0,
+            sums: vec![0.; channels],
+            recent: VecDeque::with_capacity(4),
+            blocks: Vec::new(),
+            peak: 0.,
+        }
+    }
+
+    fn push(&mut self, frame: &[f32]) {
+        for (c, &sample) in frame.iter().enumerate() {
+            self.peak = self.peak.max(sample.abs());
+            let [shelf, high_pass] = &mut self.filters[c];
+            let y = high_pass.process(shelf.process(sample as f64));
+            self.sums[c] += y * y;
+        }
+
+        self.count += 1;
+        if self.count < self.step {
+            return;
+        }
+
+        let energy = self
+            .sums
+            .iter()
+            .zip(&self.weights)
+            .map(|(sum, weight)| sum * weight)
+            .sum::<f64>()
+            / self.step as f64;
+        self.count = 0;
+        self.sums.iter_mut().for_each(|sum| *sum = 0.);
+
+        if self.recent.len() == 4 {
+            self.recent.pop_front();
+        }
+        self.recent.push_back(energy);
+        if self.recent.len() == 4 {
+            self.blocks.push(self.recent.iter().sum::<f64>() / 4.);
+        }
+    }
+
+    fn integrated(&self) -> Option<f64> {
+        let loudness = |energy: f64| -0.691 + 10. * energy.log10();
+        let mean = |blocks: &[f64]| blocks.iter().sum::<f64>() / blocks.len() as f64;
+
+        let blocks: Vec<_> = self
+            .blocks
+            .iter()
+            .copied()
+            .filter(|&e| loudness(e) > -70.)
+            .collect();
+        if blocks.is_empty() {
+            return None;
+        }
+
+        let threshold = loudness(mean(&blocks)) - 10.;
+        let blocks: Vec<_> = blocks
+            .into_iter()
+            .filter(|&e| loudness(e) > threshold)
+            .collect();
+
+        Some(loudness(mean(&blocks)))
+    }
+}
+
+/// The track gain (dB) and sample peak, `None` once `alive` fails.
+fn measure<I: Source<Item = f32>>(input: I, alive: impl Fn() -> bool) -> Option<(f32, f32)> {
+    // Samples between checks of `alive`.
+    const CHECK: usize = 1 << 16;
+
+    let channels = input.channels() as usize;
+    let mut meter = Meter::new(channels, input.sample_rate());
+    let mut frame = Vec::with_capacity(channels);
+
+    for (i, sample) in input.enumerate() {
+        if i % CHECK == 0 && !alive() {
+            return None;
+        }
+        frame.push(sample);
+        if frame.len() == channels {
+            meter.push(&frame);
+            frame.clear();
+        }
+    }
+
+    let loudness = meter.integrated()?;
+    Some(((REFERENCE - loudness) as f32, meter.peak))
+}
+
+#[cfg(test)]
+mod tests {
+    use {
+        super::*,
+        symphonia::core::meta::{MetadataBuilder, Tag, Value},
+    };
+
+    fn revision(tags: &[(Option<StandardTagKey>, &str, &str)]) -> MetadataRevision {
+        let mut builder = MetadataBuilder::new();
+        for &(std_key, key, value) in tags {
+            builder.add_tag(Tag::new(std_key, key, Value::String(value.to_owned())));
+        }
+        builder.metadata()
+    }
+
+    #[test]
+    fn parses_values() {
+        assert_eq!(ReplayGain::parse_gain("-6.54 dB"), Some(-6.54));
+        assert_eq!(ReplayGain::parse_gain(" +2.5dB "), Some(2.5));
+        assert_eq!(ReplayGain::parse_gain("3"), Some(3.));
+        assert_eq!(ReplayGain::parse_gain("loud"), None);
+
+        assert_eq!(ReplayGain::parse_peak("0.988525"), Some(0.988525));
+        assert_eq!(ReplayGain::parse_peak("0"), None);
+        assert_eq!(ReplayGain::parse_peak("-1"), None);
+
+        // -5 dB relative to -23 LUFS is 0 dB relative to -18 LUFS.
+        assert_eq!(ReplayGain::parse_r128("-1280"), Some(0.));
+        assert_eq!(ReplayGain::parse_r128("0"), Some(5.));
+        assert_eq!(ReplayGain::parse_r128("40000"), None);
+    }
+
+    #[test]
+    fn reads_tags() {
+        let gain = ReplayGain::from_metadata(&revision(&[
+            (None, "TXXX:REPLAYGAIN_TRACK_GAIN", "-7.00 dB"),
+            (None, "----:com.apple.iTunes:replaygain_track_peak", "0.5"),
+            (
+                Some(StandardTagKey::ReplayGainAlbumGain),
+                "ALBUM GAIN",
+                "-8 dB",
+            ),
+            (None, "R128_TRACK_GAIN", "0"),
+            (None, "R128_ALBUM_GAIN", "-1280"),
+        ]));
+        // R128 only fills in what ReplayGain tags left out.
+        assert_eq!(gain.track_gain, Some(-7.));
+        assert_eq!(gain.track_peak, Some(0.5));
+        assert_eq!(gain.album_gain, Some(-8.));
+        assert_eq!(gain.album_peak, None);
+
+        let gain = ReplayGain::from_metadata(&revision(&[(None, "R128_TRACK_GAIN", "256")]));
+        assert_eq!(gain.track_gain, Some(6.));
+    }
+
+    #[test]
+    fn factor() {
+        let gain = ReplayGain {
+            track_gain: Some(-6.),
+            track_peak: Some(0.5),
+            album_gain: Some(12.),
+            album_peak: Some(0.5),
+        };
+        assert_eq!(gain.factor(GainMode::Off, 0.), 1.);
+        assert!((gain.factor(GainMode::Track, 0.) - 0.501).abs() < 1e-3);
+        assert!((gain.factor(GainMode::Track, 6.) - 1.).abs() < 1e-3);
+        // Limited by the peak.
+        assert_eq!(gain.factor(GainMode::Album, 0.), 2.);
+
+        let track = ReplayGain {
+            album_gain: None,
+            ..gain
+        };
+        assert!((track.factor(GainMode::Album, 0.) - 0.501).abs() < 1e-3);
+
+        // Never boosted without a peak, nor changed without a gain.
+        let unknown = ReplayGain {
+            track_gain: Some(6.),
+            ..ReplayGain::default()
+        };
+        assert_eq!(unknown.factor(GainMode::Track, 0.), 1.);
+        assert_eq!(ReplayGain::default().factor(GainMode::Track, 6.), 1.);
+    }
+
+    #[test]
+    fn meter() {
+        // EBU Tech 3341 case 1: a 1 kHz sine at -23 dBFS in both channels.
+        let rate = 48000;
+        let amplitude = 10f32.powf(-23. / 20.);
+        let mut meter = Meter::new(2, rate);
+        for i in 0..rate * 20 {
+            let t = i as f32 / rate as f32;
+            let sample = amplitude * (2. * std::f32::consts::PI * 1000. * t).sin();
+            meter.push(&[sample, sample]);
+        }
+        let loudness = meter.integrated().unwrap();
+        assert!((loudness + 23.).abs() < 0.1, "{}", loudness);
+        assert!((meter.peak - amplitude).abs() < 1e-3);
+
+        let mut silent = Meter::new(2, rate);
+        for _ in 0..rate {
+            silent.push(&[0., 0.]);
+        }
+        assert!(silent.integrated().is_none());
+    }
+}
@@ -0,0 +1,259 @@
+use {
+    crate::error::{guard, lock, OrThrow},
+    neon::prelude::*,
+    std::{
+        collections::HashMap,
+        fs::File,
+        io::{self, Read, Seek, SeekFrom},
+        sync::{Arc, Condvar, Mutex, OnceLock, PoisonError},
+        time::{Duration, Instant},
+    },
+    symphonia::core::io::MediaSource,
+};
+
+// The writer does not wake us up on every write, so EOF is polled.
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+// Give up when the file stops growing without being finished.
+const STALL_TIMEOUT: Duration = Duration::from_secs(30);
+
+#[derive(Default)]
+struct State {
+    total: Option<u64>,
+    complete: bool,
+    failed: bool,
+}
+
+#[derive(Default)]
+struct Download {
+    state: Mutex<State>,
+    cond: Condvar,
+}
+
+/// Files that are still being written, by path.
+fn downloads() -> &'static Mutex<HashMap<String, Arc<Download>>> {
+    static DOWNLOADS: OnceLock<Mutex<HashMap<String, Arc<Download>>>> = OnceLock::new();
+    DOWNLOADS.get_or_init(Default::default)
+}
+
+/// Reads a file while it is being written, EOF blocks until the declared
+/// length is reached or the writer finishes.
+///
+/// The player only reads it through `Prefetch`, so that waiting happens on
+/// the decoder thread and the output plays silence meanwhile.
+pub struct GrowingFile {
+    file: File,
+    download: Arc<Download>,
+}
+
+impl GrowingFile {
+    /// `None` when `path` is not being downloaded.
+    pub fn open(path: &str) -> Option<io::Result<Self>> {
+        let download = lock(downloads()).get(path)?.clone();
+
+        Some(File::open(path).map(|file| Self { file, download }))
+    }
+
+    #[inline]
+    fn finished(&self, state: &State) -> io::Result<bool> {
+        if state.failed {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        let written = self.file.metadata()?.len();
+        Ok(state.complete || matches!(state.total, Some(total) if written >= total))
+    }
+}
+
+impl Read for GrowingFile {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let mut stalled = Instant::now();
+
+        loop {
+            let len = self.file.read(buf)?;
+            if len > 0 || buf.is_empty() {
+                return Ok(len);
+            }
+
+            let state = lock(&self.download.state);
+            if self.finished(&state)? {
+                // Written between the read and the check.
+                return self.file.read(buf);
+            }
+            if stalled.elapsed() > STALL_TIMEOUT {
+                return Err(io::ErrorKind::TimedOut.into());
+            }
+
+            let written = self.file.metadata()?.len();
+            let (_state, _) = self
+                .download
+                .cond
+                .wait_timeout(state, POLL_INTERVAL)
+                .unwrap_or_else(PoisonError::into_inner);
+            if self.file.metadata()?.len() > written {
+                stalled = Instant::now();
+            }
+        }
+    }
+}
+
+impl Seek for GrowingFile {
+    /// Seeking past the written part is allowed, reading there waits.
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let pos = match pos {
+            SeekFrom::End(offset) => {
+                let total = lock(&self.download.state).total;
+                let end = match total {
+                    Some(total) => total,
+                    None => self.file.metadata()?.len(),
+                };
+                SeekFrom::Start(end.saturating_add_signed(offset))
+            }
+            pos => pos,
+        };
+
+        self.file.seek(pos)
+    }
+}
+
+impl MediaSource for GrowingFile {
+    #[inline]
+    fn is_seekable(&self) -> bool {
+        true
+    }
+
+    #[inline]
+    fn byte_len(&self) -> Option<u64> {
+        let state = lock(&self.download.state);
+        match state.complete {
+            true => self.file.metadata().ok().map(|m| m.len()),
+            false => state.total,
+        }
+    }
+}
+
+/// Declares that `path` is being written, with its final length if known.
+pub fn download_start(mut cx: FunctionContext) -> JsResult<JsUndefined> {
+    let path = cx.argument::<JsString>(0)?.value(&mut cx);
+    let total = match cx.argument_opt(1) {
+        Some(total) => total
+            .downcast::<JsNumber, _>(&mut cx)
+            .ok()
+            .map(|total| total.value(&mut cx)),
+        None => None,
+    };
+    let total = match total {
+        // Exactly representable, so nothing is lost on the way.
+        Some(total) if total >= 0. && total.fract() == 0. && total <= (1u64 << 53) as f64 => {
+            Some(total as u64)
+        }
+        Some(total) => {
+            return cx.throw_range_error(format!("invalid total length: {total}"));
+        }
+        None => None,
+    };
+
+    let download = Download {
+        state: Mutex::new(State {
+            total,
+            ..Default::default()
+        }),
+        ..Default::default()
+    };
+    guard(|| {
+        lock(downloads()).insert(path, Arc::new(download));
+        Ok(())
+    })
+    .or_throw(&mut cx)?;
+
+    Ok(cx.undefined())
+}
+
+/// Wakes up the readers of `path`, they fail when `success` is false.
+pub fn download_finish(mut cx: FunctionContext) -> JsResult<JsUndefined> {
+    let path = cx.argument::<JsString>(0)?.value(&mut cx);
+    let success = cx.argument::<JsBoolean>(1)?.value(&mut cx);
+
+    guard(|| {
+        if let Some(download) = lock(downloads()).remove(&path) {
+            let mut state = lock(&download.state);
+            state.complete = success;
+            state.failed = !success;
+            download.cond.notify_all();
+        }
+        Ok(())
+    })
+    .or_throw(&mut cx)?;
+
+    Ok(cx.undefined())
+}
+
+#[cfg(test)]
+mod tests {
+    use {
+        super::*,
+        std::{
+            fs::{self, OpenOptions},
+            io::Write,
+            path::PathBuf,
+            thread,
+        },
+    };
+
+    /// An empty file under a name of its own, declared as being written.
+    fn start(name: &str, total: Option<u64>) -> (PathBuf, Arc<Download>) {
+        let path = std::env::temp_dir().join(format!("cloudmusic-{}-{name}", std::process::id()));
+        File::create(&path).unwrap();
+        let download = Arc::new(Download {
+            state: Mutex::new(State {
+                total,
+                ..Default::default()
+            }),
+            ..Default::default()
+        });
+        downloads()
+            .lock()
+            .unwrap()
+            .insert(path.to_str().unwrap().to_owned(), download.clone());
+        (path, download)
+    }
+
+    fn write_later(path: PathBuf, data: Vec<u8>) -> thread::JoinHandle<()> {
+        thread::spawn(move || {
+            let mut file = OpenOptions::new().append(true).open(path).unwrap();
+            for part in data.chunks(100) {
+                thread::sleep(Duration::from_millis(20));
+                file.write_all(part).unwrap();
+            }
+        })
+    }
+
+    #[test]
+    fn waits_for_the_declared_length() {
+        let (path, _) = start("declared", Some(1000));
+        let mut file = GrowingFile::open(path.to_str().unwrap()).unwrap().unwrap();
+        let data: Vec<u8> = (0..1000).map(|i| i as u8).collect();
+        let writer = write_later(path.clone(), data.clone());
+
+        let mut all = Vec::new();
+        file.read_to_end(&mut all).unwrap();
+        assert_eq!(all, data);
+        writer.join().unwrap();
+        let _ = fs::remove_file(path);
+    }
+
+    #[test]
+    fn fails_with_the_download() {
+        let (path, download) = start("failed", None);
+        let mut file = GrowingFile::open(path.to_str().unwrap()).unwrap().unwrap();
+        let writer = write_later(path.clone(), vec![1; 300]);
+        writer.join().unwrap();
+
+        download.state.lock().unwrap().failed = true;
+        download.cond.notify_all();
+        let mut all = Vec::new();
+        let err = file.read_to_end(&mut all).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+        assert_eq!(all.len(), 300);
+        let _ = fs::remove_file(path);
+    }
+}
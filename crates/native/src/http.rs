@@ -0,0 +1,563 @@
+use {
+    crate::error::{lock, wait},
+    std::{
+        collections::BTreeMap,
+        error::Error,
+        fmt,
+        io::{self, Read, Seek, SeekFrom},
+        sync::{Arc, Condvar, Mutex},
+        thread,
+        time::Duration,
+    },
+    symphonia::core::io::MediaSource,
+    ureq::{Agent, AgentBuilder},
+};
+
+const CHUNK_SIZE: u64 = 64 * 1024;
+
+// How far the download runs ahead of the reader, in chunks.
+const READ_AHEAD: u64 = 64;
+
+const TIMEOUT: Duration = Duration::from_secs(15);
+
+// Failed requests or reads in a row before giving up, each waits a little
+// longer.
+const RETRIES: u32 = 3;
+const RETRY_DELAY: Duration = Duration::from_millis(500);
+
+type Body = Box<dyn Read + Send + Sync>;
+
+#[inline]
+pub fn is_remote(url: &str) -> bool {
+    url.starts_with("http://") || url.starts_with("https://")
+}
+
+/// The server answered with an error status, carried inside an `io::Error`.
+#[derive(Debug)]
+pub struct StatusError(pub u16);
+
+impl fmt::Display for StatusError {
+    #[inline]
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "The server answered with HTTP status {}", self.0)
+    }
+}
+
+impl Error for StatusError {}
+
+/// Why the download stopped, every reader gets it.
+#[derive(Clone, Copy)]
+enum Failure {
+    Io(io::ErrorKind),
+    Status(u16),
+}
+
+impl From<&io::Error> for Failure {
+    #[inline]
+    fn from(err: &io::Error) -> Self {
+        match err
+            .get_ref()
+            .and_then(|err| err.downcast_ref::<StatusError>())
+        {
+            Some(status) => Failure::Status(status.0),
+            None => Failure::Io(err.kind()),
+        }
+    }
+}
+
+impl From<Failure> for io::Error {
+    #[inline]
+    fn from(failure: Failure) -> Self {
+        match failure {
+            Failure::Io(kind) => kind.into(),
+            Failure::Status(status) => io::Error::other(StatusError(status)),
+        }
+    }
+}
+
+/// Worth trying again: not a client error, and not something a new
+/// request cannot change.
+#[inline]
+fn transient(err: &io::Error) -> bool {
+    match Failure::from(err) {
+        Failure::Status(status) => !(400..500).contains(&status),
+        Failure::Io(kind) => !matches!(
+            kind,
+            io::ErrorKind::UnexpectedEof | io::ErrorKind::Unsupported
+        ),
+    }
+}
+
+struct State {
+    chunks: BTreeMap<u64, Vec<u8>>,
+    // The chunk the reader is waiting for or reading.
+    want: u64,
+    error: Option<Failure>,
+    // The download stops once none is left.
+    readers: usize,
+}
+
+impl State {
+    /// The first missing chunk the download should continue from.
+    #[inline]
+    fn next_missing(&self, count: u64) -> Option<u64> {
+        (self.want..count).find(|i| !self.chunks.contains_key(i))
+    }
+}
+
+struct Shared {
+    agent: Agent,
+    url: String,
+    len: u64,
+    state: Mutex<State>,
+    cond: Condvar,
+}
+
+/// Reads a file over HTTP with Range requests, ahead of the reader.
+///
+/// Downloaded chunks are kept, so seeking back is free and seeking
+/// forward past the downloaded part issues a new range.
+pub struct HttpSource {
+    shared: Arc<Shared>,
+    pos: u64,
+    seekable: bool,
+}
+
+impl HttpSource {
+    pub fn new(url: &str) -> io::Result<Self> {
+        // The body is read slowly, so only connecting and each read time out.
+        let agent = AgentBuilder::new()
+            .timeout_connect(TIMEOUT)
+            .timeout_read(TIMEOUT)
+            .build();
+        let (body, len, seekable) = request(&agent, url, 0)?;
+        let len = len.ok_or_else(|| io::Error::from(io::ErrorKind::InvalidData))?;
+
+        let shared = Arc::new(Shared {
+            agent,
+            url: url.to_owned(),
+            len,
+            state: Mutex::new(State {
+                chunks: BTreeMap::new(),
+                want: 0,
+                error: None,
+                readers: 1,
+            }),
+            cond: Condvar::new(),
+        });
+
+        let downloader = shared.clone();
+        thread::spawn(move || download(downloader, body, seekable));
+
+        Ok(Self {
+            shared,
+            pos: 0,
+            seekable,
+        })
+    }
+
+    /// Another reader of the same download, from the start. What is
+    /// already downloaded is not requested again.
+    pub fn reopen(&self) -> Self {
+        lock(&self.shared.state).readers += 1;
+        Self {
+            shared: self.shared.clone(),
+            pos: 0,
+            seekable: self.seekable,
+        }
+    }
+
+    #[inline]
+    fn chunk_count(&self) -> u64 {
+        self.shared.len.div_ceil(CHUNK_SIZE)
+    }
+}
+
+impl Drop for HttpSource {
+    #[inline]
+    fn drop(&mut self) {
+        lock(&self.shared.state).readers -= 1;
+        self.shared.cond.notify_all();
+    }
+}
+
+impl Read for HttpSource {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if buf.is_empty() || self.pos >= self.shared.len {
+            return Ok(0);
+        }
+
+        let index = self.pos / CHUNK_SIZE;
+        let mut state = lock(&self.shared.state);
+        // Without ranges the body arrives in order, from the start.
+        if !self.seekable && index > state.chunks.len() as u64 {
+            return Err(io::ErrorKind::Unsupported.into());
+        }
+        if state.want != index {
+            state.want = index;
+            self.shared.cond.notify_all();
+        }
+        while !state.chunks.contains_key(&index) {
+            if let Some(failure) = state.error {
+                return Err(failure.into());
+            }
+            state = wait(&self.shared.cond, state);
+        }
+
+        let chunk = &state.chunks[&index];
+        let offset = (self.pos - index * CHUNK_SIZE) as usize;
+        let len = buf.len().min(chunk.len() - offset);
+        buf[..len].copy_from_slice(&chunk[offset..offset + len]);
+        self.pos += len as u64;
+
+        Ok(len)
+    }
+}
+
+impl Seek for HttpSource {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let pos = match pos {
+            SeekFrom::Start(pos) => pos as i64,
+            SeekFrom::End(offset) => self.shared.len as i64 + offset,
+            SeekFrom::Current(offset) => self.pos as i64 + offset,
+        };
+        if pos < 0 {
+            return Err(io::ErrorKind::InvalidInput.into());
+        }
+
+        self.pos = pos as u64;
+        Ok(self.pos)
+    }
+}
+
+impl MediaSource for HttpSource {
+    #[inline]
+    fn is_seekable(&self) -> bool {
+        // Without ranges, seeking only works inside the downloaded part.
+        self.seekable || self.chunk_count() <= 1
+    }
+
+    #[inline]
+    fn byte_len(&self) -> Option<u64> {
+        Some(self.shared.len)
+    }
+}
+
+/// Requests the file from `start`, also returns the total length and
+/// whether the server honored the range.
+fn request(agent: &Agent, url: &str, start: u64) -> io::Result<(Body, Option<u64>, bool)> {
+    let response = agent
+        .get(url)
+        .set("Range", &format!("bytes={}-", start))
+        .call()
+        .map_err(|err| match err {
+            ureq::Error::Status(status, _) => io::Error::other(StatusError(status)),
+            err => io::Error::other(err),
+        })?;
+
+    // `bytes 0-1023/146515`
+    let (len, seekable) = match response.status() {
+        206 => (
+            response
+                .header("Content-Range")
+                .and_then(|range| range.rsplit('/').next())
+                .and_then(|len| len.trim().parse().ok()),
+            true,
+        ),
+        _ => (
+            response
+                .header("Content-Length")
+                .and_then(|len| len.trim().parse::<u64>().ok())
+                .map(|len| len + start),
+            false,
+        ),
+    };
+    if !seekable && start != 0 {
+        return Err(io::ErrorKind::Unsupported.into());
+    }
+
+    Ok((response.into_reader(), len, seekable))
+}
+
+/// Fills `buf` unless the body ends first.
+fn read_chunk(body: &mut Body, buf: &mut Vec<u8>, len: usize) -> io::Result<()> {
+    buf.clear();
+    body.by_ref().take(len as u64).read_to_end(buf)?;
+    if buf.len() < len {
+        return Err(io::ErrorKind::UnexpectedEof.into());
+    }
+    Ok(())
+}
+
+fn download(shared: Arc<Shared>, body: Body, seekable: bool) {
+    let count = shared.len.div_ceil(CHUNK_SIZE);
+    // The open response and the chunk it is at.
+    let mut current = Some((body, 0));
+    let mut failures = 0;
+
+    loop {
+        let index = {
+            let mut state = lock(&shared.state);
+            loop {
+                if state.readers == 0 {
+                    return;
+                }
+                match state.next_missing(count) {
+                    Some(index) if index < state.want + READ_AHEAD => break index,
+                    _ => state = wait(&shared.cond, state),
+                }
+            }
+        };
+
+        // Keep reading the open response when it is at the right place,
+        // a server without ranges can only be read from the start.
+        let res = match current.take() {
+            Some((body, at)) if at == index || !seekable => Ok((body, at)),
+            _ => request(&shared.agent, &shared.url, index * CHUNK_SIZE)
+                .map(|(body, ..)| (body, index)),
+        };
+        let res = res.and_then(|(mut body, at)| {
+            let len = (shared.len - at * CHUNK_SIZE).min(CHUNK_SIZE) as usize;
+            let mut buf = Vec::with_capacity(len);
+            read_chunk(&mut body, &mut buf, len).map(|_| (body, at, buf))
+        });
+        let (body, at, buf) = match res {
+            Ok(read) => read,
+            // The connection may just have timed out while waiting, or the
+            // server was briefly unavailable.
+            Err(err) if seekable && transient(&err) && failures < RETRIES => {
+                failures += 1;
+                thread::sleep(RETRY_DELAY * failures);
+                continue;
+            }
+            Err(err) => {
+                lock(&shared.state).error = Some(Failure::from(&err));
+                shared.cond.notify_all();
+                return;
+            }
+        };
+
+        failures = 0;
+        lock(&shared.state).chunks.insert(at, buf);
+        shared.cond.notify_all();
+        if at + 1 < count {
+            current = Some((body, at + 1));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use {
+        super::*,
+        std::{
+            io::{BufRead, BufReader, Write},
+            net::{TcpListener, TcpStream},
+            sync::atomic::{AtomicUsize, Ordering},
+        },
+    };
+
+    // Larger than the read-ahead, so far seeks need a new range.
+    const LARGE: usize = ((READ_AHEAD + 8) * CHUNK_SIZE) as usize + 123;
+    const SMALL: usize = (3 * CHUNK_SIZE) as usize + 45;
+
+    fn song(len: usize) -> Arc<Vec<u8>> {
+        Arc::new((0..len).map(|i| (i % 251) as u8).collect())
+    }
+
+    /// Serves `data` on every connection, honoring `Range` if `ranges` is
+    /// set. The body is cut at `end`. Also returns the request count.
+    fn serve(data: Arc<Vec<u8>>, ranges: bool, end: usize) -> (String, Arc<AtomicUsize>) {
+        serve_failing(data, ranges, end, |_| None)
+    }
+
+    /// Like `serve`, but answers with the status `fail` returns for the
+    /// request with that index.
+    fn serve_failing(
+        data: Arc<Vec<u8>>,
+        ranges: bool,
+        end: usize,
+        fail: fn(usize) -> Option<u16>,
+    ) -> (String, Arc<AtomicUsize>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/song.mp3", listener.local_addr().unwrap());
+        let requests = Arc::new(AtomicUsize::new(0));
+
+        let counter = requests.clone();
+        thread::spawn(move || {
+            for stream in listener.incoming().map_while(Result::ok) {
+                let status = fail(counter.fetch_add(1, Ordering::SeqCst));
+                let data = data.clone();
+                thread::spawn(move || respond(stream, &data, ranges, end, status));
+            }
+        });
+
+        (url, requests)
+    }
+
+    fn respond(mut stream: TcpStream, data: &[u8], ranges: bool, end: usize, status: Option<u16>) {
+        let mut start = 0;
+        let mut reader = BufReader::new(stream.try_clone().unwrap());
+        let mut line = String::new();
+        while reader.read_line(&mut line).unwrap_or(0) > 0 && line != "\r\n" {
+            let header = line.to_ascii_lowercase();
+            if let Some(range) = header.strip_prefix("range: bytes=") {
+                start = range.trim().trim_end_matches('-').parse().unwrap();
+            }
+            line.clear();
+        }
+        if let Some(status) = status {
+            let head = format!(
+                "HTTP/1.1 {status} Error\r\nContent-Length: 0\r\nConnection: close\r\n\r\n"
+            );
+            let _ = stream.write_all(head.as_bytes());
+            return;
+        }
+        if !ranges {
+            start = 0;
+        }
+
+        let mut head = match ranges {
+            true => format!(
+                "HTTP/1.1 206 Partial Content\r\nContent-Range: bytes {start}-{}/{}\r\n",
+                data.len() - 1,
+                data.len()
+            ),
+            false => "HTTP/1.1 200 OK\r\n".to_owned(),
+        };
+        head.push_str(&format!(
+            "Content-Length: {}\r\nConnection: close\r\n\r\n",
+            data.len() - start
+        ));
+
+        // The client hangs up on seeks.
+        let _ = stream.write_all(head.as_bytes());
+        let _ = stream.write_all(&data[start..end.clamp(start, data.len())]);
+    }
+
+    fn read_at(source: &mut HttpSource, pos: u64, len: usize) -> io::Result<Vec<u8>> {
+        source.seek(SeekFrom::Start(pos))?;
+        let mut buf = vec![0; len];
+        source.read_exact(&mut buf)?;
+        Ok(buf)
+    }
+
+    #[test]
+    fn ranges() {
+        let data = song(LARGE);
+        let (url, requests) = serve(data.clone(), true, LARGE);
+        let mut source = HttpSource::new(&url).unwrap();
+        assert!(source.is_seekable());
+        assert_eq!(source.byte_len(), Some(LARGE as u64));
+
+        // Past the read-ahead and across chunks.
+        let pos = (READ_AHEAD + 4) * CHUNK_SIZE + 7;
+        let len = 3 * CHUNK_SIZE as usize;
+        let buf = read_at(&mut source, pos, len).unwrap();
+        assert_eq!(buf, data[pos as usize..][..len]);
+        assert!(requests.load(Ordering::SeqCst) >= 2);
+
+        let mut rest = Vec::new();
+        source.seek(SeekFrom::End(-100)).unwrap();
+        source.read_to_end(&mut rest).unwrap();
+        assert_eq!(rest, data[LARGE - 100..]);
+    }
+
+    #[test]
+    fn seek_back_into_cache() {
+        let data = song(SMALL);
+        let (url, requests) = serve(data.clone(), true, SMALL);
+        let mut source = HttpSource::new(&url).unwrap();
+
+        let mut all = Vec::new();
+        source.read_to_end(&mut all).unwrap();
+        assert_eq!(all, *data);
+
+        let pos = CHUNK_SIZE - 10;
+        let buf = read_at(&mut source, pos, 20).unwrap();
+        assert_eq!(buf, data[pos as usize..][..20]);
+        assert_eq!(requests.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn reopen_reads_from_cache() {
+        let data = song(SMALL);
+        let (url, requests) = serve(data.clone(), true, SMALL);
+        let mut source = HttpSource::new(&url).unwrap();
+        let mut all = Vec::new();
+        source.read_to_end(&mut all).unwrap();
+
+        // Still readable after the first reader is gone.
+        let mut again = source.reopen();
+        drop(source);
+        let mut rest = Vec::new();
+        again.read_to_end(&mut rest).unwrap();
+        assert_eq!(rest, *data);
+        assert_eq!(requests.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn without_ranges() {
+        let data = song(LARGE);
+        let (url, _) = serve(data.clone(), false, LARGE);
+        let mut source = HttpSource::new(&url).unwrap();
+        assert!(!source.is_seekable());
+
+        let pos = LARGE as u64 - 10;
+        let err = read_at(&mut source, pos, 10).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::Unsupported);
+
+        // Reading in order still works.
+        let mut all = Vec::new();
+        source.seek(SeekFrom::Start(0)).unwrap();
+        source.read_to_end(&mut all).unwrap();
+        assert_eq!(all, *data);
+    }
+
+    #[test]
+    fn truncated() {
+        let data = song(SMALL);
+        let (url, _) = serve(data, true, CHUNK_SIZE as usize + 10);
+        let mut source = HttpSource::new(&url).unwrap();
+
+        let mut all = Vec::new();
+        let err = source.read_to_end(&mut all).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+        assert_eq!(all.len(), CHUNK_SIZE as usize);
+    }
+
+    #[test]
+    fn status_error() {
+        let (url, _) = serve_failing(song(SMALL), true, SMALL, |_| Some(404));
+        let err = HttpSource::new(&url).err().unwrap();
+        let status = err
+            .get_ref()
+            .and_then(|err| err.downcast_ref::<StatusError>());
+        assert_eq!(status.map(|status| status.0), Some(404));
+    }
+
+    #[test]
+    fn retries_failed_requests() {
+        let data = song(LARGE);
+        // The range request for the seek fails once.
+        let (url, requests) = serve_failing(data.clone(), true, LARGE, |i| (i == 1).then_some(503));
+        let mut source = HttpSource::new(&url).unwrap();
+
+        let pos = (READ_AHEAD + 4) * CHUNK_SIZE;
+        let buf = read_at(&mut source, pos, 10).unwrap();
+        assert_eq!(buf, data[pos as usize..][..10]);
+        assert!(requests.load(Ordering::SeqCst) >= 3);
+    }
+
+    #[test]
+    fn client_errors_are_not_retried() {
+        let data = song(LARGE);
+        let (url, requests) = serve_failing(data, true, LARGE, |i| (i > 0).then_some(403));
+        let mut source = HttpSource::new(&url).unwrap();
+
+        let pos = (READ_AHEAD + 4) * CHUNK_SIZE;
+        let err = read_at(&mut source, pos, 10).unwrap_err();
+        assert!(err.get_ref().is_some_and(|err| err.is::<StatusError>()));
+        assert_eq!(requests.load(Ordering::SeqCst), 2);
+    }
+}
@@ -1,9 +1,26 @@
+#[cfg(target_os = "linux")]
+pub mod arbiter;
+pub mod decoder;
+pub mod device;
+pub mod eq;
+pub mod error;
+pub mod fade;
+pub mod gain;
+pub mod growing;
+pub mod http;
 pub mod media;
+pub mod meta;
+#[cfg(target_os = "linux")]
+pub mod mpris;
+#[cfg(feature = "opus")]
+pub mod opus;
 pub mod player;
+pub mod prefetch;
+pub mod protocol;
 
 // use crate::download::*;
 // use crate::keyboard::*;
-use {media::*, neon::prelude::*, player::*};
+use {growing::*, media::*, meta::*, neon::prelude::*, player::*};
 
 #[neon::main]
 fn main(mut cx: ModuleContext) -> NeonResult<()> {
@@ -11,22 +28,47 @@ fn main(mut cx: ModuleContext) -> NeonResult<()> {
 
     // cx.export_function("startKeyboardEvent", start_keyboard_event)?;
 
+    cx.export_function("downloadFinish", download_finish)?;
+    cx.export_function("downloadStart", download_start)?;
+
+    cx.export_function("readMetadata", read_metadata)?;
+
+    cx.export_function("playerDuration", player_duration)?;
     cx.export_function("playerEmpty", player_empty)?;
+    cx.export_function("playerEnqueue", player_enqueue)?;
+    cx.export_function("playerImportAutoEq", player_import_auto_eq)?;
+    cx.export_function("playerListDevices", player_list_devices)?;
     cx.export_function("playerLoad", player_load)?;
     cx.export_function("playerNew", player_new)?;
     cx.export_function("playerPause", player_pause)?;
     cx.export_function("playerPlay", player_play)?;
     cx.export_function("playerPosition", player_position)?;
+    cx.export_function("playerSetCrossfade", player_set_crossfade)?;
+    cx.export_function("playerSetDevice", player_set_device)?;
+    cx.export_function("playerSetEqualizer", player_set_equalizer)?;
+    cx.export_function("playerSetFallback", player_set_fallback)?;
+    cx.export_function("playerSetRamp", player_set_ramp)?;
+    cx.export_function("playerSetReplayGain", player_set_replay_gain)?;
     cx.export_function("playerSetSpeed", player_set_speed)?;
     cx.export_function("playerSetVolume", player_set_volume)?;
+    cx.export_function("playerState", player_state)?;
     cx.export_function("playerStop", player_stop)?;
+    cx.export_function("playerStreamInfo", player_stream_info)?;
+    cx.export_function("playerSubscribe", player_subscribe)?;
+    cx.export_function("playerSupportedFormats", player_supported_formats)?;
+    cx.export_function("playerUnsubscribe", player_unsubscribe)?;
     cx.export_function("playerSeek", player_seek)?;
+    cx.export_function("playerSeekTo", player_seek_to)?;
 
     // #[cfg(target_os = "windows")]
     // cx.export_function("mediaSessionHwnd", media_session_hwnd)?;
+    cx.export_function("mediaSessionAttach", media_session_attach)?;
     cx.export_function("mediaSessionNew", media_session_new)?;
+    cx.export_function("mediaSessionRecreate", media_session_recreate)?;
+    cx.export_function("mediaSessionSeeked", media_session_seeked)?;
     cx.export_function("mediaSessionSetMetadata", media_session_set_metadata)?;
     cx.export_function("mediaSessionSetPlayback", media_session_set_playback)?;
+    cx.export_function("mediaSessionSetProperties", media_session_set_properties)?;
 
     Ok(())
 }
@@ -1,30 +1,351 @@
 use {
+    crate::{
+        error::{lock, MediaError, OrThrow},
+        player::{Observer, Playback, PlayerHandle, Remote},
+        protocol,
+    },
     neon::prelude::*,
-    std::{cell::RefCell, sync::Arc},
+    std::{
+        cell::RefCell,
+        sync::{Arc, Mutex},
+        time::Duration,
+    },
 };
 
 // static ACCESSABLE: AtomicBool = AtomicBool::new(true);
 
-#[cfg(not(target_os = "macos"))]
-pub struct MediaSession {
-    controls: souvlaki::MediaControls,
+/// In seconds, for media keys that seek without saying how far.
+const SEEK_STEP: f64 = 10.;
+
+#[cfg(not(any(target_os = "macos", target_os = "linux")))]
+struct Backend {
+    // Also used from the audio thread once a player is attached.
+    controls: Mutex<souvlaki::MediaControls>,
+}
+
+/// MPRIS in process, or souvlaki's D-Bus backend in the helper when that
+/// fails.
+#[cfg(target_os = "linux")]
+enum Backend {
+    Mpris(crate::mpris::Mpris),
+    Helper(Helper),
 }
 
 #[cfg(target_os = "macos")]
+struct Backend {
+    helper: Helper,
+}
+
+/// The controls are rebuilt in place by `mediaSessionRecreate`, the
+/// handler and the attached player are kept.
 pub struct MediaSession {
-    stdin: std::process::ChildStdin,
+    dispatcher: Dispatcher,
+    // Of the helper, empty when there is none.
+    path: String,
+    // `None` while there are no controls. The attached player only holds
+    // it weakly.
+    backend: RefCell<Option<Arc<Backend>>>,
+    #[cfg(target_os = "linux")]
+    arbiter: RefCell<Option<crate::arbiter::Arbiter>>,
 }
 
-type JSMediaSession = Option<MediaSession>;
+impl MediaSession {
+    #[inline]
+    fn new(dispatcher: Dispatcher, path: String) -> Self {
+        Self {
+            dispatcher,
+            path,
+            backend: RefCell::new(None),
+            #[cfg(target_os = "linux")]
+            arbiter: RefCell::new(None),
+        }
+    }
+
+    #[inline]
+    fn backend(&self) -> Result<Arc<Backend>, MediaError> {
+        self.backend
+            .borrow()
+            .clone()
+            .ok_or_else(|| MediaError::Unavailable("There are no media controls".to_owned()))
+    }
 
-impl Finalize for MediaSession {}
+    /// Creates the controls, the old ones are dropped first since they may
+    /// still own the bus name.
+    fn open(&self) -> Result<(), MediaError> {
+        #[cfg(target_os = "linux")]
+        self.arbiter.borrow_mut().take();
+        self.backend.borrow_mut().take();
 
-#[cfg(not(target_os = "macos"))]
-impl MediaSession {
+        let backend = Arc::new(Backend::new(&self.dispatcher, &self.path)?);
+        if let Some(ref player) = *lock(&self.dispatcher.player) {
+            let _ = player.observe(observer(&backend));
+        }
+        #[cfg(target_os = "linux")]
+        {
+            *self.arbiter.borrow_mut() = arbitrate(&backend);
+        }
+        *self.backend.borrow_mut() = Some(backend);
+
+        Ok(())
+    }
+}
+
+/// Shows the controls only while this instance owns the media keys.
+/// Without a bus there is nobody to share them with.
+#[cfg(target_os = "linux")]
+fn arbitrate(backend: &Arc<Backend>) -> Option<crate::arbiter::Arbiter> {
+    use crate::arbiter::Arbiter;
+
+    let weak = Arc::downgrade(backend);
+    let arbiter = Arbiter::new(move |active| {
+        if let Some(backend) = weak.upgrade() {
+            backend.set_active(active);
+        }
+    });
+    if arbiter.is_err() {
+        backend.set_active(true);
+    }
+    arbiter.ok()
+}
+
+/// Pushes the playback of the attached player to `backend`.
+fn observer(backend: &Arc<Backend>) -> Observer {
+    let backend = Arc::downgrade(backend);
+    Box::new(move |playback| {
+        if let Some(backend) = backend.upgrade() {
+            backend.show(playback);
+        }
+    })
+}
+
+/// The D-Bus name, unique per process so every window can own one.
+#[inline]
+fn instance_name() -> String {
+    format!("cloudmusic-vscode.instance{}", std::process::id())
+}
+
+/// A request from the OS, handed to JS as `{ type, ... }`. Times are in
+/// seconds, the volume is in `0..=1`.
+#[cfg_attr(test, derive(Debug, PartialEq))]
+pub enum MediaEvent {
+    Play,
+    Pause,
+    Toggle,
+    Next,
+    Previous,
+    Stop,
+    /// A step of the player's choosing.
+    Seek {
+        forward: bool,
+    },
+    SeekBy {
+        forward: bool,
+        offset: f64,
+    },
+    SetPosition(f64),
+    SetVolume(f64),
+    OpenUri(String),
+    Raise,
+    Quit,
+    // Only sent by MPRIS.
+    SetRate(f64),
+    SetShuffle(bool),
+    SetLoopStatus(String),
+}
+
+impl MediaEvent {
+    fn to_object<'a>(&self, cx: &mut impl Context<'a>) -> JsResult<'a, JsObject> {
+        let obj = cx.empty_object();
+        let type_ = match self {
+            MediaEvent::Play => "play",
+            MediaEvent::Pause => "pause",
+            MediaEvent::Toggle => "toggle",
+            MediaEvent::Next => "next",
+            MediaEvent::Previous => "previous",
+            MediaEvent::Stop => "stop",
+            MediaEvent::Seek { .. } => "seek",
+            MediaEvent::SeekBy { .. } => "seekBy",
+            MediaEvent::SetPosition(_) => "setPosition",
+            MediaEvent::SetVolume(_) => "setVolume",
+            MediaEvent::OpenUri(_) => "openUri",
+            MediaEvent::Raise => "raise",
+            MediaEvent::Quit => "quit",
+            MediaEvent::SetRate(_) => "setRate",
+            MediaEvent::SetShuffle(_) => "setShuffle",
+            MediaEvent::SetLoopStatus(_) => "setLoopStatus",
+        };
+        let type_ = cx.string(type_);
+        obj.set(cx, "type", type_)?;
+
+        match *self {
+            MediaEvent::Seek { forward } => {
+                let direction = cx.string(if forward { "forward" } else { "backward" });
+                obj.set(cx, "direction", direction)?;
+            }
+            MediaEvent::SeekBy { forward, offset } => {
+                let direction = cx.string(if forward { "forward" } else { "backward" });
+                obj.set(cx, "direction", direction)?;
+                let offset = cx.number(offset);
+                obj.set(cx, "offset", offset)?;
+            }
+            MediaEvent::SetPosition(position) => {
+                let position = cx.number(position);
+                obj.set(cx, "position", position)?;
+            }
+            MediaEvent::SetVolume(volume) => {
+                let volume = cx.number(volume);
+                obj.set(cx, "volume", volume)?;
+            }
+            MediaEvent::OpenUri(ref uri) => {
+                let uri = cx.string(uri);
+                obj.set(cx, "uri", uri)?;
+            }
+            MediaEvent::SetRate(rate) => {
+                let rate = cx.number(rate);
+                obj.set(cx, "rate", rate)?;
+            }
+            MediaEvent::SetShuffle(shuffle) => {
+                let shuffle = cx.boolean(shuffle);
+                obj.set(cx, "shuffle", shuffle)?;
+            }
+            MediaEvent::SetLoopStatus(ref status) => {
+                let status = cx.string(status);
+                obj.set(cx, "loopStatus", status)?;
+            }
+            _ => {}
+        }
+
+        Ok(obj)
+    }
+
+    #[cfg(not(any(target_os = "macos", target_os = "linux")))]
+    fn from_souvlaki(event: souvlaki::MediaControlEvent) -> Self {
+        use souvlaki::{MediaControlEvent, MediaPosition, SeekDirection};
+
+        match event {
+            MediaControlEvent::Play => MediaEvent::Play,
+            MediaControlEvent::Pause => MediaEvent::Pause,
+            MediaControlEvent::Toggle => MediaEvent::Toggle,
+            MediaControlEvent::Next => MediaEvent::Next,
+            MediaControlEvent::Previous => MediaEvent::Previous,
+            MediaControlEvent::Stop => MediaEvent::Stop,
+            MediaControlEvent::Seek(direction) => MediaEvent::Seek {
+                forward: matches!(direction, SeekDirection::Forward),
+            },
+            MediaControlEvent::SeekBy(direction, offset) => MediaEvent::SeekBy {
+                forward: matches!(direction, SeekDirection::Forward),
+                offset: offset.as_secs_f64(),
+            },
+            MediaControlEvent::SetPosition(MediaPosition(position)) => {
+                MediaEvent::SetPosition(position.as_secs_f64())
+            }
+            MediaControlEvent::SetVolume(volume) => MediaEvent::SetVolume(volume),
+            MediaControlEvent::OpenUri(uri) => MediaEvent::OpenUri(uri),
+            MediaControlEvent::Raise => MediaEvent::Raise,
+            MediaControlEvent::Quit => MediaEvent::Quit,
+        }
+    }
+}
+
+/// Events of the helper.
+impl From<protocol::Event> for MediaEvent {
+    fn from(event: protocol::Event) -> Self {
+        use protocol::{Direction, Event};
+
+        match event {
+            Event::Play => MediaEvent::Play,
+            Event::Pause => MediaEvent::Pause,
+            Event::Toggle => MediaEvent::Toggle,
+            Event::Next => MediaEvent::Next,
+            Event::Previous => MediaEvent::Previous,
+            Event::Stop => MediaEvent::Stop,
+            Event::Seek { direction } => MediaEvent::Seek {
+                forward: direction == Direction::Forward,
+            },
+            Event::SeekBy { direction, offset } => MediaEvent::SeekBy {
+                forward: direction == Direction::Forward,
+                offset,
+            },
+            Event::SetPosition { position } => MediaEvent::SetPosition(position),
+            Event::SetVolume { volume } => MediaEvent::SetVolume(volume.clamp(0., 1.)),
+            Event::OpenUri { uri } => MediaEvent::OpenUri(uri),
+            Event::Raise => MediaEvent::Raise,
+            Event::Quit => MediaEvent::Quit,
+        }
+    }
+}
+
+/// Hands the events to JS. Transport ones are applied to the attached
+/// player first, so they work while JS is busy, and are marked `handled`.
+#[derive(Clone)]
+struct Dispatcher {
+    channel: Channel,
+    handler: Arc<Root<JsFunction>>,
+    player: Arc<Mutex<Option<Remote>>>,
+}
+
+impl Dispatcher {
     #[inline]
-    fn new() -> Option<Self> {
+    fn new(cx: &mut FunctionContext, handler: Handle<JsFunction>) -> Self {
+        Self {
+            channel: cx.channel(),
+            handler: Arc::new(handler.root(cx)),
+            player: Default::default(),
+        }
+    }
+
+    fn dispatch(&self, event: MediaEvent) {
+        let handled = match *lock(&self.player) {
+            Some(ref player) => apply(player, &event),
+            None => false,
+        };
+        let handler = self.handler.clone();
+
+        self.channel.send(move |mut cx| {
+            let this = cx.undefined();
+            let obj = event.to_object(&mut cx)?;
+            if handled {
+                let handled = cx.boolean(true);
+                obj.set(&mut cx, "handled", handled)?;
+            }
+            handler
+                .to_inner(&mut cx)
+                .call(&mut cx, this, [obj.upcast()])?;
+            Ok(())
+        });
+    }
+}
+
+/// Whether `event` was one for the player and it got it.
+fn apply(player: &Remote, event: &MediaEvent) -> bool {
+    let res = match *event {
+        MediaEvent::Play => player.play(),
+        MediaEvent::Pause => player.pause(),
+        MediaEvent::Toggle => player.toggle(),
+        MediaEvent::Stop => player.stop(),
+        MediaEvent::Seek { forward } => player.seek(if forward { SEEK_STEP } else { -SEEK_STEP }),
+        MediaEvent::SeekBy { forward, offset } => {
+            player.seek(if forward { offset } else { -offset })
+        }
+        MediaEvent::SetPosition(position) => player.seek_to(position),
+        _ => return false,
+    };
+    res.is_ok()
+}
+
+/// Remote artwork, or embedded one cached by `readMetadata`.
+#[inline]
+fn is_cover_url(url: &str) -> bool {
+    url.starts_with("http") || url.starts_with("file://")
+}
+
+impl Finalize for MediaSession {}
+
+#[cfg(not(any(target_os = "macos", target_os = "linux")))]
+impl Backend {
+    fn new(dispatcher: &Dispatcher, _: &str) -> Result<Self, MediaError> {
         use {
-            souvlaki::{MediaControls, PlatformConfig},
+            souvlaki::{MediaControlEvent, MediaControls, MediaPlayback, PlatformConfig},
             std::ffi::c_void,
         };
 
@@ -37,30 +358,42 @@ impl MediaSession {
                 {
                     use {
                         raw_window_handle::{HasWindowHandle, RawWindowHandle},
+                        std::sync::OnceLock,
                         winit::{event_loop::EventLoop, window::Window},
                     };
-                    match EventLoop::new()
-                        .unwrap()
-                        .create_window(
-                            Window::default_attributes()
-                                .with_title(TITLE)
-                                .with_visible(false)
-                                .with_transparent(true)
-                                .with_decorations(false),
+
+                    // winit allows one event loop per process, the window is
+                    // kept alive and reused when the controls are recreated.
+                    static WINDOW: OnceLock<Option<Window>> = OnceLock::new();
+                    let window = WINDOW.get_or_init(|| {
+                        let event_loop = EventLoop::new().ok()?;
+                        event_loop
+                            .create_window(
+                                Window::default_attributes()
+                                    .with_title(TITLE)
+                                    .with_visible(false)
+                                    .with_transparent(true)
+                                    .with_decorations(false),
+                            )
+                            .ok()
+                    });
+                    let hwnd = window.as_ref().and_then(|window| {
+                        match window.window_handle().ok()?.as_raw() {
+                            RawWindowHandle::Win32(han) => Some(han.hwnd.get() as *mut c_void),
+                            _ => None,
+                        }
+                    });
+                    Some(hwnd.ok_or_else(|| {
+                        MediaError::Unavailable(
+                            "No hwnd was found! Try to use wasm mode.".to_owned(),
                         )
-                        .unwrap()
-                        .window_handle()
-                        .unwrap()
-                        .as_raw()
-                    {
-                        RawWindowHandle::Win32(han) => Some(han.hwnd.get() as *mut c_void),
-                        _ => panic!("No hwnd was found! Try to use wasm mode."),
-                    }
+                    })?)
                 }
                 #[cfg(not(any(target_arch = "x86_64", target_arch = "x86")))]
                 {
-                    panic!("No hwnd was found! Try to use wasm mode.");
-                    None
+                    return Err(MediaError::Unavailable(
+                        "No hwnd was found! Try to use wasm mode.".to_owned(),
+                    ));
                 }
             }
             #[cfg(not(target_os = "windows"))]
@@ -69,9 +402,9 @@ impl MediaSession {
             }
         };
 
-        fn config<'a>(hwnd: Option<*mut c_void>) -> PlatformConfig<'a> {
+        fn config(name: &str, hwnd: Option<*mut c_void>) -> PlatformConfig<'_> {
             PlatformConfig {
-                dbus_name: "cloudmusic-vscode",
+                dbus_name: name,
                 display_name: TITLE,
                 hwnd,
             }
@@ -87,92 +420,484 @@ impl MediaSession {
                 MediaControls::new(config(fallback())).unwrap()
             }
         }; */
-        match MediaControls::new(config(hwnd)) {
-            Ok(controls) => Some(MediaSession { controls }),
-            Err(_) => None,
+        let name = instance_name();
+        let mut controls = MediaControls::new(config(&name, hwnd))?;
+        let events = dispatcher.clone();
+        controls.attach(move |event: MediaControlEvent| {
+            events.dispatch(MediaEvent::from_souvlaki(event))
+        })?;
+        controls.set_playback(MediaPlayback::Stopped)?;
+
+        Ok(Backend {
+            controls: Mutex::new(controls),
+        })
+    }
+
+    #[inline]
+    fn set_metadata(
+        &self,
+        title: String,
+        album: String,
+        artist: String,
+        cover_url: String,
+        duration: Option<Duration>,
+    ) -> Result<(), MediaError> {
+        use souvlaki::MediaMetadata;
+
+        lock(&self.controls).set_metadata(MediaMetadata {
+            title: Some(title.as_str()),
+            album: (!album.is_empty()).then_some(album.as_str()),
+            artist: (!artist.is_empty()).then_some(artist.as_str()),
+            cover_url: is_cover_url(&cover_url).then_some(cover_url.as_str()),
+            duration,
+        })?;
+        Ok(())
+    }
+
+    #[inline]
+    fn set_playback(&self, playing: bool, position: Duration) -> Result<(), MediaError> {
+        use souvlaki::{MediaPlayback, MediaPosition};
+
+        let progress = Some(MediaPosition(position));
+        lock(&self.controls).set_playback(match playing {
+            true => MediaPlayback::Playing { progress },
+            false => MediaPlayback::Paused { progress },
+        })?;
+        Ok(())
+    }
+
+    /// Runs on the audio thread, a failure is left for the next change.
+    #[inline]
+    fn show(&self, playback: Playback) {
+        let position = Duration::try_from_secs_f64(playback.position).unwrap_or_default();
+        let _ = self.set_playback(playback.playing, position);
+    }
+}
+
+#[cfg(target_os = "linux")]
+impl Backend {
+    /// The helper lacks the rate, shuffle, loop status and `Seeked`, so it
+    /// is only used when MPRIS can not be served in process. Both stay
+    /// hidden until they win the arbitration.
+    fn new(dispatcher: &Dispatcher, path: &str) -> Result<Self, MediaError> {
+        use crate::mpris::Mpris;
+
+        let events = dispatcher.clone();
+        match Mpris::new(&instance_name(), move |event| events.dispatch(event)) {
+            Ok(mpris) => return Ok(Backend::Mpris(mpris)),
+            Err(err) if path.is_empty() => return Err(err.into()),
+            Err(err) => eprintln!("MPRIS: {err}, falling back to the media helper"),
+        }
+        let helper = Helper::spawn(path.into(), instance_name(), false, dispatcher.clone())?;
+        Ok(Backend::Helper(helper))
+    }
+
+    /// For what only MPRIS has.
+    #[inline]
+    fn mpris(&self) -> Result<&crate::mpris::Mpris, MediaError> {
+        match self {
+            Backend::Mpris(mpris) => Ok(mpris),
+            Backend::Helper(_) => Err(MediaError::Unavailable(
+                "The media helper has no such property".to_owned(),
+            )),
         }
     }
 
     #[inline]
     fn set_metadata(
-        &mut self,
+        &self,
         title: String,
         album: String,
         artist: String,
         cover_url: String,
-        duration: f64,
-    ) {
-        use {souvlaki::MediaMetadata, std::time::Duration};
-        self.controls
-            .set_metadata(MediaMetadata {
-                title: Some(title.as_str()),
-                album: (!album.is_empty()).then_some(album.as_str()),
-                artist: (!artist.is_empty()).then_some(artist.as_str()),
-                cover_url: cover_url.starts_with("http").then_some(cover_url.as_str()),
-                duration: (duration != 0.).then_some(Duration::from_secs_f64(duration)),
-            })
-            .unwrap();
+        duration: Option<Duration>,
+    ) -> Result<(), MediaError> {
+        use crate::mpris::Metadata;
+
+        match self {
+            Backend::Mpris(mpris) => Ok(mpris.set_metadata(Metadata {
+                title,
+                album,
+                artist,
+                cover_url: is_cover_url(&cover_url).then_some(cover_url),
+                duration,
+            })?),
+            Backend::Helper(helper) => {
+                helper.set_metadata(title, album, artist, cover_url, duration)
+            }
+        }
     }
 
     #[inline]
-    fn set_playback(&mut self, playing: bool, position: f64) {
-        use {
-            souvlaki::{MediaPlayback, MediaPosition},
-            std::time::Duration,
-        };
+    fn set_playback(&self, playing: bool, position: Duration) -> Result<(), MediaError> {
+        match self {
+            Backend::Mpris(mpris) => Ok(mpris.set_playback(playing, position)?),
+            Backend::Helper(helper) => helper.set_playback(playing, position),
+        }
+    }
 
-        let progress = Some(MediaPosition(Duration::from_secs_f64(position)));
-        self.controls
-            .set_playback(match playing {
-                true => MediaPlayback::Playing { progress },
-                false => MediaPlayback::Paused { progress },
-            })
-            .unwrap();
+    /// `Seeked` and the rate are MPRIS only. Runs on the audio thread, a
+    /// failure is left for the next change.
+    fn show(&self, playback: Playback) {
+        use crate::mpris::Properties;
+
+        let position = Duration::try_from_secs_f64(playback.position).unwrap_or_default();
+        match self {
+            Backend::Mpris(mpris) => {
+                let _ = mpris.set_playback(playback.playing, position);
+                if playback.seeked {
+                    let _ = mpris.seeked(position);
+                }
+                let _ = mpris.set_properties(Properties {
+                    rate: Some(playback.speed),
+                    ..Default::default()
+                });
+            }
+            Backend::Helper(helper) => {
+                let _ = helper.set_playback(playback.playing, position);
+            }
+        }
+    }
+
+    #[inline]
+    fn set_active(&self, active: bool) {
+        match self {
+            Backend::Mpris(mpris) => {
+                let _ = mpris.set_visible(active);
+            }
+            Backend::Helper(helper) => helper.set_active(active),
+        }
     }
 }
 
 #[cfg(target_os = "macos")]
-impl MediaSession {
+impl Backend {
+    /// The system picks the app that played last.
+    #[inline]
+    fn new(dispatcher: &Dispatcher, path: &str) -> Result<Self, MediaError> {
+        let helper = Helper::spawn(path.into(), instance_name(), true, dispatcher.clone())?;
+        Ok(Backend { helper })
+    }
+
     #[inline]
     fn set_metadata(
-        &mut self,
+        &self,
         title: String,
         album: String,
         artist: String,
         cover_url: String,
-        duration: f64,
-    ) {
-        use std::io::Write;
+        duration: Option<Duration>,
+    ) -> Result<(), MediaError> {
+        self.helper
+            .set_metadata(title, album, artist, cover_url, duration)
+    }
+
+    #[inline]
+    fn set_playback(&self, playing: bool, position: Duration) -> Result<(), MediaError> {
+        self.helper.set_playback(playing, position)
+    }
+
+    #[inline]
+    fn show(&self, playback: Playback) {
+        let position = Duration::try_from_secs_f64(playback.position).unwrap_or_default();
+        let _ = self.helper.set_playback(playback.playing, position);
+    }
+}
 
-        let mut items = Vec::new();
-        items.push(format!("title:{title}"));
-        if !album.is_empty() {
-            items.push(format!("album:{album}"));
+/// The out-of-process media controls.
+#[cfg(any(target_os = "macos", target_os = "linux"))]
+mod helper {
+    use {
+        super::{is_cover_url, Dispatcher},
+        crate::{
+            error::{lock, MediaError},
+            protocol,
+        },
+        std::{
+            io::{self, BufRead, BufReader, Write},
+            path::{Path, PathBuf},
+            process::{Child, ChildStdin, ChildStdout, Command, Stdio},
+            sync::{Arc, Mutex, MutexGuard},
+            thread,
+            time::{Duration, Instant},
+        },
+    };
+
+    /// Sends heartbeats this often.
+    const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(2);
+    /// The helper is restarted when it has not answered for this long.
+    const HEARTBEAT_TIMEOUT: Duration = Duration::from_secs(6);
+    const MIN_BACKOFF: Duration = Duration::from_millis(500);
+    const MAX_BACKOFF: Duration = Duration::from_secs(30);
+
+    /// `cloudmusic-macmedia`, restarted whenever it exits or misses heartbeats.
+    /// Every new process gets the last metadata and playback state again.
+    pub struct Helper {
+        shared: Arc<Mutex<HelperState>>,
+    }
+
+    struct HelperState {
+        child: Option<Child>,
+        stdin: Option<ChildStdin>,
+        // Replayed after a restart.
+        metadata: Option<protocol::Request>,
+        playback: Option<protocol::Request>,
+        pong: Instant,
+        // While another instance shows its controls.
+        suspended: bool,
+        closed: bool,
+        // Set with `closed` when the handshake failed.
+        incompatible: Option<String>,
+    }
+
+    impl HelperState {
+        /// A failed write means the process is gone, it is restarted anyway.
+        fn send(&mut self, request: &protocol::Request) {
+            if let (Some(stdin), Ok(line)) = (self.stdin.as_mut(), protocol::encode(request)) {
+                if stdin.write_all(line.as_bytes()).is_err() {
+                    self.stdin = None;
+                }
+            }
         }
-        if !artist.is_empty() {
-            items.push(format!("artist:{artist}"));
+
+        fn kill(&mut self) {
+            self.stdin = None;
+            if let Some(child) = self.child.as_mut() {
+                let _ = child.kill();
+            }
         }
-        if cover_url.starts_with("http") {
-            items.push(format!("cover_url:{cover_url}"));
+
+        /// Requests are kept for the next process until it gave up.
+        #[inline]
+        fn check(&self) -> Result<(), MediaError> {
+            match (&self.incompatible, self.closed) {
+                (Some(message), _) => Err(MediaError::Incompatible(message.clone())),
+                (None, true) => Err(MediaError::Disconnected(
+                    "The media helper has stopped".to_owned(),
+                )),
+                (None, false) => Ok(()),
+            }
+        }
+    }
+
+    impl Helper {
+        /// `name` is the D-Bus one, the helper is not started until
+        /// `active`.
+        pub fn spawn(
+            path: PathBuf,
+            name: String,
+            active: bool,
+            dispatcher: Dispatcher,
+        ) -> io::Result<Self> {
+            // Dropped on failure, which stops a thread that did start.
+            let helper = Self {
+                shared: Arc::new(Mutex::new(HelperState {
+                    child: None,
+                    stdin: None,
+                    metadata: None,
+                    playback: None,
+                    pong: Instant::now(),
+                    suspended: !active,
+                    closed: false,
+                    incompatible: None,
+                })),
+            };
+
+            let state = helper.shared.clone();
+            thread::Builder::new()
+                .name("cloudmusic-media-helper".to_owned())
+                .spawn(move || supervise(&path, &name, &state, &dispatcher))?;
+            let state = helper.shared.clone();
+            thread::Builder::new()
+                .name("cloudmusic-media-heartbeat".to_owned())
+                .spawn(move || heartbeat(&state))?;
+
+            Ok(helper)
+        }
+
+        #[inline]
+        fn state(&self) -> MutexGuard<'_, HelperState> {
+            lock(&self.shared)
+        }
+
+        pub fn set_metadata(
+            &self,
+            title: String,
+            album: String,
+            artist: String,
+            cover_url: String,
+            duration: Option<Duration>,
+        ) -> Result<(), MediaError> {
+            let request = protocol::Request::Metadata {
+                title,
+                album: (!album.is_empty()).then_some(album),
+                artist: (!artist.is_empty()).then_some(artist),
+                cover_url: is_cover_url(&cover_url).then_some(cover_url),
+                duration: duration.map(|duration| duration.as_secs_f64()),
+            };
+            let mut state = self.state();
+            state.send(&request);
+            state.metadata = Some(request);
+            state.check()
+        }
+
+        pub fn set_playback(&self, playing: bool, position: Duration) -> Result<(), MediaError> {
+            let request = protocol::Request::Playback {
+                playing,
+                position: position.as_secs_f64(),
+            };
+            let mut state = self.state();
+            state.send(&request);
+            state.playback = Some(request);
+            state.check()
         }
-        if duration != 0. {
-            items.push(format!("duration:{duration}"));
+
+        /// An inactive helper is stopped, it gets the last state again
+        /// once it is restarted.
+        #[cfg(target_os = "linux")]
+        pub fn set_active(&self, active: bool) {
+            let mut state = self.state();
+            state.suspended = !active;
+            if !active {
+                state.kill();
+            }
         }
+    }
 
-        let string = items.join("\t");
-        self.stdin.write_fmt(format_args!("{string}0\n")).unwrap();
+    impl Drop for Helper {
+        fn drop(&mut self) {
+            let mut state = self.state();
+            state.closed = true;
+            state.kill();
+        }
     }
 
-    #[inline]
-    fn set_playback(&mut self, playing: bool, position: f64) {
-        use std::io::Write;
+    /// Runs the helper until the session is dropped, restarting it with an
+    /// exponential backoff.
+    fn supervise(path: &Path, name: &str, shared: &Mutex<HelperState>, dispatcher: &Dispatcher) {
+        let mut backoff = MIN_BACKOFF;
+        loop {
+            let started = Instant::now();
+            if let Some(stdout) = start(path, name, shared) {
+                read(stdout, shared, dispatcher);
+            }
+
+            {
+                let mut state = lock(shared);
+                state.kill();
+                if let Some(mut child) = state.child.take() {
+                    let _ = child.wait();
+                }
+                if state.closed {
+                    return;
+                }
+                // Not a crash, it waits to be resumed.
+                if state.suspended {
+                    backoff = MIN_BACKOFF;
+                    drop(state);
+                    thread::sleep(MIN_BACKOFF);
+                    continue;
+                }
+            }
+
+            // A helper that ran for a while is restarted right away.
+            if started.elapsed() > MAX_BACKOFF {
+                backoff = MIN_BACKOFF;
+            }
+            eprintln!("cloudmusic-macmedia: exited, restarting in {:?}", backoff);
+            thread::sleep(backoff);
+            backoff = (backoff * 2).min(MAX_BACKOFF);
+        }
+    }
+
+    /// Spawns the process and replays the handshake and the last state.
+    fn start(path: &Path, name: &str, shared: &Mutex<HelperState>) -> Option<ChildStdout> {
+        use protocol::{Request, VERSION};
+
+        let mut state = lock(shared);
+        if state.closed || state.suspended {
+            return None;
+        }
+        let mut child = match Command::new(path)
+            .arg(name)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .spawn()
+        {
+            Ok(child) => child,
+            Err(err) => {
+                eprintln!("cloudmusic-macmedia: {err}");
+                return None;
+            }
+        };
+
+        let stdout = child.stdout.take();
+        state.stdin = child.stdin.take();
+        state.child = Some(child);
+        state.pong = Instant::now();
+
+        let replay = [
+            Some(Request::Hello { version: VERSION }),
+            state.metadata.clone(),
+            state.playback.clone(),
+        ];
+        for request in replay.iter().flatten() {
+            state.send(request);
+        }
+        stdout
+    }
 
-        self.stdin
-            .write_fmt(format_args!("{playing},{position}1\n"))
-            .unwrap();
+    /// Handles the output of one process until it exits.
+    fn read(stdout: ChildStdout, shared: &Mutex<HelperState>, dispatcher: &Dispatcher) {
+        use protocol::{decode, Response, VERSION};
+
+        for line in BufReader::new(stdout).lines().map_while(Result::ok) {
+            match decode(&line) {
+                Ok(Response::Hello { version }) if version == VERSION => {}
+                Ok(Response::Hello { version }) => {
+                    // Restarting the same binary would not help.
+                    let message = format!(
+                        "cloudmusic-macmedia: protocol version {version}, expected {VERSION}"
+                    );
+                    eprintln!("{message}");
+                    let mut state = lock(shared);
+                    state.incompatible = Some(message);
+                    state.closed = true;
+                    return;
+                }
+                Ok(Response::Pong) => lock(shared).pong = Instant::now(),
+                Ok(Response::Event { event }) => dispatcher.dispatch(event.into()),
+                Ok(Response::Error { message }) => eprintln!("cloudmusic-macmedia: {message}"),
+                Err(err) => eprintln!("cloudmusic-macmedia: {err}: {line}"),
+            }
+        }
+    }
+
+    /// Pings the helper, kills it when it stops answering so `supervise`
+    /// restarts it.
+    fn heartbeat(shared: &Mutex<HelperState>) {
+        loop {
+            thread::sleep(HEARTBEAT_INTERVAL);
+            let mut state = lock(shared);
+            if state.closed {
+                return;
+            }
+            if state.stdin.is_none() {
+                continue;
+            }
+            if state.pong.elapsed() > HEARTBEAT_TIMEOUT {
+                eprintln!("cloudmusic-macmedia: missed heartbeats");
+                state.kill();
+            } else {
+                state.send(&protocol::Request::Ping);
+            }
+        }
     }
 }
 
+#[cfg(any(target_os = "macos", target_os = "linux"))]
+use helper::Helper;
+
 /* #[cfg(target_os = "windows")]
 pub fn media_session_hwnd(mut cx: FunctionContext) -> JsResult<JsString> {
     if !ACCESSABLE.load(Ordering::Relaxed) {
@@ -242,113 +967,166 @@ pub fn media_session_hwnd(mut cx: FunctionContext) -> JsResult<JsString> {
     }))
 } */
 
-#[cfg(not(target_os = "macos"))]
-pub fn media_session_new(mut cx: FunctionContext) -> JsResult<JsValue> {
-    use souvlaki::{MediaControlEvent, MediaPlayback};
-
+/// Controls that can not be created are reported by the other exports, with
+/// an `Unavailable` error. `path` is the helper, on Linux it only takes over
+/// when MPRIS can not be served in process.
+pub fn media_session_new(mut cx: FunctionContext) -> JsResult<JsBox<MediaSession>> {
     // let hwnd = cx.argument::<JsString>(0)?.value(&mut cx);
-    let handler = Arc::new(cx.argument::<JsFunction>(0)?.root(&mut cx));
-
-    let media_session: JSMediaSession = MediaSession::new();
-    let media_session = cx.boxed(RefCell::new(media_session));
-    let channel = cx.channel();
-
-    let _ = media_session.borrow_mut().as_mut().map(|m| {
-        m.controls.attach(move |event: MediaControlEvent| {
-            let type_ = match event {
-                MediaControlEvent::Play => 0.,
-                MediaControlEvent::Pause => 1.,
-                MediaControlEvent::Toggle => 2.,
-                MediaControlEvent::Next => 3.,
-                MediaControlEvent::Previous => 4.,
-                MediaControlEvent::Stop => 5.,
-                _ => return,
-            };
-            let handler = handler.clone();
-
-            channel.send(move |mut cx| {
-                let this = cx.undefined();
-                let args = [cx.number(type_).upcast()];
-                handler.to_inner(&mut cx).call(&mut cx, this, args)?;
-                Ok(())
-            });
-        })
-    });
+    let handler = cx.argument::<JsFunction>(0)?;
+    let path = match cx.argument_opt(1) {
+        Some(path) => path
+            .downcast_or_throw::<JsString, _>(&mut cx)?
+            .value(&mut cx),
+        None => String::new(),
+    };
+    let dispatcher = Dispatcher::new(&mut cx, handler);
+
+    let media_session = MediaSession::new(dispatcher, path);
+    let _ = media_session.open();
+    Ok(cx.boxed(media_session))
+}
 
-    let _ = media_session
-        .borrow_mut()
-        .as_mut()
-        .map(|m| m.controls.set_playback(MediaPlayback::Stopped));
+/// Rebuilds the controls, after the bus connection dropped or the helper
+/// gave up. The metadata has to be set again.
+pub fn media_session_recreate(mut cx: FunctionContext) -> JsResult<JsUndefined> {
+    let media_session = cx.argument::<JsBox<MediaSession>>(0)?;
+    media_session.open().or_throw(&mut cx)?;
 
-    Ok(media_session.upcast())
+    Ok(cx.undefined())
 }
 
-#[cfg(target_os = "macos")]
-pub fn media_session_new(mut cx: FunctionContext) -> JsResult<JsValue> {
-    use std::{
-        io::{BufRead, BufReader},
-        path::Path,
-        process::{Command, Stdio},
-        thread,
-    };
+/// Pushes the playback state and position of `player` from the audio
+/// thread, and lets media keys act on it while JS is busy. Those events
+/// still reach the handler, with `handled` set.
+pub fn media_session_attach(mut cx: FunctionContext) -> JsResult<JsUndefined> {
+    let media_session = cx.argument::<JsBox<MediaSession>>(0)?;
+    let player = cx.argument::<JsBox<PlayerHandle>>(1)?;
 
-    let handler = Arc::new(cx.argument::<JsFunction>(0)?.root(&mut cx));
-    let path = cx.argument::<JsString>(1)?.value(&mut cx);
-
-    let mut child = Command::new(Path::new(&path))
-        .stdin(Stdio::piped())
-        .stdout(Stdio::piped())
-        .spawn()
-        .unwrap();
-
-    let stdout = child.stdout.take().unwrap();
-    let channel = cx.channel();
-    thread::spawn(move || {
-        for line in BufReader::new(stdout).lines() {
-            if let Ok(type_) = line.unwrap().parse::<i32>() {
-                let handler = handler.clone();
-
-                channel.send(move |mut cx| {
-                    let this = cx.undefined();
-                    let args = [cx.number(type_).upcast()];
-                    handler.to_inner(&mut cx).call(&mut cx, this, args)?;
-                    Ok(())
-                });
-            }
-        }
-    });
+    // Otherwise it is observed once the controls are recreated.
+    if let Ok(backend) = media_session.backend() {
+        player.observe(observer(&backend)).or_throw(&mut cx)?;
+    }
+    *lock(&media_session.dispatcher.player) = Some(player.remote());
 
-    let stdin = child.stdin.take().unwrap();
-    let media_session: JSMediaSession = Some(MediaSession { stdin });
-    let media_session = cx.boxed(RefCell::new(media_session));
-    Ok(media_session.upcast())
+    Ok(cx.undefined())
 }
 
+/// Throws a `MediaError`, an unknown or invalid `duration` is left out.
 pub fn media_session_set_metadata(mut cx: FunctionContext) -> JsResult<JsUndefined> {
-    let media_session = cx.argument::<JsBox<RefCell<JSMediaSession>>>(0)?;
+    let media_session = cx.argument::<JsBox<MediaSession>>(0)?;
     let title = cx.argument::<JsString>(1)?.value(&mut cx);
     let album = cx.argument::<JsString>(2)?.value(&mut cx);
     let artist = cx.argument::<JsString>(3)?.value(&mut cx);
     let cover_url = cx.argument::<JsString>(4)?.value(&mut cx);
     let duration = cx.argument::<JsNumber>(5)?.value(&mut cx);
+    let duration = Duration::try_from_secs_f64(duration)
+        .ok()
+        .filter(|duration| !duration.is_zero());
 
     media_session
-        .borrow_mut()
-        .as_mut()
-        .map(|m| m.set_metadata(title, album, artist, cover_url, duration));
+        .backend()
+        .and_then(|backend| backend.set_metadata(title, album, artist, cover_url, duration))
+        .or_throw(&mut cx)?;
 
     Ok(cx.undefined())
 }
 
+/// Throws a `MediaError`.
 pub fn media_session_set_playback(mut cx: FunctionContext) -> JsResult<JsUndefined> {
-    let media_session = cx.argument::<JsBox<RefCell<JSMediaSession>>>(0)?;
+    let media_session = cx.argument::<JsBox<MediaSession>>(0)?;
     let playing = cx.argument::<JsBoolean>(1)?.value(&mut cx);
     let position = cx.argument::<JsNumber>(2)?.value(&mut cx);
 
-    media_session
-        .borrow_mut()
-        .as_mut()
-        .map(|m| m.set_playback(playing, position));
+    position_of(position)
+        .and_then(|position| {
+            let backend = media_session.backend()?;
+            backend.set_playback(playing, position)
+        })
+        .or_throw(&mut cx)?;
+
+    Ok(cx.undefined())
+}
+
+/// Emits `Seeked` with in-process MPRIS after a jump in the position, in
+/// seconds. Throws a `MediaError`, an `Unavailable` one with the helper.
+pub fn media_session_seeked(mut cx: FunctionContext) -> JsResult<JsUndefined> {
+    let media_session = cx.argument::<JsBox<MediaSession>>(0)?;
+    let position = cx.argument::<JsNumber>(1)?.value(&mut cx);
+    let position = position_of(position).or_throw(&mut cx)?;
+
+    #[cfg(target_os = "linux")]
+    {
+        let backend = media_session.backend().or_throw(&mut cx)?;
+        backend
+            .mpris()
+            .and_then(|mpris| Ok(mpris.seeked(position)?))
+            .or_throw(&mut cx)?;
+    }
+    #[cfg(not(target_os = "linux"))]
+    let _ = (media_session, position);
+
+    Ok(cx.undefined())
+}
+
+#[inline]
+fn position_of(seconds: f64) -> Result<Duration, MediaError> {
+    Duration::try_from_secs_f64(seconds)
+        .map_err(|_| MediaError::InvalidArgument(format!("Invalid position {}", seconds)))
+}
+
+/// Updates `rate`, `volume`, `shuffle`, `loopStatus`, `canGoNext` and
+/// `canGoPrevious`, missing keys are left alone. Only in-process MPRIS has
+/// them. Throws a `MediaError`, an `Unavailable` one with the helper.
+pub fn media_session_set_properties(mut cx: FunctionContext) -> JsResult<JsUndefined> {
+    let media_session = cx.argument::<JsBox<MediaSession>>(0)?;
+    let props = cx.argument::<JsObject>(1)?;
+
+    #[cfg(target_os = "linux")]
+    {
+        use crate::mpris::Properties;
+
+        fn number(
+            cx: &mut FunctionContext,
+            obj: Handle<JsObject>,
+            key: &str,
+        ) -> NeonResult<Option<f64>> {
+            let value: Option<Handle<JsNumber>> = obj.get_opt(cx, key)?;
+            Ok(value.map(|v| v.value(cx)))
+        }
+        fn boolean(
+            cx: &mut FunctionContext,
+            obj: Handle<JsObject>,
+            key: &str,
+        ) -> NeonResult<Option<bool>> {
+            let value: Option<Handle<JsBoolean>> = obj.get_opt(cx, key)?;
+            Ok(value.map(|v| v.value(cx)))
+        }
+
+        let loop_status: Option<Handle<JsString>> = props.get_opt(&mut cx, "loopStatus")?;
+        let loop_status = loop_status.map(|v| v.value(&mut cx));
+        if let Some(ref status) = loop_status {
+            if !matches!(status.as_str(), "None" | "Track" | "Playlist") {
+                let err = MediaError::InvalidArgument(format!("Invalid loop status: {}", status));
+                return Err(err).or_throw(&mut cx);
+            }
+        }
+        let properties = Properties {
+            rate: number(&mut cx, props, "rate")?,
+            volume: number(&mut cx, props, "volume")?,
+            shuffle: boolean(&mut cx, props, "shuffle")?,
+            loop_status,
+            can_go_next: boolean(&mut cx, props, "canGoNext")?,
+            can_go_previous: boolean(&mut cx, props, "canGoPrevious")?,
+        };
+
+        let backend = media_session.backend().or_throw(&mut cx)?;
+        backend
+            .mpris()
+            .and_then(|mpris| Ok(mpris.set_properties(properties)?))
+            .or_throw(&mut cx)?;
+    }
+    #[cfg(not(target_os = "linux"))]
+    let _ = (media_session, props);
 
     Ok(cx.undefined())
 }
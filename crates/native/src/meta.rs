@@ -0,0 +1,463 @@
+use {
+    crate::error::{guard, OrThrow, PlayerError},
+    neon::prelude::*,
+    std::{
+        fs::{self, File},
+        io,
+        path::{Path, PathBuf},
+    },
+    symphonia::{
+        core::{
+            codecs::CODEC_TYPE_NULL,
+            io::MediaSourceStream,
+            meta::{MetadataRevision, StandardTagKey, StandardVisualKey, Tag, Value},
+            probe::Hint,
+        },
+        default::get_probe,
+    },
+};
+
+// Frame ids and field names lyrics are stored under, besides `StandardTagKey::Lyrics`.
+const LYRICS_KEYS: [&str; 4] = ["USLT", "LYRICS", "UNSYNCEDLYRICS", "©LYR"];
+
+/// The tags of a local file, the first value found wins.
+#[derive(Default)]
+pub struct Metadata {
+    pub title: Option<String>,
+    pub artist: Option<String>,
+    pub album: Option<String>,
+    pub album_artist: Option<String>,
+    pub track: Option<u32>,
+    pub track_total: Option<u32>,
+    pub disc: Option<u32>,
+    pub disc_total: Option<u32>,
+    pub year: Option<u32>,
+    pub genre: Option<String>,
+    pub duration: Option<f64>,
+    pub lyrics: Option<String>,
+    pub cover: Option<Cover>,
+}
+
+/// Embedded artwork, from an `APIC` frame, a FLAC `PICTURE` block...
+pub struct Cover {
+    pub media_type: String,
+    pub data: Box<[u8]>,
+}
+
+impl Cover {
+    /// Writes the image to `dir` named after its content, so every track of
+    /// an album shares one file. Returns the path of the file.
+    pub fn cache(&self, dir: &Path) -> io::Result<PathBuf> {
+        let ext = match self.media_type.as_str() {
+            "image/png" => "png",
+            "image/gif" => "gif",
+            "image/bmp" => "bmp",
+            "image/webp" => "webp",
+            _ => "jpg",
+        };
+        let path = dir.join(format!("{:016x}.{}", fnv1a(&self.data), ext));
+        if !path.exists() {
+            fs::create_dir_all(dir)?;
+            fs::write(&path, &self.data)?;
+        }
+
+        Ok(path)
+    }
+}
+
+/// A stable hash, `DefaultHasher` may change between Rust releases.
+#[inline]
+fn fnv1a(data: &[u8]) -> u64 {
+    data.iter().fold(0xcbf29ce484222325, |hash, &byte| {
+        (hash ^ byte as u64).wrapping_mul(0x100000001b3)
+    })
+}
+
+/// `file://` URL of an absolute path.
+pub fn file_url(path: &Path) -> String {
+    let path = path.to_string_lossy().replace('\\', "/");
+    let mut url = String::from("file://");
+    // `C:/...` on Windows.
+    if !path.starts_with('/') {
+        url.push('/');
+    }
+    for byte in path.bytes() {
+        match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'/' | b':' | b'-' | b'_' | b'.' | b'~' => {
+                url.push(byte as char)
+            }
+            _ => url.push_str(&format!("%{:02X}", byte)),
+        }
+    }
+    url
+}
+
+impl Metadata {
+    pub fn read(path: &str) -> Result<Self, PlayerError> {
+        let mut hint = Hint::new();
+        if let Some(ext) = Path::new(path).extension().and_then(|e| e.to_str()) {
+            hint.with_extension(ext);
+        }
+        let file = File::open(path)?;
+        let mss = MediaSourceStream::new(Box::new(file), Default::default());
+        let mut probed =
+            get_probe().format(&hint, mss, &Default::default(), &Default::default())?;
+
+        let mut metadata = Self::default();
+        // The container's own tags first, then ID3 ones in front of it.
+        if let Some(revision) = probed.format.metadata().current() {
+            metadata.apply(revision);
+        }
+        if let Some(revision) = probed.metadata.get().as_ref().and_then(|m| m.current()) {
+            metadata.apply(revision);
+        }
+
+        metadata.duration = probed
+            .format
+            .tracks()
+            .iter()
+            .find(|t| t.codec_params.codec != CODEC_TYPE_NULL)
+            .and_then(|track| {
+                let params = &track.codec_params;
+                params.time_base.zip(params.n_frames)
+            })
+            .map(|(base, frames)| base.calc_time(frames))
+            .map(|time| time.seconds as f64 + time.frac);
+
+        Ok(metadata)
+    }
+
+    fn apply(&mut self, revision: &MetadataRevision) {
+        if self.cover.is_none() {
+            let visuals = revision.visuals();
+            self.cover = visuals
+                .iter()
+                .find(|v| v.usage == Some(StandardVisualKey::FrontCover))
+                .or_else(|| visuals.first())
+                .map(|visual| Cover {
+                    media_type: visual.media_type.clone(),
+                    data: visual.data.clone(),
+                });
+        }
+
+        // Unsynchronized lyrics are preferred, synchronized ones become LRC.
+        let mut synced = None;
+
+        for tag in revision.tags() {
+            let value = tag.value.to_string();
+            let value = value.trim_matches(char::from(0)).trim();
+
+            match tag.std_key {
+                Some(StandardTagKey::TrackTitle) => set(&mut self.title, value),
+                Some(StandardTagKey::Artist) => set(&mut self.artist, value),
+                Some(StandardTagKey::Album) => set(&mut self.album, value),
+                Some(StandardTagKey::AlbumArtist) => set(&mut self.album_artist, value),
+                Some(StandardTagKey::Genre) => set(&mut self.genre, value),
+                Some(StandardTagKey::TrackNumber) => {
+                    let (number, total) = parse_position(value);
+                    self.track = self.track.or(number);
+                    self.track_total = self.track_total.or(total);
+                }
+                Some(StandardTagKey::TrackTotal) => {
+                    self.track_total = self.track_total.or(value.parse().ok())
+                }
+                Some(StandardTagKey::DiscNumber) => {
+                    let (number, total) = parse_position(value);
+                    self.disc = self.disc.or(number);
+                    self.disc_total = self.disc_total.or(total);
+                }
+                Some(StandardTagKey::DiscTotal) => {
+                    self.disc_total = self.disc_total.or(value.parse().ok())
+                }
+                Some(StandardTagKey::Date) | Some(StandardTagKey::OriginalDate) => {
+                    self.year = self.year.or(parse_year(value))
+                }
+                Some(StandardTagKey::Lyrics) => set(&mut self.lyrics, value),
+                _ if is_key(tag, &LYRICS_KEYS) => set(&mut self.lyrics, value),
+                _ if is_key(tag, &["SYLT"]) => {
+                    if let Value::Binary(ref data) = tag.value {
+                        synced = synced.or_else(|| parse_sylt(data));
+                    }
+                }
+                _ => {}
+            }
+        }
+
+        if self.lyrics.is_none() {
+            self.lyrics = synced;
+        }
+    }
+}
+
+#[inline]
+fn set(field: &mut Option<String>, value: &str) {
+    if field.is_none() && !value.is_empty() {
+        *field = Some(value.to_owned());
+    }
+}
+
+/// `USLT`, `USLT:eng`, `----:com.apple.iTunes:LYRICS`...
+#[inline]
+fn is_key(tag: &Tag, keys: &[&str]) -> bool {
+    tag.key
+        .to_uppercase()
+        .split(':')
+        .any(|part| keys.contains(&part))
+}
+
+/// `3` or `3/12`.
+fn parse_position(value: &str) -> (Option<u32>, Option<u32>) {
+    let mut parts = value.split('/').map(|part| part.trim().parse().ok());
+    let number = parts.next().flatten();
+    let total = parts.next().flatten();
+    (number, total)
+}
+
+/// `2019`, `2019-05-01`, `2019-05-01T00:00:00Z`...
+#[inline]
+fn parse_year(value: &str) -> Option<u32> {
+    value.get(..4).and_then(|year| year.parse().ok())
+}
+
+/// Decodes a null-terminated string, also returns the rest of `data`.
+fn read_text(data: &[u8], encoding: u8) -> Option<(String, &[u8])> {
+    match encoding {
+        // UTF-16 with or without BOM, terminated by two zero bytes.
+        1 | 2 => {
+            let end = data.chunks_exact(2).position(|c| c == [0, 0])? * 2;
+            let units = data[..end].chunks_exact(2);
+            let units: Vec<_> = match encoding {
+                2 => units.map(|c| u16::from_be_bytes([c[0], c[1]])).collect(),
+                _ if data.starts_with(&[0xFE, 0xFF]) => {
+                    units.map(|c| u16::from_be_bytes([c[0], c[1]])).collect()
+                }
+                _ => units.map(|c| u16::from_le_bytes([c[0], c[1]])).collect(),
+            };
+            let text = String::from_utf16_lossy(&units);
+            Some((
+                text.trim_start_matches('\u{feff}').to_owned(),
+                &data[end + 2..],
+            ))
+        }
+        // Latin-1, every byte is its own code point.
+        0 => {
+            let end = data.iter().position(|&b| b == 0)?;
+            let text = data[..end].iter().map(|&b| char::from(b)).collect();
+            Some((text, &data[end + 1..]))
+        }
+        _ => {
+            let end = data.iter().position(|&b| b == 0)?;
+            let text = String::from_utf8_lossy(&data[..end]).into_owned();
+            Some((text, &data[end + 1..]))
+        }
+    }
+}
+
+/// Converts an ID3v2 `SYLT` frame with millisecond timestamps to LRC.
+///
+/// encoding (1), language (3), timestamp format (1), content type (1),
+/// descriptor, then (text, timestamp (4)) pairs.
+fn parse_sylt(data: &[u8]) -> Option<String> {
+    let (&encoding, rest) = data.split_first()?;
+    // Timestamps in MPEG frames can not be converted without the stream.
+    if rest.get(3) != Some(&2) {
+        return None;
+    }
+    let (_, mut rest) = read_text(rest.get(5..)?, encoding)?;
+
+    let mut lines = Vec::new();
+    while let Some((text, next)) = read_text(rest, encoding) {
+        // A truncated frame keeps the lines before the cut.
+        let time = match next.get(..4) {
+            Some(time) => u32::from_be_bytes([time[0], time[1], time[2], time[3]]),
+            None => break,
+        };
+        rest = &next[4..];
+        let (minutes, millis) = (time / 60000, time % 60000);
+        lines.push(format!(
+            "[{:02}:{:02}.{:02}]{}",
+            minutes,
+            millis / 1000,
+            millis % 1000 / 10,
+            text.trim_start_matches('\n')
+        ));
+    }
+
+    (!lines.is_empty()).then(|| lines.join("\n"))
+}
+
+/// Reads the tags of a local file off the main thread, missing ones are
+/// left out. With `coverDir`, embedded artwork is cached there and `cover`
+/// is its URL.
+pub fn read_metadata(mut cx: FunctionContext) -> JsResult<JsPromise> {
+    let path = cx.argument::<JsString>(0)?.value(&mut cx);
+    let cover_dir = match cx.argument_opt(1) {
+        Some(dir) => Some(
+            dir.downcast_or_throw::<JsString, _>(&mut cx)?
+                .value(&mut cx),
+        ),
+        None => None,
+    };
+    let promise = cx
+        .task(move || {
+            guard(|| {
+                let metadata = Metadata::read(&path)?;
+                let cover = match (cover_dir, &metadata.cover) {
+                    (Some(dir), Some(cover)) => Some(file_url(&cover.cache(Path::new(&dir))?)),
+                    _ => None,
+                };
+                Ok((metadata, cover))
+            })
+        })
+        .promise(|mut cx, res| {
+            let (metadata, cover) = res.or_throw(&mut cx)?;
+            to_object(&mut cx, metadata, cover)
+        });
+
+    Ok(promise)
+}
+
+fn to_object<'a>(
+    cx: &mut impl Context<'a>,
+    metadata: Metadata,
+    cover: Option<String>,
+) -> JsResult<'a, JsObject> {
+    let obj = cx.empty_object();
+    let strings = [
+        ("title", metadata.title),
+        ("artist", metadata.artist),
+        ("album", metadata.album),
+        ("albumArtist", metadata.album_artist),
+        ("genre", metadata.genre),
+        ("lyrics", metadata.lyrics),
+        ("cover", cover),
+    ];
+    for (key, value) in strings {
+        if let Some(value) = value {
+            let value = cx.string(value);
+            obj.set(cx, key, value)?;
+        }
+    }
+    let numbers = [
+        ("track", metadata.track.map(f64::from)),
+        ("trackTotal", metadata.track_total.map(f64::from)),
+        ("disc", metadata.disc.map(f64::from)),
+        ("discTotal", metadata.disc_total.map(f64::from)),
+        ("year", metadata.year.map(f64::from)),
+        ("duration", metadata.duration),
+    ];
+    for (key, value) in numbers {
+        if let Some(value) = value {
+            let value = cx.number(value);
+            obj.set(cx, key, value)?;
+        }
+    }
+
+    Ok(obj)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A `SYLT` frame in milliseconds, the texts are already encoded.
+    fn sylt(encoding: u8, descriptor: &[u8], lines: &[(&[u8], u32)]) -> Vec<u8> {
+        let mut frame = vec![encoding, b'e', b'n', b'g', 2, 1];
+        frame.extend_from_slice(descriptor);
+        for (text, time) in lines {
+            frame.extend_from_slice(text);
+            frame.extend_from_slice(&time.to_be_bytes());
+        }
+        frame
+    }
+
+    #[test]
+    fn position() {
+        assert_eq!(parse_position("3"), (Some(3), None));
+        assert_eq!(parse_position("3/12"), (Some(3), Some(12)));
+        assert_eq!(parse_position(" 3 / 12 "), (Some(3), Some(12)));
+        assert_eq!(parse_position("/12"), (None, Some(12)));
+        assert_eq!(parse_position("A/B"), (None, None));
+    }
+
+    #[test]
+    fn year() {
+        assert_eq!(parse_year("2019"), Some(2019));
+        assert_eq!(parse_year("2019-05-01T00:00:00Z"), Some(2019));
+        assert_eq!(parse_year("19"), None);
+        assert_eq!(parse_year("May 2019"), None);
+    }
+
+    #[test]
+    fn text() {
+        assert_eq!(
+            read_text(&b"caf\xe9\0rest"[..], 0),
+            Some(("caf\u{e9}".to_owned(), &b"rest"[..]))
+        );
+        assert_eq!(
+            read_text("café\0".as_bytes(), 3),
+            Some(("café".to_owned(), &b""[..]))
+        );
+        // Little endian with BOM, big endian with BOM, then without.
+        assert_eq!(
+            read_text(&b"\xff\xfeh\0i\0\0\0!"[..], 1),
+            Some(("hi".to_owned(), &b"!"[..]))
+        );
+        assert_eq!(
+            read_text(&b"\xfe\xff\0h\0i\0\0"[..], 1),
+            Some(("hi".to_owned(), &b""[..]))
+        );
+        assert_eq!(
+            read_text(&b"\0h\0i\0\0"[..], 2),
+            Some(("hi".to_owned(), &b""[..]))
+        );
+        // A zero byte inside a code unit does not end it.
+        assert_eq!(
+            read_text(&b"\0h\0\0"[..], 2),
+            Some(("h".to_owned(), &b""[..]))
+        );
+
+        assert_eq!(read_text(&b"no end"[..], 0), None);
+        assert_eq!(read_text(&b"no end"[..], 3), None);
+        assert_eq!(read_text(&b"\0h\0i"[..], 2), None);
+    }
+
+    #[test]
+    fn sylt_to_lrc() {
+        let frame = sylt(
+            0,
+            b"\0",
+            &[(&b"\nfirst\0"[..], 1234), (&b"second\0"[..], 61_000)],
+        );
+        assert_eq!(
+            parse_sylt(&frame).as_deref(),
+            Some("[00:01.23]first\n[01:01.00]second")
+        );
+
+        let frame = sylt(1, b"\xff\xfed\0\0\0", &[(&b"\xff\xfeh\0i\0\0\0"[..], 500)]);
+        assert_eq!(parse_sylt(&frame).as_deref(), Some("[00:00.50]hi"));
+    }
+
+    #[test]
+    fn sylt_truncated() {
+        let mut frame = sylt(
+            3,
+            b"\0",
+            &[(&b"first\0"[..], 1000), (&b"second\0"[..], 2000)],
+        );
+        frame.truncate(frame.len() - 2);
+        assert_eq!(parse_sylt(&frame).as_deref(), Some("[00:01.00]first"));
+
+        frame.truncate(12);
+        assert_eq!(parse_sylt(&frame), None);
+        assert_eq!(parse_sylt(&[3, b'e', b'n']), None);
+        assert_eq!(parse_sylt(&[]), None);
+    }
+
+    #[test]
+    fn sylt_in_mpeg_frames() {
+        let mut frame = sylt(0, b"\0", &[(&b"first\0"[..], 1000)]);
+        frame[4] = 1;
+        assert_eq!(parse_sylt(&frame), None);
+    }
+}
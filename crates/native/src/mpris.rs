@@ -0,0 +1,644 @@
+//! `org.mpris.MediaPlayer2` on Linux. souvlaki only covers the basic
+//! controls, so the whole `Player` interface is served here.
+//!
+//! The session bus is found through `DBUS_SESSION_BUS_ADDRESS`, the tests
+//! point it at a private `dbus-daemon --session`.
+
+use {
+    crate::media::MediaEvent,
+    std::{
+        collections::HashMap,
+        sync::Arc,
+        time::{Duration, Instant},
+    },
+    zbus::{
+        block_on,
+        blocking::{connection, object_server::InterfaceRef, Connection},
+        fdo, interface,
+        zvariant::{ObjectPath, Value},
+        SignalContext,
+    },
+};
+
+const PATH: &str = "/org/mpris/MediaPlayer2";
+const NO_TRACK: &str = "/org/mpris/MediaPlayer2/TrackList/NoTrack";
+
+pub const MIN_RATE: f64 = 0.25;
+pub const MAX_RATE: f64 = 4.;
+
+type Handler = Arc<dyn Fn(MediaEvent) + Send + Sync>;
+
+#[derive(Default)]
+pub struct Metadata {
+    pub title: String,
+    pub album: String,
+    pub artist: String,
+    pub cover_url: Option<String>,
+    pub duration: Option<Duration>,
+}
+
+/// Properties the desktop can not change by itself, all optional.
+#[derive(Default)]
+pub struct Properties {
+    pub rate: Option<f64>,
+    pub volume: Option<f64>,
+    pub shuffle: Option<bool>,
+    pub loop_status: Option<String>,
+    pub can_go_next: Option<bool>,
+    pub can_go_previous: Option<bool>,
+}
+
+struct Root {
+    handler: Handler,
+}
+
+#[interface(name = "org.mpris.MediaPlayer2")]
+impl Root {
+    fn raise(&self) {
+        (self.handler)(MediaEvent::Raise)
+    }
+
+    fn quit(&self) {
+        (self.handler)(MediaEvent::Quit)
+    }
+
+    #[zbus(property(emits_changed_signal = "const"))]
+    fn can_quit(&self) -> bool {
+        true
+    }
+
+    #[zbus(property(emits_changed_signal = "const"))]
+    fn can_raise(&self) -> bool {
+        true
+    }
+
+    #[zbus(property(emits_changed_signal = "const"))]
+    fn has_track_list(&self) -> bool {
+        false
+    }
+
+    #[zbus(property(emits_changed_signal = "const"))]
+    fn identity(&self) -> String {
+        "Cloudmusic VSCode".to_owned()
+    }
+
+    #[zbus(property(emits_changed_signal = "const"))]
+    fn supported_uri_schemes(&self) -> Vec<String> {
+        vec!["file".to_owned(), "http".to_owned(), "https".to_owned()]
+    }
+
+    #[zbus(property(emits_changed_signal = "const"))]
+    fn supported_mime_types(&self) -> Vec<String> {
+        [
+            "audio/mpeg",
+            "audio/flac",
+            "audio/wav",
+            "audio/aac",
+            "audio/mp4",
+            "audio/ogg",
+        ]
+        .map(String::from)
+        .to_vec()
+    }
+}
+
+struct Player {
+    handler: Handler,
+    playing: bool,
+    stopped: bool,
+    // Where the track was at `at`.
+    position: Duration,
+    at: Instant,
+    metadata: Metadata,
+    // Bumped for every track so `SetPosition` can reject stale requests.
+    track: u64,
+    rate: f64,
+    volume: f64,
+    shuffle: bool,
+    loop_status: String,
+    can_go_next: bool,
+    can_go_previous: bool,
+}
+
+impl Player {
+    fn new(handler: Handler) -> Self {
+        Self {
+            handler,
+            playing: false,
+            stopped: true,
+            position: Duration::ZERO,
+            at: Instant::now(),
+            metadata: Metadata::default(),
+            track: 0,
+            rate: 1.,
+            volume: 1.,
+            shuffle: false,
+            loop_status: "None".to_owned(),
+            can_go_next: true,
+            can_go_previous: true,
+        }
+    }
+
+    fn track_id(&self) -> ObjectPath<'static> {
+        let path = match self.stopped {
+            true => NO_TRACK.to_owned(),
+            false => format!("/org/cloudmusic/track/{}", self.track),
+        };
+        ObjectPath::try_from(path).unwrap()
+    }
+
+    #[inline]
+    fn elapsed(&self) -> Duration {
+        match self.playing {
+            true => self.position + self.at.elapsed().mul_f64(self.rate),
+            false => self.position,
+        }
+    }
+}
+
+#[interface(name = "org.mpris.MediaPlayer2.Player")]
+impl Player {
+    fn next(&self) {
+        if self.can_go_next {
+            (self.handler)(MediaEvent::Next)
+        }
+    }
+
+    fn previous(&self) {
+        if self.can_go_previous {
+            (self.handler)(MediaEvent::Previous)
+        }
+    }
+
+    fn pause(&self) {
+        (self.handler)(MediaEvent::Pause)
+    }
+
+    fn play_pause(&self) {
+        (self.handler)(MediaEvent::Toggle)
+    }
+
+    fn stop(&self) {
+        (self.handler)(MediaEvent::Stop)
+    }
+
+    fn play(&self) {
+        (self.handler)(MediaEvent::Play)
+    }
+
+    /// `offset` is in microseconds, negative values seek backwards.
+    fn seek(&self, offset: i64) {
+        if !self.stopped {
+            (self.handler)(MediaEvent::SeekBy {
+                forward: offset >= 0,
+                offset: offset.unsigned_abs() as f64 / 1e6,
+            })
+        }
+    }
+
+    /// Ignored if `track_id` is not the current track or `position` is
+    /// outside of it, as the specification requires.
+    fn set_position(&self, track_id: ObjectPath<'_>, position: i64) {
+        let position = Duration::from_micros(match u64::try_from(position) {
+            Ok(position) => position,
+            Err(_) => return,
+        });
+        if track_id != self.track_id() || self.metadata.duration.map_or(false, |d| position > d) {
+            return;
+        }
+        (self.handler)(MediaEvent::SetPosition(position.as_secs_f64()))
+    }
+
+    fn open_uri(&self, uri: String) -> fdo::Result<()> {
+        if !["file://", "http://", "https://"]
+            .iter()
+            .any(|scheme| uri.starts_with(scheme))
+        {
+            return Err(fdo::Error::InvalidArgs(format!("Unsupported URI: {}", uri)));
+        }
+        (self.handler)(MediaEvent::OpenUri(uri));
+        Ok(())
+    }
+
+    #[zbus(signal)]
+    async fn seeked(ctxt: &SignalContext<'_>, position: i64) -> zbus::Result<()>;
+
+    #[zbus(property)]
+    fn playback_status(&self) -> String {
+        match (self.stopped, self.playing) {
+            (true, _) => "Stopped",
+            (false, true) => "Playing",
+            (false, false) => "Paused",
+        }
+        .to_owned()
+    }
+
+    #[zbus(property)]
+    fn loop_status(&self) -> String {
+        self.loop_status.clone()
+    }
+
+    // Changes are only forwarded, JS reports back what it applied.
+    #[zbus(property)]
+    fn set_loop_status(&mut self, value: String) -> fdo::Result<()> {
+        if !matches!(value.as_str(), "None" | "Track" | "Playlist") {
+            return Err(fdo::Error::InvalidArgs(format!(
+                "Invalid loop status: {}",
+                value
+            )));
+        }
+        (self.handler)(MediaEvent::SetLoopStatus(value));
+        Ok(())
+    }
+
+    #[zbus(property)]
+    fn rate(&self) -> f64 {
+        self.rate
+    }
+
+    #[zbus(property)]
+    fn set_rate(&mut self, value: f64) {
+        // A rate of 0 means pause.
+        match value {
+            _ if value == 0. => (self.handler)(MediaEvent::Pause),
+            _ if (MIN_RATE..=MAX_RATE).contains(&value) => {
+                (self.handler)(MediaEvent::SetRate(value))
+            }
+            _ => {}
+        }
+    }
+
+    #[zbus(property)]
+    fn shuffle(&self) -> bool {
+        self.shuffle
+    }
+
+    #[zbus(property)]
+    fn set_shuffle(&mut self, value: bool) {
+        (self.handler)(MediaEvent::SetShuffle(value))
+    }
+
+    #[zbus(property)]
+    fn metadata(&self) -> HashMap<String, Value<'static>> {
+        let mut map = HashMap::new();
+        map.insert("mpris:trackid".to_owned(), Value::from(self.track_id()));
+        if self.stopped {
+            return map;
+        }
+
+        let metadata = &self.metadata;
+        map.insert(
+            "xesam:title".to_owned(),
+            Value::from(metadata.title.clone()),
+        );
+        if !metadata.album.is_empty() {
+            map.insert(
+                "xesam:album".to_owned(),
+                Value::from(metadata.album.clone()),
+            );
+        }
+        if !metadata.artist.is_empty() {
+            let artists: Vec<_> = metadata.artist.split('/').map(str::to_owned).collect();
+            map.insert("xesam:artist".to_owned(), Value::from(artists));
+        }
+        if let Some(ref url) = metadata.cover_url {
+            map.insert("mpris:artUrl".to_owned(), Value::from(url.clone()));
+        }
+        if let Some(duration) = metadata.duration {
+            map.insert(
+                "mpris:length".to_owned(),
+                Value::from(duration.as_micros() as i64),
+            );
+        }
+        map
+    }
+
+    #[zbus(property)]
+    fn volume(&self) -> f64 {
+        self.volume
+    }
+
+    #[zbus(property)]
+    fn set_volume(&mut self, value: f64) {
+        (self.handler)(MediaEvent::SetVolume(value.clamp(0., 1.)))
+    }
+
+    // Changes continuously, clients poll it and listen to `Seeked`.
+    #[zbus(property(emits_changed_signal = "false"))]
+    fn position(&self) -> i64 {
+        self.elapsed().as_micros() as i64
+    }
+
+    #[zbus(property(emits_changed_signal = "const"))]
+    fn minimum_rate(&self) -> f64 {
+        MIN_RATE
+    }
+
+    #[zbus(property(emits_changed_signal = "const"))]
+    fn maximum_rate(&self) -> f64 {
+        MAX_RATE
+    }
+
+    #[zbus(property)]
+    fn can_go_next(&self) -> bool {
+        self.can_go_next
+    }
+
+    #[zbus(property)]
+    fn can_go_previous(&self) -> bool {
+        self.can_go_previous
+    }
+
+    #[zbus(property)]
+    fn can_play(&self) -> bool {
+        true
+    }
+
+    #[zbus(property)]
+    fn can_pause(&self) -> bool {
+        true
+    }
+
+    #[zbus(property)]
+    fn can_seek(&self) -> bool {
+        !self.stopped
+    }
+
+    #[zbus(property(emits_changed_signal = "const"))]
+    fn can_control(&self) -> bool {
+        true
+    }
+}
+
+pub struct Mpris {
+    connection: Connection,
+    name: String,
+}
+
+impl Mpris {
+    /// Serves `/org/mpris/MediaPlayer2`, it shows up as
+    /// `org.mpris.MediaPlayer2.{name}` once it is made visible.
+    pub fn new(
+        name: &str,
+        handler: impl Fn(MediaEvent) + Send + Sync + 'static,
+    ) -> zbus::Result<Self> {
+        let handler: Handler = Arc::new(handler);
+        let connection = connection::Builder::session()?
+            .serve_at(
+                PATH,
+                Root {
+                    handler: handler.clone(),
+                },
+            )?
+            .serve_at(PATH, Player::new(handler))?
+            .build()?;
+        Ok(Self {
+            connection,
+            name: format!("org.mpris.MediaPlayer2.{}", name),
+        })
+    }
+
+    /// Owns or releases the name, the state is kept in between.
+    pub fn set_visible(&self, visible: bool) -> zbus::Result<()> {
+        match visible {
+            true => self.connection.request_name(self.name.as_str()),
+            false => self.connection.release_name(self.name.as_str()).map(|_| ()),
+        }
+    }
+
+    #[inline]
+    fn player(&self) -> zbus::Result<InterfaceRef<Player>> {
+        self.connection.object_server().interface(PATH)
+    }
+
+    pub fn set_metadata(&self, metadata: Metadata) -> zbus::Result<()> {
+        let iface = self.player()?;
+        let mut player = iface.get_mut();
+        player.metadata = metadata;
+        player.track += 1;
+        player.stopped = false;
+        let ctxt = iface.signal_context();
+        block_on(async {
+            player.metadata_changed(ctxt).await?;
+            player.playback_status_changed(ctxt).await?;
+            player.can_seek_changed(ctxt).await
+        })
+    }
+
+    pub fn set_playback(&self, playing: bool, position: Duration) -> zbus::Result<()> {
+        let iface = self.player()?;
+        let mut player = iface.get_mut();
+        let changed = player.playing != playing || player.stopped;
+        player.playing = playing;
+        player.stopped = false;
+        player.position = position;
+        player.at = Instant::now();
+        match changed {
+            true => block_on(player.playback_status_changed(iface.signal_context())),
+            false => Ok(()),
+        }
+    }
+
+    /// Emits `Seeked`, for jumps that did not come from the desktop too.
+    pub fn seeked(&self, position: Duration) -> zbus::Result<()> {
+        let iface = self.player()?;
+        let mut player = iface.get_mut();
+        player.position = position;
+        player.at = Instant::now();
+        block_on(Player::seeked(
+            iface.signal_context(),
+            position.as_micros() as i64,
+        ))
+    }
+
+    pub fn set_properties(&self, properties: Properties) -> zbus::Result<()> {
+        let iface = self.player()?;
+        let mut player = iface.get_mut();
+        let ctxt = iface.signal_context();
+
+        // Pushed with every playback change by an attached player.
+        let rate = properties.rate.map(|rate| rate.clamp(MIN_RATE, MAX_RATE));
+        if let Some(rate) = rate.filter(|rate| *rate != player.rate) {
+            // Keeps the extrapolated position continuous.
+            player.position = player.elapsed();
+            player.at = Instant::now();
+            player.rate = rate;
+            block_on(player.rate_changed(ctxt))?;
+        }
+        if let Some(volume) = properties.volume {
+            player.volume = volume.clamp(0., 1.);
+            block_on(player.volume_changed(ctxt))?;
+        }
+        if let Some(shuffle) = properties.shuffle {
+            player.shuffle = shuffle;
+            block_on(player.shuffle_changed(ctxt))?;
+        }
+        if let Some(loop_status) = properties.loop_status {
+            player.loop_status = loop_status;
+            block_on(player.loop_status_changed(ctxt))?;
+        }
+        if let Some(can_go_next) = properties.can_go_next {
+            player.can_go_next = can_go_next;
+            block_on(player.can_go_next_changed(ctxt))?;
+        }
+        if let Some(can_go_previous) = properties.can_go_previous {
+            player.can_go_previous = can_go_previous;
+            block_on(player.can_go_previous_changed(ctxt))?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use {
+        super::*,
+        std::{
+            io::{self, BufRead, BufReader},
+            process::{Child, Command, Stdio},
+            sync::mpsc::{channel, Receiver},
+        },
+        zbus::{
+            blocking::{proxy, Proxy},
+            proxy::CacheProperties,
+        },
+    };
+
+    const TIMEOUT: Duration = Duration::from_secs(5);
+
+    /// A private session bus, stopped when dropped.
+    struct Daemon(Child);
+
+    impl Drop for Daemon {
+        fn drop(&mut self) {
+            let _ = self.0.kill();
+            let _ = self.0.wait();
+        }
+    }
+
+    /// `None` without `dbus-daemon`.
+    fn start_daemon() -> Option<Daemon> {
+        let child = Command::new("dbus-daemon")
+            .args(["--session", "--nofork", "--print-address"])
+            .stdout(Stdio::piped())
+            .spawn();
+        let mut daemon = match child {
+            Ok(child) => Daemon(child),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return None,
+            Err(err) => panic!("{err}"),
+        };
+
+        let mut address = String::new();
+        let stdout = daemon.0.stdout.take().unwrap();
+        BufReader::new(stdout).read_line(&mut address).unwrap();
+        // Only this test connects to a session bus.
+        std::env::set_var("DBUS_SESSION_BUS_ADDRESS", address.trim());
+        Some(daemon)
+    }
+
+    #[inline]
+    fn next(events: &Receiver<MediaEvent>) -> MediaEvent {
+        events.recv_timeout(TIMEOUT).unwrap()
+    }
+
+    #[test]
+    fn player_interface() {
+        let Some(_daemon) = start_daemon() else {
+            eprintln!("dbus-daemon is missing, skipped");
+            return;
+        };
+
+        let (sender, events) = channel();
+        let mpris = Mpris::new("test", move |event| {
+            let _ = sender.send(event);
+        })
+        .unwrap();
+        mpris.set_visible(true).unwrap();
+
+        let client = Connection::session().unwrap();
+        let player = proxy::Builder::<Proxy>::new(&client)
+            .destination("org.mpris.MediaPlayer2.test")
+            .unwrap()
+            .path(PATH)
+            .unwrap()
+            .interface("org.mpris.MediaPlayer2.Player")
+            .unwrap()
+            .cache_properties(CacheProperties::No)
+            .build()
+            .unwrap();
+
+        // Nothing to seek in yet.
+        player.call::<_, _, ()>("Seek", &(5_000_000i64,)).unwrap();
+        assert!(events.try_recv().is_err());
+
+        mpris
+            .set_metadata(Metadata {
+                title: "A".to_owned(),
+                duration: Some(Duration::from_secs(180)),
+                ..Default::default()
+            })
+            .unwrap();
+        // The first track.
+        let track = ObjectPath::try_from("/org/cloudmusic/track/1").unwrap();
+
+        player.call::<_, _, ()>("Seek", &(-2_500_000i64,)).unwrap();
+        assert_eq!(
+            next(&events),
+            MediaEvent::SeekBy {
+                forward: false,
+                offset: 2.5
+            }
+        );
+
+        // A stale track and a position past the end are ignored.
+        let stale = ObjectPath::try_from("/org/cloudmusic/track/0").unwrap();
+        player
+            .call::<_, _, ()>("SetPosition", &(&stale, 10_000_000i64))
+            .unwrap();
+        player
+            .call::<_, _, ()>("SetPosition", &(&track, 200_000_000i64))
+            .unwrap();
+        assert!(events.try_recv().is_err());
+        player
+            .call::<_, _, ()>("SetPosition", &(&track, 10_000_000i64))
+            .unwrap();
+        assert_eq!(next(&events), MediaEvent::SetPosition(10.));
+
+        let mut seeked = player.receive_signal("Seeked").unwrap();
+        mpris.seeked(Duration::from_secs(42)).unwrap();
+        let signal = seeked.next().unwrap();
+        assert_eq!(signal.body().deserialize::<i64>().unwrap(), 42_000_000);
+
+        mpris
+            .set_properties(Properties {
+                rate: Some(8.),
+                loop_status: Some("Playlist".to_owned()),
+                ..Default::default()
+            })
+            .unwrap();
+        assert_eq!(player.get_property::<f64>("Rate").unwrap(), MAX_RATE);
+        assert_eq!(
+            player.get_property::<String>("LoopStatus").unwrap(),
+            "Playlist"
+        );
+
+        player.set_property("Rate", 1.5).unwrap();
+        assert_eq!(next(&events), MediaEvent::SetRate(1.5));
+        player.set_property("Rate", 0.).unwrap();
+        assert_eq!(next(&events), MediaEvent::Pause);
+        // Out of range, dropped.
+        player.set_property("Rate", 10.).unwrap();
+
+        player.set_property("LoopStatus", "Track").unwrap();
+        assert_eq!(next(&events), MediaEvent::SetLoopStatus("Track".to_owned()));
+        assert!(player.set_property("LoopStatus", "Bogus").is_err());
+
+        let uri = "file:///tmp/a%20b.mp3";
+        player.call::<_, _, ()>("OpenUri", &(uri,)).unwrap();
+        assert_eq!(next(&events), MediaEvent::OpenUri(uri.to_owned()));
+        assert!(player
+            .call::<_, _, ()>("OpenUri", &("ftp://host/a.mp3",))
+            .is_err());
+        assert!(events.try_recv().is_err());
+    }
+}
@@ -0,0 +1,109 @@
+//! Opus decoding through libopus, symphonia only demuxes it.
+
+use {
+    ::opus::{Channels as OpusChannels, Decoder as LibOpus},
+    std::sync::{Mutex, PoisonError},
+    symphonia::core::{
+        audio::{AsAudioBufferRef, AudioBuffer, AudioBufferRef, Channels, Signal, SignalSpec},
+        codecs::{
+            CodecDescriptor, CodecParameters, Decoder, DecoderOptions, FinalizeResult,
+            CODEC_TYPE_OPUS,
+        },
+        errors::{decode_error, unsupported_error, Result},
+        formats::Packet,
+        support_codec,
+    },
+};
+
+// Opus is always decoded at 48 kHz.
+const SAMPLE_RATE: u32 = 48000;
+
+// Of the longest packet, 120 ms.
+const MAX_FRAMES: usize = SAMPLE_RATE as usize * 120 / 1000;
+
+pub struct OpusDecoder {
+    // Only there to be `Sync`, `get_mut` never locks.
+    decoder: Mutex<LibOpus>,
+    params: CodecParameters,
+    // Interleaved, as libopus writes it.
+    pcm: Vec<f32>,
+    buf: AudioBuffer<f32>,
+}
+
+impl Decoder for OpusDecoder {
+    fn try_new(params: &CodecParameters, _: &DecoderOptions) -> Result<Self> {
+        let (channels, layout) = match params.channels.map(|channels| channels.count()) {
+            Some(1) => (OpusChannels::Mono, Channels::FRONT_LEFT),
+            Some(2) | None => (
+                OpusChannels::Stereo,
+                Channels::FRONT_LEFT | Channels::FRONT_RIGHT,
+            ),
+            // Needs the multistream decoder.
+            Some(_) => return unsupported_error("opus: more than 2 channels"),
+        };
+        let decoder = match LibOpus::new(SAMPLE_RATE, channels) {
+            Ok(decoder) => decoder,
+            Err(_) => return unsupported_error("opus: libopus failed to start"),
+        };
+        let spec = SignalSpec::new(SAMPLE_RATE, layout);
+
+        Ok(Self {
+            decoder: Mutex::new(decoder),
+            params: params.clone(),
+            pcm: vec![0.; MAX_FRAMES * spec.channels.count()],
+            buf: AudioBuffer::new(MAX_FRAMES as u64, spec),
+        })
+    }
+
+    fn supported_codecs() -> &'static [CodecDescriptor] {
+        &[support_codec!(CODEC_TYPE_OPUS, "opus", "Opus")]
+    }
+
+    fn reset(&mut self) {
+        let decoder = self
+            .decoder
+            .get_mut()
+            .unwrap_or_else(PoisonError::into_inner);
+        let _ = decoder.reset_state();
+    }
+
+    #[inline]
+    fn codec_params(&self) -> &CodecParameters {
+        &self.params
+    }
+
+    fn decode(&mut self, packet: &Packet) -> Result<AudioBufferRef> {
+        let decoder = self
+            .decoder
+            .get_mut()
+            .unwrap_or_else(PoisonError::into_inner);
+        let frames = match decoder.decode_float(packet.buf(), &mut self.pcm, false) {
+            Ok(frames) => frames,
+            Err(_) => return decode_error("opus: invalid packet"),
+        };
+
+        let channels = self.buf.spec().channels.count();
+        self.buf.clear();
+        self.buf.render_reserved(Some(frames));
+        for channel in 0..channels {
+            for (i, sample) in self.buf.chan_mut(channel).iter_mut().enumerate() {
+                *sample = self.pcm[i * channels + channel];
+            }
+        }
+        // The encoder delay and padding, like the other symphonia decoders.
+        self.buf
+            .trim(packet.trim_start() as usize, packet.trim_end() as usize);
+
+        Ok(self.buf.as_audio_buffer_ref())
+    }
+
+    #[inline]
+    fn finalize(&mut self) -> FinalizeResult {
+        Default::default()
+    }
+
+    #[inline]
+    fn last_decoded(&self) -> AudioBufferRef {
+        self.buf.as_audio_buffer_ref()
+    }
+}
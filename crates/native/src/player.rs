@@ -1,10 +1,29 @@
 use {
+    crate::{
+        decoder::SymphoniaSource,
+        device::{find_device, open_output, DeviceWatch, Fallback, Output},
+        eq::{Band, BandKind, Equalize, Equalizer},
+        error::{guard, lock, OrThrow, PlayerError},
+        fade::{Curve, Fade, Fader},
+        gain::{Gain, GainMode, Normalizer, ReplayGain, TrackGain},
+        http::{is_remote, HttpSource},
+        prefetch::Prefetch,
+    },
+    formats::{supported_formats, StreamInfo},
     neon::prelude::*,
-    rodio::{Decoder, OutputStream, OutputStreamHandle, PlayError, Sink, Source},
+    rodio::{
+        cpal::{self, traits::HostTrait},
+        source::EmptyCallback,
+        DeviceTrait, Sink, Source,
+    },
     std::{
-        cell::RefCell,
-        fs::File,
-        io::BufReader,
+        collections::VecDeque,
+        fs,
+        sync::{
+            mpsc::{self, Receiver, RecvTimeoutError, Sender},
+            Arc, Mutex, MutexGuard, Weak,
+        },
+        thread::{self, JoinHandle},
         time::{Duration, Instant},
     },
 };
@@ -30,10 +49,11 @@ impl Status {
         }
     }
 
+    /// The track keeps playing for `ramp` while it fades out.
     #[inline]
-    fn stop(&mut self, speed: f64) {
+    fn stop(&mut self, speed: f64, ramp: Duration) {
         if let Status::Playing(start, extra) = *self {
-            *self = Status::Stopped(start.elapsed().mul_f64(speed) + extra)
+            *self = Status::Stopped(start.elapsed().mul_f64(speed) + extra + ramp)
         }
     }
 
@@ -49,6 +69,13 @@ impl Status {
         *self = Status::Stopped(Duration::from_nanos(0));
     }
 
+    #[inline]
+    fn restart(&mut self) {
+        if let Status::Playing(..) = *self {
+            *self = Status::Playing(Instant::now(), Duration::from_nanos(0))
+        }
+    }
+
     #[inline]
     fn store(&mut self, speed: f64) {
         if let Status::Playing(start, extra) = *self {
@@ -56,37 +83,455 @@ impl Status {
         }
     }
 
+    /// The jump happens after `delay`, once the old position faded out.
     #[inline]
-    fn seek(&mut self, pos: Duration) {
+    fn seek(&mut self, pos: Duration, delay: Duration) {
         match self {
             Status::Stopped(d) => *d = pos,
             Status::Playing(start, extra) => {
-                *start = Instant::now();
+                *start = Instant::now() + delay;
                 *extra = pos;
             }
         }
     }
 }
 
+// How often the audio thread checks for the end of the track.
+const TICK_INTERVAL: Duration = Duration::from_millis(100);
+
+#[derive(Clone, Copy, PartialEq)]
+enum PlayerState {
+    Idle,
+    Loading,
+    Playing,
+    Paused,
+    Ended,
+    Error,
+}
+
+impl PlayerState {
+    #[inline]
+    fn name(self) -> &'static str {
+        match self {
+            PlayerState::Idle => "idle",
+            PlayerState::Loading => "loading",
+            PlayerState::Playing => "playing",
+            PlayerState::Paused => "paused",
+            PlayerState::Ended => "ended",
+            PlayerState::Error => "error",
+        }
+    }
+}
+
+enum PlayerEvent {
+    TrackChanged,
+    DeviceChanged { paused: bool },
+    Ended,
+    Position(f64),
+    Error(PlayerError),
+    StateChanged(PlayerState),
+}
+
+impl PlayerEvent {
+    #[inline]
+    fn name(&self) -> &'static str {
+        match self {
+            PlayerEvent::TrackChanged => "trackChanged",
+            PlayerEvent::DeviceChanged { .. } => "deviceChanged",
+            PlayerEvent::Ended => "ended",
+            PlayerEvent::Position(_) => "position",
+            PlayerEvent::Error(_) => "error",
+            PlayerEvent::StateChanged(_) => "stateChanged",
+        }
+    }
+}
+
+#[derive(Clone)]
+struct Events {
+    channel: Channel,
+    handler: Arc<Root<JsFunction>>,
+}
+
+impl Events {
+    fn emit(&self, event: PlayerEvent) {
+        let handler = self.handler.clone();
+
+        self.channel.send(move |mut cx| {
+            let this = cx.undefined();
+            let obj = cx.empty_object();
+            let type_ = cx.string(event.name());
+            obj.set(&mut cx, "type", type_)?;
+            match event {
+                PlayerEvent::DeviceChanged { paused } => {
+                    let paused = cx.boolean(paused);
+                    obj.set(&mut cx, "paused", paused)?;
+                }
+                PlayerEvent::Position(position) => {
+                    let position = cx.number(position);
+                    obj.set(&mut cx, "position", position)?;
+                }
+                PlayerEvent::Error(err) => {
+                    let message = cx.string(err.to_string());
+                    let kind = cx.string(err.kind());
+                    obj.set(&mut cx, "message", message)?;
+                    obj.set(&mut cx, "kind", kind)?;
+                }
+                PlayerEvent::StateChanged(state) => {
+                    let state = cx.string(state.name());
+                    obj.set(&mut cx, "state", state)?;
+                }
+                _ => {}
+            }
+            handler
+                .to_inner(&mut cx)
+                .call(&mut cx, this, [obj.upcast()])?;
+            Ok(())
+        });
+    }
+}
+
+/// What the JS thread can read without waiting for the audio thread,
+/// published after every command and tick.
+struct Snapshot {
+    state: PlayerState,
+    position: f64,
+    // When `position` was taken, it keeps running while playing.
+    at: Instant,
+    speed: f64,
+    playing: bool,
+    empty: bool,
+    info: Option<StreamInfo>,
+}
+
+impl Snapshot {
+    #[inline]
+    fn new() -> Self {
+        Self {
+            state: PlayerState::Idle,
+            position: 0.,
+            at: Instant::now(),
+            speed: 1.,
+            playing: false,
+            empty: true,
+            info: None,
+        }
+    }
+
+    #[inline]
+    fn position(&self) -> f64 {
+        match self.playing {
+            true => self.position + self.at.elapsed().as_secs_f64() * self.speed,
+            false => self.position,
+        }
+    }
+}
+
+/// What a media session shows, pushed from the audio thread whenever it
+/// changes. `seeked` is set when the position jumped, after a seek or when
+/// the track changed.
+#[derive(Clone, Copy)]
+pub struct Playback {
+    pub playing: bool,
+    pub position: f64,
+    pub speed: f64,
+    pub seeked: bool,
+}
+
+pub type Observer = Box<dyn FnMut(Playback) + Send>;
+
+// Further off from where the position should be, it is a jump.
+const SEEK_THRESHOLD: f64 = 0.5;
+
+struct Link {
+    observer: Observer,
+    // The last one that was pushed, and when.
+    last: Option<(Playback, Instant)>,
+}
+
+impl Link {
+    fn update(&mut self, state: PlayerState, playback: Playback, at: Instant) {
+        // Shown again once it is playing or paused.
+        if state == PlayerState::Loading {
+            return;
+        }
+
+        let (changed, seeked) = match self.last {
+            Some((last, last_at)) => {
+                let expected = match last.playing {
+                    true => {
+                        let elapsed = at.saturating_duration_since(last_at).as_secs_f64();
+                        last.position + elapsed * last.speed
+                    }
+                    false => last.position,
+                };
+                (
+                    last.playing != playback.playing || last.speed != playback.speed,
+                    (playback.position - expected).abs() > SEEK_THRESHOLD,
+                )
+            }
+            None => (true, false),
+        };
+        if changed || seeked {
+            let playback = Playback { seeked, ..playback };
+            (self.observer)(playback);
+            self.last = Some((playback, at));
+        }
+    }
+}
+
+type Command = Box<dyn FnOnce(&mut Player) + Send>;
+
+/// Settles the promise of a command that opened a track off the audio
+/// thread, once that is done.
+type Done = Box<dyn FnOnce(&mut Player, Result<(), PlayerError>) + Send>;
+
+/// Owns the audio thread, the `Player` is created and used only there.
+/// Dropping the handle closes the channel, which ends the thread.
+pub struct PlayerHandle {
+    // Remotes only hold it weakly, so they do not keep the thread alive.
+    commands: Arc<Sender<Command>>,
+    snapshot: Arc<Mutex<Snapshot>>,
+    // Both are lock-free, so they are set without a round trip.
+    equalizer: Arc<Equalizer>,
+    normalizer: Arc<Normalizer>,
+}
+
+impl Finalize for PlayerHandle {}
+
+impl PlayerHandle {
+    fn spawn() -> Result<Self, PlayerError> {
+        let (commands, receiver) = mpsc::channel();
+        let commands = Arc::new(commands);
+        let snapshot = Arc::new(Mutex::new(Snapshot::new()));
+        let equalizer = Equalizer::new();
+        let normalizer = Normalizer::new();
+        let remote = Remote {
+            commands: Arc::downgrade(&commands),
+        };
+
+        let shared = (snapshot.clone(), equalizer.clone(), normalizer.clone());
+        thread::Builder::new()
+            .name("cloudmusic-player".to_owned())
+            .spawn(move || {
+                let (snapshot, equalizer, normalizer) = shared;
+                Player::new(remote, snapshot, equalizer, normalizer).run(receiver)
+            })?;
+
+        Ok(Self {
+            commands,
+            snapshot,
+            equalizer,
+            normalizer,
+        })
+    }
+
+    #[inline]
+    fn send(&self, command: impl FnOnce(&mut Player) + Send + 'static) -> Result<(), PlayerError> {
+        self.commands
+            .send(Box::new(command))
+            .map_err(|_| PlayerError::Panic("The audio thread has stopped".to_owned()))
+    }
+
+    /// Fails once the audio thread panicked while publishing it.
+    #[inline]
+    fn snapshot(&self) -> Result<MutexGuard<Snapshot>, PlayerError> {
+        self.snapshot
+            .lock()
+            .map_err(|_| PlayerError::Panic("The audio thread has stopped".to_owned()))
+    }
+
+    /// Calls `observer` on the audio thread when the playback changes, it
+    /// replaces the previous one.
+    #[inline]
+    pub fn observe(&self, observer: Observer) -> Result<(), PlayerError> {
+        self.remote().observe(observer)
+    }
+
+    #[inline]
+    pub fn remote(&self) -> Remote {
+        Remote {
+            commands: Arc::downgrade(&self.commands),
+        }
+    }
+}
+
+/// Controls the player from other threads, it stops working once the
+/// handle is dropped.
+#[derive(Clone)]
+pub struct Remote {
+    commands: Weak<Sender<Command>>,
+}
+
+impl Remote {
+    fn send(&self, command: impl FnOnce(&mut Player) + Send + 'static) -> Result<(), PlayerError> {
+        self.commands
+            .upgrade()
+            .and_then(|commands| commands.send(Box::new(command)).ok())
+            .ok_or_else(|| PlayerError::Panic("The audio thread has stopped".to_owned()))
+    }
+
+    /// See `PlayerHandle::observe`.
+    #[inline]
+    pub fn observe(&self, observer: Observer) -> Result<(), PlayerError> {
+        self.send(move |player| {
+            player.link = Some(Link {
+                observer,
+                last: None,
+            })
+        })
+    }
+
+    #[inline]
+    pub fn play(&self) -> Result<(), PlayerError> {
+        self.send(|player| {
+            player.recover();
+            if !player.empty() {
+                player.play();
+            }
+        })
+    }
+
+    #[inline]
+    pub fn pause(&self) -> Result<(), PlayerError> {
+        self.send(Player::pause)
+    }
+
+    /// Decided on the audio thread, so two presses in a row cancel out.
+    #[inline]
+    pub fn toggle(&self) -> Result<(), PlayerError> {
+        self.send(
+            |player| match player.state == PlayerState::Playing && !player.paused() {
+                true => player.pause(),
+                false => {
+                    player.recover();
+                    if !player.empty() {
+                        player.play();
+                    }
+                }
+            },
+        )
+    }
+
+    #[inline]
+    pub fn stop(&self) -> Result<(), PlayerError> {
+        self.send(Player::stop)
+    }
+
+    #[inline]
+    pub fn seek(&self, offset: f64) -> Result<(), PlayerError> {
+        self.send(move |player| player.seek(offset))
+    }
+
+    #[inline]
+    pub fn seek_to(&self, seconds: f64) -> Result<(), PlayerError> {
+        let pos = Duration::try_from_secs_f64(seconds.max(0.)).unwrap_or_default();
+        self.send(move |player| player.seek_to(pos))
+    }
+}
+
+type TrackSource = Gain<Equalize<Prefetch>>;
+
+/// A track opened and seeked, off the audio thread when it is new.
+struct Opened {
+    source: Prefetch,
+    info: StreamInfo,
+    replay_gain: ReplayGain,
+    // When it was started for this track.
+    download: Option<HttpSource>,
+    // Where it starts, from the start when seeking there failed.
+    pos: Duration,
+}
+
+impl Opened {
+    /// Streams from `download` when the track was opened before, this may
+    /// wait for the network.
+    fn new(url: &str, download: Option<&HttpSource>, pos: Duration) -> Result<Self, PlayerError> {
+        let (mut source, download) = match download {
+            // The track keeps the download.
+            Some(download) => (SymphoniaSource::stream(download.reopen(), url)?, None),
+            None if is_remote(url) => {
+                let download = HttpSource::new(url)?;
+                (
+                    SymphoniaSource::stream(download.reopen(), url)?,
+                    Some(download),
+                )
+            }
+            None => (SymphoniaSource::open(url)?, None),
+        };
+        // Seeking before the first sample, it starts over when that fails.
+        let pos = match pos.is_zero() || source.try_seek(pos).is_ok() {
+            true => pos,
+            false => Duration::ZERO,
+        };
+
+        Ok(Self {
+            info: source.info(),
+            replay_gain: source.replay_gain(),
+            source: Prefetch::new(source),
+            download,
+            pos,
+        })
+    }
+}
+
+struct Track {
+    url: String,
+    info: StreamInfo,
+    // Kept to reopen it on another device without measuring or
+    // downloading it again.
+    gain: Arc<TrackGain>,
+    download: Option<HttpSource>,
+}
+
 pub struct Player {
     speed: f64,
     volume: f32,
-    status: Status,
+    crossfade: Duration,
+    curve: Curve,
+    // Fade length of pause, resume, stop and seek.
+    ramp: Duration,
+    normalizer: Arc<Normalizer>,
+    equalizer: Arc<Equalizer>,
+    status: Arc<Mutex<Status>>,
     sink: Option<Sink>,
-    #[allow(dead_code)]
-    stream: OutputStream,
-    handle: OutputStreamHandle,
+    fader: Arc<Fader>,
+    // The crossfading track, it becomes `sink` once its fader is open.
+    next: Option<(Sink, Arc<Fader>)>,
+    // Sinks that are fading out, they end by themselves.
+    fading: Vec<Sink>,
+    events: Option<Events>,
+    state: PlayerState,
+    // Between two `position` events.
+    interval: Duration,
+    reported: Instant,
+    // The current track first, then the enqueued ones.
+    tracks: Arc<Mutex<VecDeque<Track>>>,
+    // The chosen output device, matched by name.
+    device: Option<String>,
+    watch: Arc<DeviceWatch>,
+    fallback: Fallback,
+    // `None` until an output device is available.
+    output: Option<Output>,
+    snapshot: Arc<Mutex<Snapshot>>,
+    // An attached media session.
+    link: Option<Link>,
+    // Brings the tracks opened on other threads back.
+    remote: Remote,
+    // Bumped by every load and stop, tracks that finish opening after
+    // that are dropped.
+    loads: u64,
+    // The last track being opened to enqueue, they are appended in order.
+    enqueued: Option<JoinHandle<()>>,
 }
 
-// We can ensure the stream is `Sned`.
-// https://github.com/RustAudio/cpal/commit/33ddf749548d87bf54ce18eb342f954cec1465b2
-unsafe impl Send for Player {}
-
-impl Finalize for Player {}
-
 impl Player {
-    #[inline]
-    fn new() -> Self {
+    /// Runs on the audio thread, which it never leaves.
+    fn new(
+        remote: Remote,
+        snapshot: Arc<Mutex<Snapshot>>,
+        equalizer: Arc<Equalizer>,
+        normalizer: Arc<Normalizer>,
+    ) -> Self {
         #[cfg(target_os = "windows")]
         {
             use {
@@ -106,197 +551,1007 @@ impl Player {
             }
         }
 
-        let (stream, handle) = OutputStream::try_default().unwrap();
+        // Without a device the player still works, loading reports it.
+        let watch = DeviceWatch::new();
+        let output = open_output(None, &watch).map(|(output, name)| {
+            watch.set(name, None);
+            output
+        });
         Self {
             speed: 1.,
             volume: 0.,
-            status: Status::new(),
+            crossfade: Duration::ZERO,
+            curve: Curve::Linear,
+            ramp: Duration::ZERO,
+            normalizer,
+            equalizer,
+            status: Arc::new(Mutex::new(Status::new())),
             sink: None,
-            stream,
-            handle,
+            fader: Fader::new(1.),
+            next: None,
+            fading: Vec::new(),
+            events: None,
+            state: PlayerState::Idle,
+            interval: Duration::from_secs(1),
+            reported: Instant::now(),
+            tracks: Default::default(),
+            device: None,
+            watch,
+            fallback: Fallback::Migrate,
+            output,
+            snapshot,
+            link: None,
+            remote,
+            loads: 0,
+            enqueued: None,
         }
     }
 
+    /// Runs the commands until the handle is dropped, and ticks in between.
+    fn run(mut self, commands: Receiver<Command>) {
+        let mut ticked = Instant::now();
+
+        loop {
+            match commands.recv_timeout(TICK_INTERVAL.saturating_sub(ticked.elapsed())) {
+                Ok(command) => {
+                    if let Err(err) = guard(|| {
+                        command(&mut self);
+                        Ok(())
+                    }) {
+                        self.emit(PlayerEvent::Error(err));
+                    }
+                }
+                Err(RecvTimeoutError::Timeout) => {}
+                Err(RecvTimeoutError::Disconnected) => return,
+            }
+
+            if ticked.elapsed() >= TICK_INTERVAL {
+                ticked = Instant::now();
+                if let Err(err) = guard(|| {
+                    self.tick();
+                    Ok(())
+                }) {
+                    self.emit(PlayerEvent::Error(err));
+                }
+            }
+            // The observer of a media session runs in there too.
+            if let Err(err) = guard(|| {
+                self.publish();
+                Ok(())
+            }) {
+                self.emit(PlayerEvent::Error(err));
+            }
+        }
+    }
+
+    /// Also pushes the playback to the media session, after the lock is
+    /// released.
     #[inline]
-    fn load(&mut self, url: String, play: bool) -> bool {
-        let file = match File::open(url) {
-            Ok(f) => f,
-            _ => return false,
+    fn publish(&mut self) {
+        let playback = Playback {
+            playing: self.state == PlayerState::Playing && !self.paused(),
+            position: self.position(),
+            speed: self.speed,
+            seeked: false,
         };
+        let at = Instant::now();
 
-        let source = match Decoder::new(BufReader::new(file)) {
-            Ok(s) => s.fade_in(Duration::from_secs(2)),
-            _ => return false,
-        };
+        let mut snapshot = lock(&self.snapshot);
+        snapshot.state = self.state;
+        snapshot.position = playback.position;
+        snapshot.at = at;
+        snapshot.speed = playback.speed;
+        snapshot.playing = playback.playing;
+        snapshot.empty = self.empty();
+        snapshot.info = self.info();
+        drop(snapshot);
 
-        self.stop();
+        if let Some(ref mut link) = self.link {
+            link.update(self.state, playback, at);
+        }
+    }
 
-        let sink = match Sink::try_new(&self.handle) {
-            Ok(sink) => sink,
-            Err(PlayError::NoDevice) => {
-                let (stream, handle) = OutputStream::try_default().unwrap();
-                self.stream = stream;
-                self.handle = handle;
-                Sink::try_new(&self.handle).unwrap()
+    #[inline]
+    fn new_sink(&mut self) -> Result<Sink, PlayerError> {
+        let sink = match self.output {
+            Some(ref output) => output.sink(),
+            // There was no device yet.
+            None => {
+                let (output, name) = open_output(self.device.as_deref(), &self.watch)
+                    .ok_or(PlayerError::NoDevice)?;
+                let sink = output.sink();
+                self.watch.set(name, self.device.clone());
+                self.output = Some(output);
+                sink
             }
-            Err(PlayError::DecoderError(_)) => return false,
         };
         sink.set_speed(self.speed as f32);
         sink.set_volume(self.volume);
-        sink.append(source);
 
+        Ok(sink)
+    }
+
+    #[inline]
+    fn sinks(&self) -> impl Iterator<Item = &Sink> {
+        self.sink
+            .iter()
+            .chain(self.next.iter().map(|(sink, _)| sink))
+            .chain(self.fading.iter())
+    }
+
+    #[inline]
+    fn paused(&self) -> bool {
+        self.fader.is_held() || self.sink.as_ref().map(Sink::is_paused).unwrap_or(true)
+    }
+
+    /// Makes the crossfading track current once it started.
+    #[inline]
+    fn promote(&mut self) {
+        if matches!(self.next, Some((_, ref fader)) if fader.is_open()) {
+            let (sink, fader) = self.next.take().unwrap();
+            self.fading.extend(self.sink.replace(sink));
+            self.fader = fader;
+        }
+        self.fading.retain(|sink| !sink.empty());
+    }
+
+    /// Opens `url` at `pos` on its own thread, since that may wait for the
+    /// network, then hands it to `then` on the audio thread once `after`
+    /// did. It is cancelled when another track was loaded or the player
+    /// stopped meanwhile.
+    fn open(
+        &self,
+        url: String,
+        pos: Duration,
+        after: Option<JoinHandle<()>>,
+        then: impl FnOnce(&mut Player, Result<(TrackSource, Track, Duration), PlayerError>)
+            + Send
+            + 'static,
+    ) -> JoinHandle<()> {
+        let loads = self.loads;
+        let remote = self.remote.clone();
+
+        thread::spawn(move || {
+            let opened = guard(|| Opened::new(&url, None, pos));
+            if let Some(after) = after {
+                let _ = after.join();
+            }
+            let _ = remote.send(move |player| {
+                let res = match player.loads == loads {
+                    true => opened.map(|opened| player.track(url, opened)),
+                    false => Err(PlayerError::Cancelled(
+                        "Another track was loaded meanwhile".to_owned(),
+                    )),
+                };
+                then(player, res)
+            });
+        })
+    }
+
+    /// Normalizes the loudness, it is measured when there are no
+    /// ReplayGain tags. Also returns what it is for the queue.
+    #[inline]
+    fn track(&self, url: String, opened: Opened) -> (TrackSource, Track, Duration) {
+        let gain = TrackGain::new(opened.replay_gain);
+        // Measuring a stream would download it twice.
+        if opened.replay_gain.track_gain.is_none() && opened.download.is_none() {
+            self.normalizer.analyze(&gain, url.clone());
+        }
+
+        let source = self.process(opened.source, gain.clone());
+        let track = Track {
+            url,
+            info: opened.info,
+            gain,
+            download: opened.download,
+        };
+
+        (source, track, opened.pos)
+    }
+
+    /// Opens a queued track again at `pos`, reading what was downloaded.
+    #[inline]
+    fn reopen(&self, track: &Track, pos: Duration) -> Result<(TrackSource, Duration), PlayerError> {
+        let opened = Opened::new(&track.url, track.download.as_ref(), pos)?;
+
+        Ok((self.process(opened.source, track.gain.clone()), opened.pos))
+    }
+
+    #[inline]
+    fn process(&self, source: Prefetch, gain: Arc<TrackGain>) -> TrackSource {
+        let source = Equalize::new(source, self.equalizer.clone());
+        Gain::new(source, gain, self.normalizer.clone())
+    }
+
+    #[inline]
+    fn emit(&self, event: PlayerEvent) {
+        if let Some(ref events) = self.events {
+            events.emit(event);
+        }
+    }
+
+    #[inline]
+    fn set_state(&mut self, state: PlayerState) {
+        if self.state != state {
+            self.state = state;
+            self.emit(PlayerEvent::StateChanged(state));
+        }
+    }
+
+    /// Notices the end of the track and device changes.
+    fn tick(&mut self) {
+        self.recover();
+        self.promote();
+        if self.state != PlayerState::Playing {
+            return;
+        }
+
+        if self.empty() {
+            self.set_state(PlayerState::Ended);
+            self.emit(PlayerEvent::Ended);
+        } else if self.reported.elapsed() >= self.interval {
+            self.reported = Instant::now();
+            self.emit(PlayerEvent::Position(self.position()));
+        }
+    }
+
+    /// The old track keeps playing while the new one opens.
+    fn load(&mut self, url: String, play: bool, start: Duration, done: Done) {
+        self.loads += 1;
+        self.enqueued = None;
+        self.set_state(PlayerState::Loading);
+
+        self.open(url, start, None, move |player, opened| {
+            let res = opened
+                .and_then(|(source, track, pos)| player.start(source, track, play, pos, true));
+            match res {
+                Ok(()) if play => player.set_state(PlayerState::Playing),
+                Ok(()) => player.set_state(PlayerState::Paused),
+                // The load or stop that took over set the state.
+                Err(PlayerError::Cancelled(_)) => {}
+                Err(ref err) => {
+                    player.set_state(PlayerState::Error);
+                    player.emit(PlayerEvent::Error(err.clone()));
+                }
+            }
+            done(player, res)
+        });
+    }
+
+    /// Plays `source`, which starts at `pos`, instead of the queue. From
+    /// silence when `fade_in` is set.
+    fn start(
+        &mut self,
+        source: TrackSource,
+        track: Track,
+        play: bool,
+        pos: Duration,
+        fade_in: bool,
+    ) -> Result<(), PlayerError> {
+        self.promote();
+        let crossfade = !self.crossfade.is_zero()
+            && play
+            && !self.paused()
+            && !self.sink.as_ref().map(Sink::empty).unwrap_or(true);
+        if crossfade {
+            self.fader.fade_out(self.crossfade, self.curve);
+            self.fading.extend(self.sink.take());
+        }
+        self.clear();
+
+        let sink = self.new_sink()?;
+        let fader = match fade_in {
+            true => Fader::new(0.),
+            false => Fader::new(1.),
+        };
+        match crossfade {
+            true => fader.fade_to(1., self.crossfade, self.curve),
+            false if fade_in => fader.fade_to(1., Duration::from_secs(2), Curve::Linear),
+            false => {}
+        }
+        sink.append(Fade::new(source, fader.clone()).starting_at(pos));
+
+        let mut status = lock(&self.status);
+        status.seek(pos, Duration::ZERO);
         if play {
-            self.status.play();
+            status.play();
         } else {
             sink.pause()
         }
+        drop(status);
         self.sink = Some(sink);
+        self.fader = fader;
+        lock(&self.tracks).push_back(track);
 
-        true
+        Ok(())
+    }
+
+    /// Appends a track after the current one. It starts right after the
+    /// previous one without a gap, or overlaps it when crossfade is set.
+    fn enqueue(&mut self, url: String, done: Done) {
+        self.promote();
+        if self.sink.is_none() {
+            let err = PlayerError::InvalidArgument("Nothing is loaded to enqueue after".to_owned());
+            return done(self, Err(err));
+        }
+
+        let after = self.enqueued.take();
+        let enqueued = self.open(url, Duration::ZERO, after, move |player, opened| {
+            let res = opened.and_then(|(source, track, _)| player.append(source, track));
+            done(player, res)
+        });
+        self.enqueued = Some(enqueued);
+    }
+
+    /// Queues `source` after the last track.
+    fn append(&mut self, source: TrackSource, track: Track) -> Result<(), PlayerError> {
+        let status = self.status.clone();
+        let events = self.events.clone();
+        let tracks = self.tracks.clone();
+        lock(&tracks).push_back(track);
+        let track_changed = move || {
+            lock(&tracks).pop_front();
+            lock(&status).restart();
+            if let Some(ref events) = events {
+                events.emit(PlayerEvent::TrackChanged);
+            }
+        };
+
+        if self.next.is_none() && !self.crossfade.is_zero() {
+            let sink = self.new_sink()?;
+            if self.paused() {
+                sink.pause();
+            }
+            let fader = Fader::gated();
+            sink.append(Fade::new(source, fader.clone()));
+
+            let next = fader.clone();
+            let curve = self.curve;
+            self.fader.set_tail(
+                self.crossfade,
+                curve,
+                Box::new(move |remaining| {
+                    next.open(remaining, curve);
+                    track_changed();
+                }),
+            );
+            self.next = Some((sink, fader));
+
+            return Ok(());
+        }
+
+        let (sink, fader) = match self.next {
+            Some((ref sink, ref fader)) => (sink, fader),
+            None => (self.sink.as_ref().unwrap(), &self.fader),
+        };
+        sink.append(EmptyCallback::<f32>::new(Box::new(track_changed)));
+        sink.append(Fade::new(source, fader.clone()));
+
+        Ok(())
     }
 
     #[inline]
     fn play(&mut self) {
-        if let Some(ref sink) = self.sink {
-            sink.play();
-            self.status.play()
+        self.promote();
+        if self.sink.is_some() {
+            self.sinks().for_each(Sink::play);
+            self.fader.release(self.ramp);
+            lock(&self.status).play();
+            self.set_state(PlayerState::Playing);
         }
     }
 
     #[inline]
     fn pause(&mut self) {
+        self.promote();
         if let Some(ref sink) = self.sink {
-            sink.pause();
-            self.status.stop(self.speed);
+            if self.ramp.is_zero() {
+                sink.pause();
+            } else {
+                self.fader.hold(self.ramp);
+            }
+            self.next.iter().for_each(|(sink, _)| sink.pause());
+            self.fading.iter().for_each(Sink::pause);
+            lock(&self.status).stop(self.speed, self.ramp);
+            self.set_state(PlayerState::Paused);
         }
     }
 
+    /// Also cancels the tracks that are still being opened.
     #[inline]
     fn stop(&mut self) {
+        self.loads += 1;
+        self.enqueued = None;
+        self.clear();
+        self.set_state(PlayerState::Idle);
+    }
+
+    /// Stops without changing the state.
+    fn clear(&mut self) {
+        self.fader.clear_tail();
+        if !self.ramp.is_zero() && !self.paused() {
+            self.fader.fade_out(self.ramp, Curve::Linear);
+            self.fading.extend(self.sink.take());
+        }
         self.sink = None;
-        self.status.reset()
+        self.next = None;
+        self.fader = Fader::new(1.);
+        lock(&self.tracks).clear();
+        lock(&self.status).reset()
+    }
+
+    fn set_device(&mut self, name: String) -> Result<(), PlayerError> {
+        let device = find_device(&name)
+            .ok_or_else(|| PlayerError::NotFound(format!("No output device named {}", name)))?;
+        let output = Output::new(&device, self.watch.on_error())?;
+
+        self.migrate(output);
+        self.watch.set(Some(name.clone()), Some(name.clone()));
+        self.device = Some(name);
+
+        Ok(())
+    }
+
+    #[inline]
+    fn set_fallback(&mut self, fallback: Fallback) {
+        self.fallback = fallback;
+    }
+
+    /// Reopens the stream once it failed, the device was unplugged or the
+    /// default one changed. It goes back to the chosen device once that is
+    /// plugged in again.
+    fn recover(&mut self) {
+        if !self.watch.take_lost() {
+            return;
+        }
+        // Tried again on the next check.
+        let (output, name) = match open_output(self.device.as_deref(), &self.watch) {
+            Some(output) => output,
+            None => return,
+        };
+
+        // Getting the chosen device back is no reason to pause.
+        let paused = self.fallback == Fallback::Pause && !self.paused() && name != self.device;
+        if paused {
+            self.pause();
+        }
+        self.migrate(output);
+        self.watch.set(name, self.device.clone());
+
+        if let Some(ref events) = self.events {
+            events.emit(PlayerEvent::DeviceChanged { paused });
+        }
+    }
+
+    /// Moves playback to another stream, the tracks are reopened there at
+    /// the same position and level.
+    fn migrate(&mut self, output: Output) {
+        self.promote();
+        let pos = Duration::try_from_secs_f64(self.position()).unwrap_or_default();
+        let play = !self.paused();
+        let mut tracks = std::mem::take(&mut *lock(&self.tracks));
+
+        self.fader.clear_tail();
+        self.sink = None;
+        self.next = None;
+        self.fading.clear();
+        self.output = Some(output);
+
+        if let Some(track) = tracks.pop_front() {
+            if let Err(err) = self
+                .reopen(&track, pos)
+                .and_then(|(source, pos)| self.start(source, track, play, pos, false))
+                .and_then(|_| {
+                    tracks.into_iter().try_for_each(|track| {
+                        let (source, _) = self.reopen(&track, Duration::ZERO)?;
+                        self.append(source, track)
+                    })
+                })
+            {
+                self.emit(PlayerEvent::Error(err));
+            }
+        }
     }
 
     #[inline]
     fn set_speed(&mut self, speed: f64) {
-        if let Some(ref sink) = self.sink {
-            sink.set_speed(speed as f32);
-            self.status.store(self.speed);
+        self.promote();
+        self.sinks().for_each(|sink| sink.set_speed(speed as f32));
+        if self.sink.is_some() {
+            lock(&self.status).store(self.speed);
         }
         self.speed = speed;
     }
 
     #[inline]
     fn set_volume(&mut self, level: f32) {
-        if let Some(ref sink) = self.sink {
-            sink.set_volume(level);
-        }
+        self.sinks().for_each(|sink| sink.set_volume(level));
         self.volume = level;
     }
 
+    #[inline]
+    fn set_ramp(&mut self, duration: f64) {
+        self.ramp = Duration::try_from_secs_f64(duration).unwrap_or_default();
+    }
+
+    #[inline]
+    fn set_crossfade(&mut self, duration: f64, curve: Curve) {
+        self.crossfade = Duration::try_from_secs_f64(duration).unwrap_or_default();
+        self.curve = curve;
+    }
+
     #[inline]
     fn empty(&self) -> bool {
-        if let Some(ref sink) = self.sink {
-            return sink.empty();
-        }
-        true
+        self.sink
+            .iter()
+            .chain(self.next.iter().map(|(sink, _)| sink))
+            .all(Sink::empty)
     }
 
+    /// Counted from the samples the output consumed, the wall clock is
+    /// only used until the first one was.
     #[inline]
     fn position(&self) -> f64 {
-        self.status.elapsed(self.speed)
+        let fader = match self.next {
+            Some((_, ref fader)) if fader.is_open() => fader,
+            _ => &self.fader,
+        };
+        fader
+            .position()
+            .unwrap_or_else(|| lock(&self.status).elapsed(self.speed))
     }
 
+    /// Of the track that is playing.
+    #[inline]
+    fn info(&self) -> Option<StreamInfo> {
+        lock(&self.tracks).front().map(|track| track.info)
+    }
+
+    /// Stops at the start of the track when seeking backward past it.
     #[inline]
     fn seek(&mut self, offset: f64) {
+        if let Ok(pos) = Duration::try_from_secs_f64((self.position() + offset).max(0.)) {
+            self.seek_to(pos);
+        }
+    }
+
+    fn seek_to(&mut self, pos: Duration) {
+        self.promote();
         if let Some(ref sink) = self.sink {
-            if let Ok(pos) = Duration::try_from_secs_f64(self.position() + offset) {
-                if let Ok(_) = sink.try_seek(pos) {
-                    self.status.seek(pos);
-                }
+            if !self.ramp.is_zero() {
+                self.fader.seek(pos, self.ramp);
+                let delay = self.ramp.div_f64(self.speed);
+                lock(&self.status).seek(pos, delay);
+            } else if sink.try_seek(pos).is_ok() {
+                lock(&self.status).seek(pos, Duration::ZERO);
             }
         }
     }
 }
 
+/// Runs `command` on the audio thread, the promise settles with its result.
+fn settle<'a>(
+    cx: &mut FunctionContext<'a>,
+    player: Handle<'a, JsBox<PlayerHandle>>,
+    command: impl FnOnce(&mut Player) -> Result<(), PlayerError> + Send + 'static,
+) -> JsResult<'a, JsPromise> {
+    let (deferred, promise) = cx.promise();
+    let channel = cx.channel();
+    player
+        .send(move |player| {
+            let res = guard(|| command(player));
+            // Reads after the promise settled see the result.
+            player.publish();
+            deferred.settle_with(&channel, move |mut cx| {
+                res.or_throw(&mut cx)?;
+                Ok(cx.undefined())
+            });
+        })
+        .or_throw(cx)?;
+
+    Ok(promise)
+}
+
+/// Like `settle`, for commands that open a track off the audio thread and
+/// call `done` once they are through.
+fn settle_later<'a>(
+    cx: &mut FunctionContext<'a>,
+    player: Handle<'a, JsBox<PlayerHandle>>,
+    command: impl FnOnce(&mut Player, Done) + Send + 'static,
+) -> JsResult<'a, JsPromise> {
+    let (deferred, promise) = cx.promise();
+    let channel = cx.channel();
+    let done: Done = Box::new(move |player, res| {
+        player.publish();
+        deferred.settle_with(&channel, move |mut cx| {
+            res.or_throw(&mut cx)?;
+            Ok(cx.undefined())
+        });
+    });
+    player
+        .send(move |player| command(player, done))
+        .or_throw(cx)?;
+
+    Ok(promise)
+}
+
+/// Runs `command` on the audio thread without waiting for it.
+#[inline]
+fn send<'a>(
+    cx: &mut FunctionContext<'a>,
+    player: Handle<'a, JsBox<PlayerHandle>>,
+    command: impl FnOnce(&mut Player) + Send + 'static,
+) -> JsResult<'a, JsUndefined> {
+    player.send(command).or_throw(cx)?;
+
+    Ok(cx.undefined())
+}
+
+/// `handler` and `interval` are the same as `playerSubscribe`.
 pub fn player_new(mut cx: FunctionContext) -> JsResult<JsValue> {
-    let player = RefCell::new(Player::new());
+    let player = guard(PlayerHandle::spawn).or_throw(&mut cx)?;
+    let player = cx.boxed(player);
+    if let Some(handler) = cx.argument_opt(0) {
+        let handler = handler.downcast_or_throw::<JsFunction, _>(&mut cx)?;
+        let interval = cx.argument_opt(1);
+        subscribe(&mut cx, player, handler, interval)?;
+    }
 
-    Ok(cx.boxed(player).upcast())
+    Ok(player.upcast())
 }
 
-pub fn player_load(mut cx: FunctionContext) -> JsResult<JsBoolean> {
-    let player = cx.argument::<JsBox<RefCell<Player>>>(0)?;
+fn subscribe<'a>(
+    cx: &mut FunctionContext<'a>,
+    player: Handle<'a, JsBox<PlayerHandle>>,
+    handler: Handle<'a, JsFunction>,
+    interval: Option<Handle<'a, JsValue>>,
+) -> NeonResult<()> {
+    let interval = match interval {
+        Some(interval) => interval.downcast_or_throw::<JsNumber, _>(cx)?.value(cx),
+        None => 1.,
+    };
+    let interval = Duration::try_from_secs_f64(interval).unwrap_or(TICK_INTERVAL);
+
+    let events = Events {
+        channel: cx.channel(),
+        handler: Arc::new(handler.root(cx)),
+    };
+    send(cx, player, move |player| {
+        player.events = Some(events);
+        player.interval = interval;
+    })?;
+
+    Ok(())
+}
+
+/// Pushes events to `handler`, `position` ones every `interval` seconds.
+pub fn player_subscribe(mut cx: FunctionContext) -> JsResult<JsUndefined> {
+    let player = cx.argument::<JsBox<PlayerHandle>>(0)?;
+    let handler = cx.argument::<JsFunction>(1)?;
+    let interval = cx.argument_opt(2);
+    subscribe(&mut cx, player, handler, interval)?;
+
+    Ok(cx.undefined())
+}
+
+pub fn player_unsubscribe(mut cx: FunctionContext) -> JsResult<JsUndefined> {
+    let player = cx.argument::<JsBox<PlayerHandle>>(0)?;
+    send(&mut cx, player, |player| player.events = None)
+}
+
+pub fn player_state(mut cx: FunctionContext) -> JsResult<JsString> {
+    let player = cx.argument::<JsBox<PlayerHandle>>(0)?;
+    let state = player.snapshot().or_throw(&mut cx)?.state;
+
+    Ok(cx.string(state.name()))
+}
+
+/// Opens the track off the audio thread, the current one keeps playing
+/// until it is ready. Starts at `seek` seconds when given, the track is
+/// seeked before its first sample. Rejects with a `NoDevice` error when
+/// there is no output device, and with `Cancelled` when another track was
+/// loaded or the player stopped first.
+pub fn player_load(mut cx: FunctionContext) -> JsResult<JsPromise> {
+    let player = cx.argument::<JsBox<PlayerHandle>>(0)?;
     let url = cx.argument::<JsString>(1)?.value(&mut cx);
     let play = cx.argument::<JsBoolean>(2)?.value(&mut cx);
-    let res = player.borrow_mut().load(url, play);
+    let start = match cx.argument_opt(3) {
+        Some(start) => start
+            .downcast::<JsNumber, _>(&mut cx)
+            .ok()
+            .map(|start| start.value(&mut cx)),
+        None => None,
+    };
+    let start = Duration::try_from_secs_f64(start.unwrap_or_default()).unwrap_or_default();
 
-    Ok(cx.boolean(res))
+    settle_later(&mut cx, player, move |player, done| {
+        player.recover();
+        player.load(url, play, start, done)
+    })
 }
 
-pub fn player_play(mut cx: FunctionContext) -> JsResult<JsBoolean> {
-    let player = cx.argument::<JsBox<RefCell<Player>>>(0)?;
-    let mut player = player.borrow_mut();
+pub fn player_enqueue(mut cx: FunctionContext) -> JsResult<JsPromise> {
+    let player = cx.argument::<JsBox<PlayerHandle>>(0)?;
+    let url = cx.argument::<JsString>(1)?.value(&mut cx);
+    settle_later(&mut cx, player, move |player, done| {
+        player.enqueue(url, done)
+    })
+}
 
-    let res = match player.empty() {
-        false => {
-            player.play();
-            true
-        }
-        _ => false,
-    };
+/// `false` when there is nothing to play.
+pub fn player_play(mut cx: FunctionContext) -> JsResult<JsBoolean> {
+    let player = cx.argument::<JsBox<PlayerHandle>>(0)?;
+    let empty = player.snapshot().or_throw(&mut cx)?.empty;
+    if !empty {
+        send(&mut cx, player, |player| {
+            player.recover();
+            if !player.empty() {
+                player.play();
+            }
+        })?;
+    }
 
-    Ok(cx.boolean(res))
+    Ok(cx.boolean(!empty))
 }
 
 pub fn player_pause(mut cx: FunctionContext) -> JsResult<JsUndefined> {
-    let player = cx.argument::<JsBox<RefCell<Player>>>(0)?;
-    player.borrow_mut().pause();
-
-    Ok(cx.undefined())
+    let player = cx.argument::<JsBox<PlayerHandle>>(0)?;
+    send(&mut cx, player, Player::pause)
 }
 
 pub fn player_stop(mut cx: FunctionContext) -> JsResult<JsUndefined> {
-    let player = cx.argument::<JsBox<RefCell<Player>>>(0)?;
-    player.borrow_mut().stop();
-
-    Ok(cx.undefined())
+    let player = cx.argument::<JsBox<PlayerHandle>>(0)?;
+    send(&mut cx, player, Player::stop)
 }
 
 pub fn player_set_speed(mut cx: FunctionContext) -> JsResult<JsUndefined> {
-    let player = cx.argument::<JsBox<RefCell<Player>>>(0)?;
+    let player = cx.argument::<JsBox<PlayerHandle>>(0)?;
     let speed = cx.argument::<JsNumber>(1)?.value(&mut cx);
-    player.borrow_mut().set_speed(speed);
-
-    Ok(cx.undefined())
+    if !speed.is_finite() || speed <= 0. {
+        return Err(PlayerError::InvalidArgument(format!(
+            "Invalid speed {}",
+            speed
+        )))
+        .or_throw(&mut cx);
+    }
+    send(&mut cx, player, move |player| player.set_speed(speed))
 }
 
 pub fn player_set_volume(mut cx: FunctionContext) -> JsResult<JsUndefined> {
-    let player = cx.argument::<JsBox<RefCell<Player>>>(0)?;
+    let player = cx.argument::<JsBox<PlayerHandle>>(0)?;
     let level = cx.argument::<JsNumber>(1)?.value(&mut cx) / 100.0;
-    player.borrow_mut().set_volume(level as f32);
+    send(&mut cx, player, move |player| {
+        player.set_volume(level as f32)
+    })
+}
+
+pub fn player_set_ramp(mut cx: FunctionContext) -> JsResult<JsUndefined> {
+    let player = cx.argument::<JsBox<PlayerHandle>>(0)?;
+    let duration = cx.argument::<JsNumber>(1)?.value(&mut cx);
+    send(&mut cx, player, move |player| player.set_ramp(duration))
+}
+
+pub fn player_set_replay_gain(mut cx: FunctionContext) -> JsResult<JsUndefined> {
+    let player = cx.argument::<JsBox<PlayerHandle>>(0)?;
+    let mode = cx.argument::<JsString>(1)?.value(&mut cx);
+    let preamp = cx.argument::<JsNumber>(2)?.value(&mut cx);
+    guard(|| {
+        player
+            .normalizer
+            .set(GainMode::from(mode.as_str()), preamp as f32);
+        Ok(())
+    })
+    .or_throw(&mut cx)?;
 
     Ok(cx.undefined())
 }
 
+/// Takes a preset name, or bands with an optional pre-amp.
+pub fn player_set_equalizer(mut cx: FunctionContext) -> JsResult<JsUndefined> {
+    let player = cx.argument::<JsBox<PlayerHandle>>(0)?;
+    let settings = cx.argument::<JsValue>(1)?;
+
+    if let Ok(preset) = settings.downcast::<JsString, _>(&mut cx) {
+        let preset = preset.value(&mut cx);
+        guard(|| match player.equalizer.set_preset(&preset) {
+            true => Ok(()),
+            false => Err(PlayerError::InvalidArgument(format!(
+                "Unknown equalizer preset {}",
+                preset
+            ))),
+        })
+        .or_throw(&mut cx)?;
+        return Ok(cx.undefined());
+    }
+
+    let mut bands = Vec::new();
+    for band in settings
+        .downcast_or_throw::<JsArray, _>(&mut cx)?
+        .to_vec(&mut cx)?
+    {
+        let band = band.downcast_or_throw::<JsObject, _>(&mut cx)?;
+        let kind = match band.get_opt::<JsString, _, _>(&mut cx, "type")? {
+            Some(kind) => BandKind::from(kind.value(&mut cx).as_str()),
+            None => BandKind::Peaking,
+        };
+        let frequency = band
+            .get::<JsNumber, _, _>(&mut cx, "frequency")?
+            .value(&mut cx);
+        let gain = band.get::<JsNumber, _, _>(&mut cx, "gain")?.value(&mut cx);
+        let q = band.get::<JsNumber, _, _>(&mut cx, "q")?.value(&mut cx);
+        bands.push(Band {
+            kind,
+            frequency: frequency as f32,
+            gain: gain as f32,
+            q: q as f32,
+        });
+    }
+    let preamp = match cx.argument_opt(2) {
+        Some(preamp) => Some(
+            preamp
+                .downcast_or_throw::<JsNumber, _>(&mut cx)?
+                .value(&mut cx) as f32,
+        ),
+        None => None,
+    };
+    guard(|| {
+        player.equalizer.set(bands, preamp);
+        Ok(())
+    })
+    .or_throw(&mut cx)?;
+
+    Ok(cx.undefined())
+}
+
+pub fn player_import_auto_eq(mut cx: FunctionContext) -> JsResult<JsUndefined> {
+    let player = cx.argument::<JsBox<PlayerHandle>>(0)?;
+    let path = cx.argument::<JsString>(1)?.value(&mut cx);
+    guard(|| {
+        let profile = fs::read_to_string(&path)?;
+        match player.equalizer.set_auto_eq(&profile) {
+            true => Ok(()),
+            false => Err(PlayerError::InvalidArgument(format!(
+                "Not an AutoEq profile: {}",
+                path
+            ))),
+        }
+    })
+    .or_throw(&mut cx)?;
+
+    Ok(cx.undefined())
+}
+
+pub fn player_set_crossfade(mut cx: FunctionContext) -> JsResult<JsUndefined> {
+    let player = cx.argument::<JsBox<PlayerHandle>>(0)?;
+    let duration = cx.argument::<JsNumber>(1)?.value(&mut cx);
+    let curve = Curve::from(cx.argument::<JsString>(2)?.value(&mut cx).as_str());
+    send(&mut cx, player, move |player| {
+        player.set_crossfade(duration, curve)
+    })
+}
+
+/// The file extensions `playerLoad` can decode.
+pub fn player_supported_formats(mut cx: FunctionContext) -> JsResult<JsArray> {
+    let res = cx.empty_array();
+    for (i, format) in supported_formats().into_iter().enumerate() {
+        let format = cx.string(format);
+        res.set(&mut cx, i as u32, format)?;
+    }
+
+    Ok(res)
+}
+
+pub fn player_list_devices(mut cx: FunctionContext) -> JsResult<JsArray> {
+    let (default, names) = guard(|| {
+        let host = cpal::default_host();
+        let default = host.default_output_device().and_then(|d| d.name().ok());
+        let names: Vec<_> = match host.output_devices() {
+            Ok(devices) => devices.filter_map(|d| d.name().ok()).collect(),
+            Err(err) => return Err(PlayerError::Device(err.to_string())),
+        };
+        Ok((default, names))
+    })
+    .or_throw(&mut cx)?;
+
+    let res = cx.empty_array();
+    for (i, name) in names.into_iter().enumerate() {
+        let obj = cx.empty_object();
+        let is_default = cx.boolean(default.as_ref() == Some(&name));
+        let name = cx.string(name);
+        obj.set(&mut cx, "name", name)?;
+        obj.set(&mut cx, "isDefault", is_default)?;
+        res.set(&mut cx, i as u32, obj)?;
+    }
+
+    Ok(res)
+}
+
+/// The tracks are reopened on the new device, so it settles afterwards.
+pub fn player_set_device(mut cx: FunctionContext) -> JsResult<JsPromise> {
+    let player = cx.argument::<JsBox<PlayerHandle>>(0)?;
+    let name = cx.argument::<JsString>(1)?.value(&mut cx);
+    settle(&mut cx, player, move |player| player.set_device(name))
+}
+
+pub fn player_set_fallback(mut cx: FunctionContext) -> JsResult<JsUndefined> {
+    let player = cx.argument::<JsBox<PlayerHandle>>(0)?;
+    let fallback = Fallback::from(cx.argument::<JsString>(1)?.value(&mut cx).as_str());
+    send(&mut cx, player, move |player| player.set_fallback(fallback))
+}
+
 pub fn player_empty(mut cx: FunctionContext) -> JsResult<JsBoolean> {
-    let player = cx.argument::<JsBox<RefCell<Player>>>(0)?;
-    let res = player.borrow().empty();
+    let player = cx.argument::<JsBox<PlayerHandle>>(0)?;
+    let res = player.snapshot().or_throw(&mut cx)?.empty;
 
     Ok(cx.boolean(res))
 }
 
 pub fn player_position(mut cx: FunctionContext) -> JsResult<JsNumber> {
-    let player = cx.argument::<JsBox<RefCell<Player>>>(0)?;
-    let res = player.borrow().position();
+    let player = cx.argument::<JsBox<PlayerHandle>>(0)?;
+    let res = player.snapshot().or_throw(&mut cx)?.position();
 
     Ok(cx.number(res))
 }
 
+/// In seconds, `undefined` when nothing is loaded or it is unknown.
+pub fn player_duration(mut cx: FunctionContext) -> JsResult<JsValue> {
+    let player = cx.argument::<JsBox<PlayerHandle>>(0)?;
+    let info = player.snapshot().or_throw(&mut cx)?.info;
+
+    Ok(match info.and_then(|info| info.duration) {
+        Some(duration) => cx.number(duration.as_secs_f64()).upcast(),
+        None => cx.undefined().upcast(),
+    })
+}
+
+/// Codec, sample rate, bit depth, channels and bitrate of the track that
+/// is playing, unknown fields are left out.
+pub fn player_stream_info(mut cx: FunctionContext) -> JsResult<JsValue> {
+    let player = cx.argument::<JsBox<PlayerHandle>>(0)?;
+    let info = match player.snapshot().or_throw(&mut cx)?.info {
+        Some(info) => info,
+        None => return Ok(cx.undefined().upcast()),
+    };
+
+    let obj = cx.empty_object();
+    let codec = cx.string(info.codec);
+    obj.set(&mut cx, "codec", codec)?;
+    let numbers = [
+        ("sampleRate", info.sample_rate.map(f64::from)),
+        ("bitDepth", info.bit_depth.map(f64::from)),
+        ("channels", info.channels.map(f64::from)),
+        ("bitrate", info.bitrate.map(f64::from)),
+        ("duration", info.duration.map(|d| d.as_secs_f64())),
+    ];
+    for (key, value) in numbers {
+        if let Some(value) = value {
+            let value = cx.number(value);
+            obj.set(&mut cx, key, value)?;
+        }
+    }
+
+    Ok(obj.upcast())
+}
+
 pub fn player_seek(mut cx: FunctionContext) -> JsResult<JsUndefined> {
-    let player = cx.argument::<JsBox<RefCell<Player>>>(0)?;
+    let player = cx.argument::<JsBox<PlayerHandle>>(0)?;
     let seek_offset = cx.argument::<JsNumber>(1)?.value(&mut cx);
-    player.borrow_mut().seek(seek_offset);
+    send(&mut cx, player, move |player| player.seek(seek_offset))
+}
 
-    Ok(cx.undefined())
+/// Seeks to `seconds` from the start of the track.
+pub fn player_seek_to(mut cx: FunctionContext) -> JsResult<JsUndefined> {
+    let player = cx.argument::<JsBox<PlayerHandle>>(0)?;
+    let seconds = cx.argument::<JsNumber>(1)?.value(&mut cx);
+    let pos = match Duration::try_from_secs_f64(seconds) {
+        Ok(pos) => pos,
+        Err(_) => {
+            return Err(PlayerError::InvalidArgument(format!(
+                "Invalid position {}",
+                seconds
+            )))
+            .or_throw(&mut cx)
+        }
+    };
+    send(&mut cx, player, move |player| player.seek_to(pos))
 }
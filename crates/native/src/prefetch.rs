@@ -0,0 +1,355 @@
+//! Decoding ahead of the output, so the audio callback never waits for the
+//! disk or the network.
+
+use {
+    crate::error::{lock, wait},
+    rodio::{source::SeekError, Source},
+    std::{
+        collections::VecDeque,
+        mem,
+        sync::{Arc, Condvar, Mutex},
+        thread,
+        time::Duration,
+    },
+};
+
+// Samples per block, the output takes one at a time.
+const BLOCK_LEN: usize = 4096;
+
+// How far the decoder runs ahead of the output, in blocks. About 1.5 s
+// of 48 kHz stereo.
+const AHEAD: usize = 36;
+
+/// Sources that can tell whether the next sample is there, instead of
+/// waiting for it.
+pub trait Ready {
+    /// `true` when `next` returns at once, also once the source ended.
+    fn ready(&mut self) -> bool;
+}
+
+struct Block {
+    samples: Vec<f32>,
+    channels: u16,
+    rate: u32,
+}
+
+struct State {
+    blocks: VecDeque<Block>,
+    // Blocks the output is done with, reused by the decoder.
+    spare: Vec<Vec<f32>>,
+    // Set by the output, the decoder seeks there and starts over.
+    seek: Option<Duration>,
+    ended: bool,
+    // The output was dropped.
+    closed: bool,
+}
+
+struct Shared {
+    state: Mutex<State>,
+    cond: Condvar,
+}
+
+/// Runs `source` on its own thread and hands out what it decoded.
+///
+/// When the decoder falls behind, `ready` is `false` and `next` outputs
+/// silence until it caught up. Seeking is done by the decoder as well, so
+/// it returns right away.
+pub struct Prefetch {
+    shared: Arc<Shared>,
+    block: Block,
+    offset: usize,
+    total_duration: Option<Duration>,
+}
+
+impl Prefetch {
+    /// The first block is decoded right away, so a track starts without
+    /// waiting for the thread.
+    pub fn new<S>(mut source: S) -> Self
+    where
+        S: Source<Item = f32> + Send + 'static,
+    {
+        let total_duration = source.total_duration();
+        let block = decode(&mut source, Vec::with_capacity(BLOCK_LEN));
+        let shared = Arc::new(Shared {
+            state: Mutex::new(State {
+                blocks: VecDeque::with_capacity(AHEAD),
+                // Every block may come back at once after a seek.
+                spare: Vec::with_capacity(AHEAD + 2),
+                seek: None,
+                ended: block.samples.is_empty(),
+                closed: false,
+            }),
+            cond: Condvar::new(),
+        });
+
+        let decoder = shared.clone();
+        thread::Builder::new()
+            .name("cloudmusic-decoder".to_owned())
+            .spawn(move || run(source, decoder))
+            .ok();
+
+        Self {
+            shared,
+            block,
+            offset: 0,
+            total_duration,
+        }
+    }
+
+    /// Takes the next block, `false` when the decoder has none yet.
+    fn fetch(&mut self) -> bool {
+        let mut state = lock(&self.shared.state);
+        let block = match state.blocks.pop_front() {
+            Some(block) => block,
+            None => return state.ended,
+        };
+        let done = mem::replace(&mut self.block, block);
+        state.spare.push(done.samples);
+        drop(state);
+        self.shared.cond.notify_one();
+        self.offset = 0;
+
+        true
+    }
+}
+
+impl Drop for Prefetch {
+    #[inline]
+    fn drop(&mut self) {
+        lock(&self.shared.state).closed = true;
+        self.shared.cond.notify_one();
+    }
+}
+
+/// Decodes up to a block, it ends early when the format changes.
+fn decode<S>(source: &mut S, mut samples: Vec<f32>) -> Block
+where
+    S: Source<Item = f32>,
+{
+    samples.clear();
+    let channels = source.channels();
+    let rate = source.sample_rate();
+    // Blocks only end on a frame boundary.
+    while samples.len() < BLOCK_LEN || samples.len() % channels.max(1) as usize != 0 {
+        if source.channels() != channels || source.sample_rate() != rate {
+            break;
+        }
+        match source.next() {
+            Some(sample) => samples.push(sample),
+            None => break,
+        }
+    }
+
+    Block {
+        samples,
+        channels,
+        rate,
+    }
+}
+
+fn run<S>(mut source: S, shared: Arc<Shared>)
+where
+    S: Source<Item = f32>,
+{
+    loop {
+        let (seek, spare) = {
+            let mut state = lock(&shared.state);
+            loop {
+                if state.closed {
+                    return;
+                }
+                if state.seek.is_some() || (!state.ended && state.blocks.len() < AHEAD) {
+                    break;
+                }
+                state = wait(&shared.cond, state);
+            }
+            (state.seek.take(), state.spare.pop())
+        };
+
+        if let Some(pos) = seek {
+            // It carries on from where it was when that fails.
+            let _ = source.try_seek(pos);
+        }
+        let block = decode(&mut source, spare.unwrap_or_default());
+
+        let mut state = lock(&shared.state);
+        // Decoded before a seek that came in meanwhile.
+        if state.seek.is_some() {
+            state.spare.push(block.samples);
+            continue;
+        }
+        match block.samples.is_empty() {
+            true => state.ended = true,
+            false => state.blocks.push_back(block),
+        }
+        drop(state);
+        shared.cond.notify_one();
+    }
+}
+
+impl Ready for Prefetch {
+    #[inline]
+    fn ready(&mut self) -> bool {
+        self.offset < self.block.samples.len() || self.fetch()
+    }
+}
+
+impl Iterator for Prefetch {
+    type Item = f32;
+
+    #[inline]
+    fn next(&mut self) -> Option<f32> {
+        if !self.ready() {
+            return Some(0.);
+        }
+
+        let sample = *self.block.samples.get(self.offset)?;
+        self.offset += 1;
+        Some(sample)
+    }
+}
+
+impl Source for Prefetch {
+    #[inline]
+    fn current_frame_len(&self) -> Option<usize> {
+        Some(self.block.samples.len() - self.offset)
+    }
+
+    #[inline]
+    fn channels(&self) -> u16 {
+        self.block.channels
+    }
+
+    #[inline]
+    fn sample_rate(&self) -> u32 {
+        self.block.rate
+    }
+
+    #[inline]
+    fn total_duration(&self) -> Option<Duration> {
+        self.total_duration
+    }
+
+    /// Drops what was decoded and lets the decoder seek, it is not ready
+    /// until it did.
+    fn try_seek(&mut self, pos: Duration) -> Result<(), SeekError> {
+        let mut state = lock(&self.shared.state);
+        let State {
+            ref mut blocks,
+            ref mut spare,
+            ..
+        } = *state;
+        spare.extend(blocks.drain(..).map(|block| block.samples));
+        state.seek = Some(pos);
+        state.ended = false;
+        drop(state);
+        self.shared.cond.notify_one();
+        self.offset = self.block.samples.len();
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use {
+        super::*,
+        std::{
+            sync::mpsc::{self, Receiver},
+            time::Instant,
+        },
+    };
+
+    /// Counts up from 0 in stereo, waiting for a go for every block.
+    struct Slow {
+        at: u32,
+        len: u32,
+        go: Receiver<()>,
+    }
+
+    impl Iterator for Slow {
+        type Item = f32;
+
+        fn next(&mut self) -> Option<f32> {
+            if self.at >= self.len {
+                return None;
+            }
+            if self.at as usize % BLOCK_LEN == 0 && self.at > 0 {
+                self.go.recv().ok()?;
+            }
+            self.at += 1;
+            Some((self.at - 1) as f32)
+        }
+    }
+
+    impl Source for Slow {
+        fn current_frame_len(&self) -> Option<usize> {
+            None
+        }
+
+        fn channels(&self) -> u16 {
+            2
+        }
+
+        fn sample_rate(&self) -> u32 {
+            48000
+        }
+
+        fn total_duration(&self) -> Option<Duration> {
+            None
+        }
+
+        fn try_seek(&mut self, pos: Duration) -> Result<(), SeekError> {
+            self.at = (pos.as_secs_f64() * 48000.) as u32 * 2;
+            Ok(())
+        }
+    }
+
+    fn wait_ready(source: &mut Prefetch) {
+        let start = Instant::now();
+        while !source.ready() {
+            assert!(start.elapsed() < Duration::from_secs(5));
+            thread::sleep(Duration::from_millis(1));
+        }
+    }
+
+    #[test]
+    fn silence_while_the_decoder_waits() {
+        let (go, slow) = mpsc::channel();
+        let len = BLOCK_LEN as u32 * 2;
+        let mut source = Prefetch::new(Slow {
+            at: 0,
+            len,
+            go: slow,
+        });
+
+        let first: Vec<_> = source.by_ref().take(BLOCK_LEN).collect();
+        assert_eq!(first[BLOCK_LEN - 1], (BLOCK_LEN - 1) as f32);
+        assert!(!source.ready());
+        assert_eq!(source.next(), Some(0.));
+
+        go.send(()).unwrap();
+        wait_ready(&mut source);
+        assert_eq!(source.next(), Some(BLOCK_LEN as f32));
+        assert_eq!(source.by_ref().count(), BLOCK_LEN - 1);
+    }
+
+    #[test]
+    fn seeks_on_the_decoder() {
+        let (go, slow) = mpsc::channel();
+        let len = 48000 * 2 * 2;
+        for _ in 0..len as usize / BLOCK_LEN {
+            go.send(()).unwrap();
+        }
+        let mut source = Prefetch::new(Slow {
+            at: 0,
+            len,
+            go: slow,
+        });
+        source.next();
+
+        source.try_seek(Duration::from_secs(1)).unwrap();
+        wait_ready(&mut source);
+        assert_eq!(source.next(), Some(96000.));
+    }
+}
@@ -0,0 +1,212 @@
+//! JSON lines exchanged with `cloudmusic-macmedia`, one object per line.
+//!
+//! The host starts with `hello`, the helper answers with its own `hello`, or
+//! with an `error` and exits if the versions differ. Requests that can not be
+//! applied later on get an `error` reply, the helper keeps running. `ping`s
+//! are sent every few seconds, a helper that stops answering is restarted.
+//!
+//! Both crates build this same file, so the two sides can not drift apart.
+
+use serde::{de::Error, Deserialize, Deserializer, Serialize};
+
+/// Bumped on every incompatible change.
+pub const VERSION: u32 = 3;
+
+/// Host to helper.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "camelCase")]
+pub enum Request {
+    Hello {
+        version: u32,
+    },
+    #[serde(rename_all = "camelCase")]
+    Metadata {
+        title: String,
+        album: Option<String>,
+        artist: Option<String>,
+        cover_url: Option<String>,
+        #[serde(default, deserialize_with = "optional_seconds")]
+        duration: Option<f64>,
+    },
+    Playback {
+        playing: bool,
+        #[serde(deserialize_with = "seconds")]
+        position: f64,
+    },
+    /// A heartbeat, answered with `Pong`.
+    Ping,
+}
+
+/// Helper to host.
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "camelCase")]
+pub enum Response {
+    Hello { version: u32 },
+    Event { event: Event },
+    Error { message: String },
+    Pong,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum Direction {
+    Forward,
+    Backward,
+}
+
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "camelCase")]
+pub enum Event {
+    Play,
+    Pause,
+    Toggle,
+    Next,
+    Previous,
+    Stop,
+    Seek { direction: Direction },
+    SeekBy { direction: Direction, offset: f64 },
+    SetPosition { position: f64 },
+    SetVolume { volume: f64 },
+    OpenUri { uri: String },
+    Raise,
+    Quit,
+}
+
+/// A single line, line breaks inside strings are escaped by JSON.
+pub fn encode<T: Serialize>(message: &T) -> serde_json::Result<String> {
+    let mut line = serde_json::to_string(message)?;
+    line.push('\n');
+    Ok(line)
+}
+
+#[inline]
+pub fn decode<'a, T: Deserialize<'a>>(line: &'a str) -> serde_json::Result<T> {
+    serde_json::from_str(line)
+}
+
+/// Durations and positions, which can not be negative.
+fn check(seconds: f64) -> Result<f64, String> {
+    match seconds.is_finite() && seconds >= 0. {
+        true => Ok(seconds),
+        false => Err(format!("invalid seconds {seconds}")),
+    }
+}
+
+fn seconds<'de, D: Deserializer<'de>>(deserializer: D) -> Result<f64, D::Error> {
+    check(f64::deserialize(deserializer)?).map_err(D::Error::custom)
+}
+
+fn optional_seconds<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<f64>, D::Error> {
+    Option::<f64>::deserialize(deserializer)?
+        .map(check)
+        .transpose()
+        .map_err(D::Error::custom)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const METADATA: &str = r#"{"type":"metadata","title":"A\tB\n0","album":null,"artist":"C:1","coverUrl":null,"duration":180.5}"#;
+    const SEEK_BY: &str =
+        r#"{"type":"event","event":{"type":"seekBy","direction":"backward","offset":5.0}}"#;
+    const PING: &str = r#"{"type":"ping"}"#;
+    const PONG: &str = r#"{"type":"pong"}"#;
+
+    #[test]
+    fn request_round_trip() {
+        let requests = [
+            Request::Hello { version: VERSION },
+            Request::Metadata {
+                title: "Tab\there, line\nbreak 0".to_owned(),
+                album: Some("\"Quoted\" \\ 1".to_owned()),
+                artist: None,
+                cover_url: Some("file:///tmp/a%20b.jpg".to_owned()),
+                duration: Some(0.1),
+            },
+            Request::Metadata {
+                title: String::new(),
+                album: None,
+                artist: None,
+                cover_url: None,
+                duration: None,
+            },
+            Request::Playback {
+                playing: true,
+                position: 42.25,
+            },
+            Request::Ping,
+        ];
+        for request in requests {
+            let line = encode(&request).unwrap();
+            assert_eq!(line.find('\n'), Some(line.len() - 1));
+            assert_eq!(decode::<Request>(&line).unwrap(), request);
+        }
+    }
+
+    #[test]
+    fn response_round_trip() {
+        let responses = [
+            Response::Hello { version: VERSION },
+            Response::Event {
+                event: Event::OpenUri {
+                    uri: "file:///tmp/a\tb\n.mp3".to_owned(),
+                },
+            },
+            Response::Event {
+                event: Event::SetPosition { position: 12.5 },
+            },
+            Response::Error {
+                message: "Tab\there, \"quoted\" 1".to_owned(),
+            },
+            Response::Pong,
+        ];
+        for response in responses {
+            let line = encode(&response).unwrap();
+            assert_eq!(line.find('\n'), Some(line.len() - 1));
+            assert_eq!(decode::<Response>(&line).unwrap(), response);
+        }
+    }
+
+    #[test]
+    fn wire_format() {
+        let metadata = Request::Metadata {
+            title: "A\tB\n0".to_owned(),
+            album: None,
+            artist: Some("C:1".to_owned()),
+            cover_url: None,
+            duration: Some(180.5),
+        };
+        assert_eq!(encode(&metadata).unwrap(), format!("{METADATA}\n"));
+        assert_eq!(decode::<Request>(METADATA).unwrap(), metadata);
+
+        let event = Response::Event {
+            event: Event::SeekBy {
+                direction: Direction::Backward,
+                offset: 5.,
+            },
+        };
+        assert_eq!(encode(&event).unwrap(), format!("{SEEK_BY}\n"));
+        assert_eq!(decode::<Response>(SEEK_BY).unwrap(), event);
+
+        assert_eq!(encode(&Request::Ping).unwrap(), format!("{PING}\n"));
+        assert_eq!(decode::<Request>(PING).unwrap(), Request::Ping);
+        assert_eq!(encode(&Response::Pong).unwrap(), format!("{PONG}\n"));
+        assert_eq!(decode::<Response>(PONG).unwrap(), Response::Pong);
+    }
+
+    #[test]
+    fn rejects_garbage() {
+        assert!(decode::<Request>("title:a\tartist:b0").is_err());
+        assert!(decode::<Request>(r#"{"type":"metadata"}"#).is_err());
+        assert!(decode::<Request>(r#"{"type":"playback","playing":true}"#).is_err());
+        assert!(decode::<Request>(r#"{"type":"playback","playing":"yes","position":0}"#).is_err());
+        assert!(decode::<Request>(r#"{"type":"playback","playing":true,"position":-1}"#).is_err());
+        assert!(decode::<Request>(
+            r#"{"type":"metadata","title":"a","album":null,"artist":null,"coverUrl":null,"duration":-0.5}"#
+        )
+        .is_err());
+        assert!(decode::<Response>("3").is_err());
+        assert!(decode::<Response>(r#"{"type":"event"}"#).is_err());
+    }
+}
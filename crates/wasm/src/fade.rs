@@ -0,0 +1,291 @@
+use {
+    rodio::{source::SeekError, Sample, Source},
+    std::{
+        f32::consts::FRAC_PI_2,
+        sync::{
+            atomic::{AtomicBool, AtomicU32, AtomicU64, AtomicU8, Ordering},
+            Arc, Mutex,
+        },
+        time::Duration,
+    },
+};
+
+#[derive(Clone, Copy, Default, PartialEq)]
+pub enum Curve {
+    #[default]
+    Linear,
+    EqualPower,
+}
+
+impl Curve {
+    /// Maps the linear fade level to a gain.
+    #[inline]
+    fn gain(self, level: f32) -> f32 {
+        match self {
+            Curve::Linear => level,
+            Curve::EqualPower => (level * FRAC_PI_2).sin(),
+        }
+    }
+}
+
+impl From<u8> for Curve {
+    #[inline]
+    fn from(value: u8) -> Self {
+        match value {
+            1 => Curve::EqualPower,
+            _ => Curve::Linear,
+        }
+    }
+}
+
+impl From<&str> for Curve {
+    #[inline]
+    fn from(value: &str) -> Self {
+        match value {
+            "equalPower" => Curve::EqualPower,
+            _ => Curve::Linear,
+        }
+    }
+}
+
+struct AtomicF32(AtomicU32);
+
+impl AtomicF32 {
+    #[inline]
+    fn new(value: f32) -> Self {
+        Self(AtomicU32::new(value.to_bits()))
+    }
+
+    #[inline]
+    fn load(&self) -> f32 {
+        f32::from_bits(self.0.load(Ordering::Relaxed))
+    }
+
+    #[inline]
+    fn store(&self, value: f32) {
+        self.0.store(value.to_bits(), Ordering::Relaxed)
+    }
+}
+
+/// A volume envelope that can be changed while the sink is playing.
+pub struct Fader {
+    level: AtomicF32,
+    target: AtomicF32,
+    // Level units per second.
+    speed: AtomicF32,
+    curve: AtomicU8,
+    end: AtomicBool,
+    hold: AtomicBool,
+    // Seek once faded out, then fade back in.
+    seek: Mutex<Option<(Duration, Duration)>>,
+    has_seek: AtomicBool,
+    // Seconds into the source as `f64` bits, NaN before it started.
+    position: AtomicU64,
+}
+
+impl Fader {
+    #[inline]
+    pub fn new(level: f32) -> Arc<Self> {
+        Arc::new(Self {
+            level: AtomicF32::new(level),
+            target: AtomicF32::new(level),
+            speed: AtomicF32::new(0.),
+            curve: AtomicU8::new(Curve::Linear as u8),
+            end: AtomicBool::new(false),
+            hold: AtomicBool::new(false),
+            seek: Mutex::new(None),
+            has_seek: AtomicBool::new(false),
+            position: AtomicU64::new(f64::NAN.to_bits()),
+        })
+    }
+
+    #[inline]
+    pub fn fade_to(&self, target: f32, duration: Duration, curve: Curve) {
+        let distance = (target - self.level.load()).abs();
+        let speed = match duration.is_zero() {
+            true => f32::INFINITY,
+            false => distance / duration.as_secs_f32(),
+        };
+        self.speed.store(speed);
+        self.curve.store(curve as u8, Ordering::Relaxed);
+        self.target.store(target);
+    }
+
+    /// Fades out, then ends the source so the sink becomes empty.
+    #[inline]
+    pub fn fade_out(&self, duration: Duration, curve: Curve) {
+        self.end.store(true, Ordering::Relaxed);
+        self.fade_to(0., duration, curve);
+    }
+
+    /// Fades out, then outputs silence without consuming the source.
+    #[inline]
+    pub fn hold(&self, duration: Duration) {
+        self.hold.store(true, Ordering::Relaxed);
+        self.fade_to(0., duration, Curve::Linear);
+    }
+
+    #[inline]
+    pub fn release(&self, duration: Duration) {
+        if self.hold.swap(false, Ordering::Relaxed) {
+            self.fade_to(1., duration, Curve::Linear);
+        }
+    }
+
+    #[inline]
+    pub fn is_held(&self) -> bool {
+        self.hold.load(Ordering::Relaxed)
+    }
+
+    /// Fades out, seeks the source to `pos`, then fades back in.
+    #[inline]
+    pub fn seek(&self, pos: Duration, duration: Duration) {
+        *self.seek.lock().unwrap() = Some((pos, duration));
+        self.has_seek.store(true, Ordering::Relaxed);
+        self.fade_to(0., duration, Curve::Linear);
+    }
+
+    /// The position of the source, from the samples played so far.
+    #[inline]
+    pub fn position(&self) -> Option<f64> {
+        let position = f64::from_bits(self.position.load(Ordering::Relaxed));
+        (!position.is_nan()).then_some(position)
+    }
+
+    #[inline]
+    fn next_level(&self, samples_per_sec: f32) -> f32 {
+        let level = self.level.load();
+        let target = self.target.load();
+        if level == target {
+            return level;
+        }
+
+        let step = self.speed.load() / samples_per_sec;
+        let level = match level < target {
+            true => (level + step).min(target),
+            false => (level - step).max(target),
+        };
+        self.level.store(level);
+        level
+    }
+}
+
+pub struct Fade<I> {
+    input: I,
+    fader: Arc<Fader>,
+    played: u64,
+    silent: u64,
+}
+
+impl<I> Fade<I>
+where
+    I: Source,
+    I::Item: Sample,
+{
+    #[inline]
+    pub fn new(input: I, fader: Arc<Fader>) -> Self {
+        Self {
+            input,
+            fader,
+            played: 0,
+            silent: 0,
+        }
+    }
+
+    fn seek_pending(&mut self) {
+        self.fader.has_seek.store(false, Ordering::Relaxed);
+        let seek = self.fader.seek.lock().unwrap().take();
+        if let Some((pos, duration)) = seek {
+            let _ = self.try_seek(pos);
+            if !self.fader.is_held() {
+                self.fader.fade_to(1., duration, Curve::Linear);
+            }
+        }
+    }
+}
+
+impl<I> Iterator for Fade<I>
+where
+    I: Source,
+    I::Item: Sample,
+{
+    type Item = I::Item;
+
+    #[inline]
+    fn next(&mut self) -> Option<I::Item> {
+        let channels = self.input.channels() as u64;
+        // Only leave the silence on a frame boundary.
+        if self.silent % channels != 0 {
+            self.silent += 1;
+            return Some(I::Item::zero_value());
+        }
+
+        let samples_per_sec = self.input.sample_rate() as f32 * channels as f32;
+        let level = self.fader.next_level(samples_per_sec);
+        // Only seek or hold on a frame boundary.
+        if level <= 0. {
+            if self.fader.end.load(Ordering::Relaxed) {
+                return None;
+            }
+            if self.played % channels == 0 {
+                if self.fader.has_seek.load(Ordering::Relaxed) {
+                    self.seek_pending();
+                } else if self.fader.is_held() {
+                    self.silent += 1;
+                    return Some(I::Item::zero_value());
+                }
+            }
+        }
+
+        let sample = self.input.next()?;
+        self.played += 1;
+        if self.played % channels == 0 {
+            let position = self.played as f64 / samples_per_sec as f64;
+            self.fader
+                .position
+                .store(position.to_bits(), Ordering::Relaxed);
+        }
+        let curve = Curve::from(self.fader.curve.load(Ordering::Relaxed));
+
+        Some(sample.amplify(curve.gain(level)))
+    }
+
+    #[inline]
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.input.size_hint()
+    }
+}
+
+impl<I> Source for Fade<I>
+where
+    I: Source,
+    I::Item: Sample,
+{
+    #[inline]
+    fn current_frame_len(&self) -> Option<usize> {
+        self.input.current_frame_len()
+    }
+
+    #[inline]
+    fn channels(&self) -> u16 {
+        self.input.channels()
+    }
+
+    #[inline]
+    fn sample_rate(&self) -> u32 {
+        self.input.sample_rate()
+    }
+
+    #[inline]
+    fn total_duration(&self) -> Option<Duration> {
+        self.input.total_duration()
+    }
+
+    #[inline]
+    fn try_seek(&mut self, pos: Duration) -> Result<(), SeekError> {
+        self.input.try_seek(pos)?;
+        self.played = (pos.as_secs_f64() * self.input.sample_rate() as f64) as u64
+            * self.input.channels() as u64;
+        Ok(())
+    }
+}
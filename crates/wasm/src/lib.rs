@@ -1,6 +1,13 @@
+mod fade;
+
 use {
-    rodio::{Decoder, OutputStream, OutputStreamHandle, Sink},
-    std::{io::Cursor, time::Duration},
+    fade::{Curve, Fade, Fader},
+    rodio::{Decoder, OutputStream, OutputStreamHandle, Sink, Source},
+    std::{io::Cursor, sync::Arc, time::Duration},
+    symphonia::{
+        core::{codecs::CODEC_TYPE_NULL, io::MediaSourceStream},
+        default::{get_codecs, get_probe},
+    },
     wasm_bindgen::prelude::*,
     web_sys::{console, window},
 };
@@ -15,6 +22,69 @@ pub fn main_js() -> Result<(), JsValue> {
     Ok(())
 }
 
+/// File extensions this build can decode, Opus is not among them since
+/// libopus is not built for wasm.
+#[wasm_bindgen]
+pub fn supported_formats() -> Vec<String> {
+    formats::supported_formats()
+        .into_iter()
+        .map(String::from)
+        .collect()
+}
+
+/// What is being decoded, as reported by the container and codec.
+#[wasm_bindgen(getter_with_clone)]
+#[derive(Clone)]
+pub struct StreamInfo {
+    pub codec: String,
+    pub sample_rate: Option<u32>,
+    pub bit_depth: Option<u32>,
+    pub channels: Option<u16>,
+    // Averaged over the whole file, in bits per second.
+    pub bitrate: Option<u32>,
+    // In seconds.
+    pub duration: Option<f64>,
+}
+
+impl StreamInfo {
+    fn probe(data: Arc<[u8]>) -> Option<Self> {
+        let len = data.len() as u64;
+        let mss = MediaSourceStream::new(Box::new(Cursor::new(data)), Default::default());
+        let probed = get_probe()
+            .format(
+                &Default::default(),
+                mss,
+                &Default::default(),
+                &Default::default(),
+            )
+            .ok()?;
+        let params = &probed
+            .format
+            .tracks()
+            .iter()
+            .find(|t| t.codec_params.codec != CODEC_TYPE_NULL)?
+            .codec_params;
+
+        let info =
+            formats::StreamInfo::new(params, get_codecs(), formats::duration(params), Some(len));
+        Some(info.into())
+    }
+}
+
+impl From<formats::StreamInfo> for StreamInfo {
+    #[inline]
+    fn from(info: formats::StreamInfo) -> Self {
+        Self {
+            codec: info.codec.to_owned(),
+            sample_rate: info.sample_rate,
+            bit_depth: info.bit_depth,
+            channels: info.channels,
+            bitrate: info.bitrate,
+            duration: info.duration.map(|duration| duration.as_secs_f64()),
+        }
+    }
+}
+
 enum Status {
     Playing(f64, f64),
     Stopped(f64),
@@ -27,7 +97,8 @@ fn now() -> f64 {
 
 #[inline]
 fn elapsed(old: f64) -> f64 {
-    now() - old
+    // `old` is in the future while a seek is fading out.
+    (now() - old).max(0.)
 }
 
 impl Status {
@@ -44,10 +115,11 @@ impl Status {
         }
     }
 
+    /// The track keeps playing for `ramp` while it fades out.
     #[inline]
-    fn stop(&mut self, speed: f64) {
+    fn stop(&mut self, speed: f64, ramp: f64) {
         if let Status::Playing(start, extra) = *self {
-            *self = Status::Stopped(elapsed(start) * speed + extra)
+            *self = Status::Stopped(elapsed(start) * speed + extra + ramp)
         }
     }
 
@@ -70,12 +142,13 @@ impl Status {
         }
     }
 
+    /// The jump happens after `delay`, once the old position faded out.
     #[inline]
-    fn seek(&mut self, pos: f64) {
+    fn seek(&mut self, pos: f64, delay: f64) {
         match self {
             Status::Stopped(d) => *d = pos,
             Status::Playing(start, extra) => {
-                *start = now();
+                *start = now() + delay;
                 *extra = pos;
             }
         }
@@ -86,8 +159,16 @@ impl Status {
 pub struct Player {
     speed: f64,
     volume: f32,
+    crossfade: Duration,
+    curve: Curve,
+    // Fade length of pause, resume, stop and seek.
+    ramp: Duration,
     status: Status,
     sink: Option<Sink>,
+    fader: Arc<Fader>,
+    // Previous tracks while they fade out, they end by themselves.
+    fading: Vec<Sink>,
+    info: Option<StreamInfo>,
     #[allow(dead_code)]
     stream: OutputStream,
     handle: OutputStreamHandle,
@@ -108,23 +189,64 @@ impl Player {
         Self {
             speed: 1.,
             volume: 0.,
+            crossfade: Duration::ZERO,
+            curve: Curve::Linear,
+            ramp: Duration::ZERO,
             status: Status::new(),
             sink: None,
+            fader: Fader::new(1.),
+            fading: Vec::new(),
+            info: None,
             stream,
             handle,
         }
     }
 
+    /// Starts at `seek` seconds when given, the track is seeked before its
+    /// first sample.
     #[wasm_bindgen]
-    pub fn load(&mut self, data: &[u8], play: bool, _seek: Option<f64>) -> bool {
+    pub fn load(&mut self, data: &[u8], play: bool, seek: Option<f64>) -> bool {
+        let crossfade = !self.crossfade.is_zero()
+            && play
+            && matches!(self.sink, Some(ref sink) if !sink.is_paused() && !sink.empty())
+            && !self.fader.is_held();
+        if crossfade {
+            self.fader.fade_out(self.crossfade, self.curve);
+            self.fading.extend(self.sink.take());
+        }
         self.stop();
 
         if let Ok(sink) = Sink::try_new(&self.handle) {
             sink.set_speed(self.speed as f32);
             sink.set_volume(self.volume);
-            let cur = Cursor::new(data.to_owned());
-            let decoder = Decoder::new(cur).unwrap();
-            sink.append(decoder);
+            // One copy, shared with the probe.
+            let data: Arc<[u8]> = data.into();
+            let decoder = match Decoder::new(Cursor::new(data.clone())) {
+                Ok(decoder) => decoder,
+                Err(_) => return false,
+            };
+            // rodio does not expose what it decodes.
+            self.info = StreamInfo::probe(data);
+            let fader = match crossfade {
+                true => {
+                    let fader = Fader::new(0.);
+                    fader.fade_to(1., self.crossfade, self.curve);
+                    fader
+                }
+                false => Fader::new(1.),
+            };
+            let mut source = Fade::new(decoder, fader.clone());
+            let pos = seek
+                .and_then(|seek| Duration::try_from_secs_f64(seek).ok())
+                .unwrap_or_default();
+            // It starts over when seeking fails.
+            let pos = match pos.is_zero() || source.try_seek(pos).is_ok() {
+                true => pos,
+                false => Duration::ZERO,
+            };
+            sink.append(source);
+            self.status.seek(pos.as_secs_f64(), 0.);
+            self.fader = fader;
             if play {
                 self.status.play();
             } else {
@@ -144,6 +266,8 @@ impl Player {
                 return false;
             } else {
                 sink.play();
+                self.fader.release(self.ramp);
+                self.fading.iter().for_each(Sink::play);
                 self.status.play()
             }
         }
@@ -153,19 +277,36 @@ impl Player {
     #[wasm_bindgen]
     pub fn pause(&mut self) {
         if let Some(ref sink) = self.sink {
-            sink.pause();
-            self.status.stop(self.speed);
+            if self.ramp.is_zero() {
+                sink.pause();
+            } else {
+                self.fader.hold(self.ramp);
+            }
+            self.fading.iter().for_each(Sink::pause);
+            self.status.stop(self.speed, self.ramp.as_secs_f64());
         }
     }
 
     #[wasm_bindgen]
     pub fn stop(&mut self) {
+        let audible =
+            matches!(self.sink, Some(ref sink) if !sink.is_paused()) && !self.fader.is_held();
+        if !self.ramp.is_zero() && audible {
+            self.fader.fade_out(self.ramp, Curve::Linear);
+            self.fading.extend(self.sink.take());
+        }
+        self.fading.retain(|sink| !sink.empty());
         self.sink = None;
+        self.fader = Fader::new(1.);
+        self.info = None;
         self.status.reset()
     }
 
     #[wasm_bindgen]
     pub fn set_speed(&mut self, speed: f64) {
+        self.fading
+            .iter()
+            .for_each(|sink| sink.set_speed(speed as f32));
         if let Some(ref sink) = self.sink {
             sink.set_speed(speed as f32);
             self.status.store(self.speed);
@@ -175,12 +316,24 @@ impl Player {
 
     #[wasm_bindgen]
     pub fn set_volume(&mut self, level: f32) {
+        self.fading.iter().for_each(|sink| sink.set_volume(level));
         if let Some(ref sink) = self.sink {
             sink.set_volume(level);
         }
         self.volume = level;
     }
 
+    #[wasm_bindgen]
+    pub fn set_ramp(&mut self, duration: f64) {
+        self.ramp = Duration::try_from_secs_f64(duration).unwrap_or_default();
+    }
+
+    #[wasm_bindgen]
+    pub fn set_crossfade(&mut self, duration: f64, curve: &str) {
+        self.crossfade = Duration::try_from_secs_f64(duration).unwrap_or_default();
+        self.curve = Curve::from(curve);
+    }
+
     #[wasm_bindgen]
     pub fn empty(&self) -> bool {
         if let Some(ref sink) = self.sink {
@@ -192,15 +345,45 @@ impl Player {
 
     #[wasm_bindgen]
     pub fn position(&self) -> f64 {
-        self.status.elapsed(self.speed)
+        // The wall clock is only used until the first sample was played.
+        self.fader
+            .position()
+            .unwrap_or_else(|| self.status.elapsed(self.speed))
+    }
+
+    /// In seconds, `undefined` when nothing is loaded or it is unknown.
+    #[wasm_bindgen]
+    pub fn duration(&self) -> Option<f64> {
+        self.info.as_ref().and_then(|info| info.duration)
+    }
+
+    #[wasm_bindgen]
+    pub fn stream_info(&self) -> Option<StreamInfo> {
+        self.info.clone()
     }
 
     #[wasm_bindgen]
     pub fn seek(&mut self, offset: f64) {
+        self.seek_to(self.position() + offset);
+    }
+
+    /// Seeks to `seconds` from the start of the track, kept within it.
+    #[wasm_bindgen]
+    pub fn seek_to(&mut self, seconds: f64) {
+        let seconds = match self.duration() {
+            Some(duration) => seconds.min(duration),
+            None => seconds,
+        };
+        // Also turns NaN into the start.
+        let seconds = seconds.max(0.);
         if let Some(ref sink) = self.sink {
-            if let Ok(pos) = Duration::try_from_secs_f64(self.position() + offset) {
-                if let Ok(_) = sink.try_seek(pos) {
-                    self.status.seek(pos.as_secs_f64());
+            if let Ok(pos) = Duration::try_from_secs_f64(seconds) {
+                if !self.ramp.is_zero() {
+                    self.fader.seek(pos, self.ramp);
+                    let delay = self.ramp.as_secs_f64() / self.speed;
+                    self.status.seek(pos.as_secs_f64(), delay);
+                } else if sink.try_seek(pos).is_ok() {
+                    self.status.seek(pos.as_secs_f64(), 0.);
                 }
             }
         }
@@ -1,6 +1,6 @@
 import { IPC, MultiStepInput, STATE, likeMusic } from "../utils/index.js";
 import { QueueItemTreeItem, QueueProvider } from "../treeview/index.js";
-import { SPEED_KEY, VOLUME_KEY } from "../constant/index.js";
+import { DEVICE_KEY, SPEED_KEY, VOLUME_KEY } from "../constant/index.js";
 import { BUTTON_MANAGER } from "../manager/index.js";
 import type { ExtensionContext } from "vscode";
 import { commands } from "vscode";
@@ -74,6 +74,28 @@ export function initCommand(context: ExtensionContext): void {
         }),
     ),
 
+    commands.registerCommand(
+      "cloudmusic.device",
+      () =>
+        void MultiStepInput.run(async (input) => {
+          const chosen = context.globalState.get(DEVICE_KEY, "");
+          const devices = await IPC.devices();
+          const { name } = await input.showQuickPick({
+            title: i18n.word.device,
+            step: 1,
+            totalSteps: 1,
+            items: devices.map(({ name, isDefault }) => ({
+              label: `${name === chosen ? "$(check) " : ""}${name}`,
+              description: isDefault ? i18n.word.default : undefined,
+              name,
+            })),
+          });
+          IPC.device(name);
+          await context.globalState.update(DEVICE_KEY, name);
+          return input.stay();
+        }),
+    ),
+
     commands.registerCommand("cloudmusic.toggleButton", () => BUTTON_MANAGER.toggle()),
   );
 }
@@ -3,6 +3,7 @@ import { AccountViewProvider, IPC, LyricType, STATE, defaultLyric } from "../uti
 import {
   CONF,
   COOKIE_KEY,
+  DEVICE_KEY,
   FOREIGN,
   HTTPS_API,
   MUSIC_CACHE_SIZE,
@@ -113,8 +114,14 @@ export async function initIPC(context: ExtensionContext): Promise<void> {
         return void commands.executeCommand("cloudmusic.previous");
       case IPCPlayer.speed:
         return BUTTON_MANAGER.buttonSpeed(data.speed);
+      case IPCPlayer.repeat:
+        return IPC.repeat(data.r);
+      case IPCPlayer.devices:
+        return IPC.devicesRequest?.(data.devices);
       case IPCQueue.fm:
         return (STATE.fmUid = data.uid);
+      case IPCQueue.random:
+        return IPC.random();
       case IPCWasm.load:
         return AccountViewProvider.wasmLoad(data.path, data.play, data.seek);
       case IPCWasm.pause:
@@ -163,6 +170,7 @@ export async function initIPC(context: ExtensionContext): Promise<void> {
         CM_NATIVE_MODULE: NATIVE_MODULE,
         CM_VOLUME: context.globalState.get(VOLUME_KEY, 85).toString(),
         CM_SPEED: context.globalState.get(SPEED_KEY, 1).toString(),
+        CM_DEVICE: context.globalState.get(DEVICE_KEY, ""),
         CM_WASM: STATE.wasm ? "1" : "0",
         CM_MUSIC_QUALITY: MUSIC_QUALITY(conf).toString(),
         CM_MUSIC_CACHE_SIZE: MUSIC_CACHE_SIZE(conf).toString(),
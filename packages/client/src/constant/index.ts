@@ -30,6 +30,7 @@ export const LYRIC_CACHE_KEY = "lyric-cache-v6";
 export const COOKIE_KEY = "cookie-v5";
 export const BUTTON_KEY = "button-v2";
 export const SPEED_KEY = "speed";
+export const DEVICE_KEY = "device";
 export const VOLUME_KEY = "volume";
 export const LYRIC_KEY = "lyric-v3";
 export const LOCAL_FOLDER_KEY = "local-folder-v2";
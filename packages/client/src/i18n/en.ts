@@ -77,6 +77,7 @@ export default {
     descending: "Descending",
     description: "Description",
     detail: "Detail",
+    device: "Output device",
     disable: "Disable",
     // disabled: "Disabled",
     download: "Download",
@@ -77,6 +77,7 @@ export default {
     descending: "降序",
     description: "描述",
     detail: "详情",
+    device: "输出设备",
     disable: "禁用",
     // disabled: "已禁用",
     download: "下载",
@@ -77,6 +77,7 @@ export default {
     descending: "降序",
     description: "描述",
     detail: "詳情",
+    device: "輸出裝置",
     disable: "禁用",
     // disabled: "已禁用",
     download: "下載",
@@ -96,6 +96,9 @@ let _nextChann = 0;
 export const IPC = {
   requestPool: <CSConnPool>new Map(),
 
+  // Resolves the pending `devices` call.
+  devicesRequest: <((devices: { name: string; isDefault: boolean }[]) => void) | undefined>undefined,
+
   connect: (
     ipcHandler: Parameters<typeof ipc.connect>[0],
     ipcBHandler: Parameters<typeof ipcB.connect>[0],
@@ -148,12 +151,21 @@ export const IPC = {
   lyricDelay: (delay: number) => ipc.send({ t: IPCPlayer.lyricDelay, delay }),
   playing: (playing: boolean) => ipc.send({ t: IPCPlayer.playing, playing }),
   position: (pos: number) => ipc.send({ t: IPCPlayer.position, pos }),
-  repeat: (r: boolean) => ipcB.send({ t: IPCPlayer.repeat, r }),
+  repeat: (r: boolean) => {
+    ipcB.send({ t: IPCPlayer.repeat, r });
+    ipc.send({ t: IPCPlayer.repeat, r });
+  },
   stop: () => ipc.send({ t: IPCPlayer.stop }),
   toggle: () => ipc.send({ t: IPCPlayer.toggle }),
   volume: (level: number) => ipc.send({ t: IPCPlayer.volume, level }),
   speed: (speed: number) => ipc.send({ t: IPCPlayer.speed, speed }),
   seek: (seekOffset: number) => ipc.send({ t: IPCPlayer.seek, seekOffset }),
+  device: (name: string) => ipc.send({ t: IPCPlayer.device, name }),
+  devices: () =>
+    new Promise<{ name: string; isDefault: boolean }[]>((resolve) => {
+      IPC.devicesRequest = resolve;
+      ipc.send({ t: IPCPlayer.devices });
+    }),
   add: (items: readonly PlayTreeItemData[], index?: number) => ipcB.send({ t: IPCQueue.add, items, index }),
   clear: () => ipcB.send({ t: IPCQueue.clear }),
   delete: (id: number | string) => ipcB.send({ t: IPCQueue.delete, id }),
@@ -15,5 +15,6 @@ export const TMP_DIR = resolve(SETTING_DIR, "tmp");
 export const CACHE_DIR = resolve(SETTING_DIR, "cache");
 export const MUSIC_CACHE_DIR = resolve(CACHE_DIR, "music");
 export const LYRIC_CACHE_DIR = resolve(CACHE_DIR, "lyric");
+export const COVER_CACHE_DIR = resolve(CACHE_DIR, "cover");
 
 export const RETAIN_FILE = resolve(SETTING_DIR, "retain");
@@ -1,33 +1,150 @@
-import { IPCPlayer, IPCWasm } from "@cloudmusic/shared";
-import { getMusicPath, getMusicPathClean, logError } from "./utils.js";
+import { IPCPlayer, IPCQueue, IPCWasm } from "@cloudmusic/shared";
+import { DOWNLOAD_HOOKS, getMusicPath, getMusicPathClean, logError } from "./utils.js";
 import type { IPCClientLoadMsg } from "@cloudmusic/shared";
 import { IPC_SRV } from "./server.js";
 import type { NeteaseTypings } from "api";
 import { STATE } from "./state.js";
-import { TMP_DIR } from "./constant.js";
+import { COVER_CACHE_DIR, TMP_DIR } from "./constant.js";
 import { fileURLToPath } from "node:url";
 import { lyric } from "./api/netease/song.js";
-import { resolve } from "node:path";
+import { basename, extname, resolve } from "node:path";
+import { existsSync } from "node:fs";
 import { rm } from "node:fs/promises";
 import { scrobble } from "./api/netease/account.js";
 
 type NativePlayerHdl = unknown;
 type NativeMediaSessionHdl = unknown;
 
+type NativePlayerState = "idle" | "loading" | "playing" | "paused" | "ended" | "error";
+
+type NativeErrorKind =
+  | "NotFound"
+  | "Unsupported"
+  | "Decode"
+  | "Io"
+  | "Network"
+  | "Http"
+  | "NoDevice"
+  | "Device"
+  | "InvalidArgument"
+  | "Cancelled"
+  | "Panic";
+
+/** Thrown by every native export. */
+type NativeError = Error & { kind: NativeErrorKind };
+
+/**
+ * Thrown by the media session exports, `Disconnected` ones are fixed by `mediaSessionRecreate`.
+ * `Incompatible` ones are permanent, the helper does not speak this protocol version.
+ */
+type NativeMediaError = Error & {
+  kind: "Unavailable" | "Disconnected" | "Incompatible" | "Platform" | "InvalidArgument";
+};
+
+type NativePlayerEvent =
+  | { type: "trackChanged" }
+  | { type: "deviceChanged"; paused: boolean }
+  | { type: "ended" }
+  | { type: "position"; position: number }
+  | { type: "error"; message: string; kind: NativeErrorKind }
+  | { type: "stateChanged"; state: NativePlayerState };
+
+type NativeMetadata = {
+  title?: string;
+  artist?: string;
+  album?: string;
+  albumArtist?: string;
+  track?: number;
+  trackTotal?: number;
+  disc?: number;
+  discTotal?: number;
+  year?: number;
+  genre?: string;
+  duration?: number;
+  lyrics?: string;
+  /** `file://` URL of the cached embedded artwork. */
+  cover?: string;
+};
+
+type NativeStreamInfo = {
+  codec: string;
+  sampleRate?: number;
+  bitDepth?: number;
+  channels?: number;
+  /** Averaged over the whole file, in bits per second. */
+  bitrate?: number;
+  duration?: number;
+};
+
+type NativeEqualizerBand = {
+  type?: "peaking" | "lowShelf" | "highShelf";
+  frequency: number;
+  gain: number;
+  q: number;
+};
+
+type NativeMediaEvent =
+  | { type: "next" | "previous" | "raise" | "quit" }
+  // `handled` once the attached player got it.
+  | { type: "play" | "pause" | "toggle" | "stop"; handled?: true }
+  | { type: "seek"; direction: "forward" | "backward"; handled?: true }
+  | { type: "seekBy"; direction: "forward" | "backward"; offset: number; handled?: true }
+  | { type: "setPosition"; position: number; handled?: true }
+  | { type: "setVolume"; volume: number }
+  | { type: "openUri"; uri: string }
+  // MPRIS only.
+  | { type: "setRate"; rate: number }
+  | { type: "setShuffle"; shuffle: boolean }
+  | { type: "setLoopStatus"; loopStatus: "None" | "Track" | "Playlist" };
+
+// MPRIS only, ignored on other platforms.
+type NativeMediaProperties = {
+  rate?: number;
+  volume?: number;
+  shuffle?: boolean;
+  loopStatus?: "None" | "Track" | "Playlist";
+  canGoNext?: boolean;
+  canGoPrevious?: boolean;
+};
+
 interface NativeModule {
+  downloadFinish(path: string, success: boolean): void;
+  downloadStart(path: string, total?: number): void;
+
+  readMetadata(path: string, coverDir?: string): Promise<NativeMetadata>;
+
+  playerDuration(player: NativePlayerHdl): number | undefined;
   playerEmpty(player: NativePlayerHdl): boolean;
-  playerLoad(player: NativePlayerHdl, url: string, play: boolean): boolean;
-  playerNew(): NativePlayerHdl;
+  playerEnqueue(player: NativePlayerHdl, url: string): Promise<void>;
+  playerImportAutoEq(player: NativePlayerHdl, path: string): void;
+  playerListDevices(): { name: string; isDefault: boolean }[];
+  playerLoad(player: NativePlayerHdl, url: string, play: boolean, seek?: number): Promise<void>;
+  playerNew(handler?: (event: NativePlayerEvent) => void, interval?: number): NativePlayerHdl;
   playerPause(player: NativePlayerHdl): void;
   playerPlay(player: NativePlayerHdl): boolean;
   playerPosition(player: NativePlayerHdl): number;
+  playerSetCrossfade(player: NativePlayerHdl, duration: number, curve: "linear" | "equalPower"): void;
+  playerSetDevice(player: NativePlayerHdl, name: string): Promise<void>;
+  playerSetEqualizer(player: NativePlayerHdl, settings: string | NativeEqualizerBand[], preamp?: number): void;
+  playerSetFallback(player: NativePlayerHdl, fallback: "migrate" | "pause"): void;
+  playerSetRamp(player: NativePlayerHdl, duration: number): void;
+  playerSetReplayGain(player: NativePlayerHdl, mode: "off" | "track" | "album", preamp: number): void;
   playerSetVolume(player: NativePlayerHdl, level: number): void;
   playerSetSpeed(player: NativePlayerHdl, speed: number): void;
+  playerState(player: NativePlayerHdl): NativePlayerState;
   playerStop(player: NativePlayerHdl): void;
+  playerStreamInfo(player: NativePlayerHdl): NativeStreamInfo | undefined;
+  playerSubscribe(player: NativePlayerHdl, handler: (event: NativePlayerEvent) => void, interval?: number): void;
+  playerSupportedFormats(): string[];
+  playerUnsubscribe(player: NativePlayerHdl): void;
   playerSeek(player: NativePlayerHdl, seekOffset: number): void;
+  playerSeekTo(player: NativePlayerHdl, seconds: number): void;
 
   // mediaSessionHwnd(pid: string): string;
-  mediaSessionNew(handler: (type: number) => void, path: string): NativeMediaSessionHdl;
+  mediaSessionAttach(mediaSession: NativeMediaSessionHdl, player: NativePlayerHdl): void;
+  mediaSessionNew(handler: (event: NativeMediaEvent) => void, path: string): NativeMediaSessionHdl;
+  mediaSessionRecreate(mediaSession: NativeMediaSessionHdl): void;
+  mediaSessionSeeked(mediaSession: NativeMediaSessionHdl, position: number): void;
   mediaSessionSetMetadata(
     mediaSession: NativeMediaSessionHdl,
     title: string,
@@ -37,8 +154,15 @@ interface NativeModule {
     duration: number,
   ): void;
   mediaSessionSetPlayback(mediaSession: NativeMediaSessionHdl, playing: boolean, position: number): void;
+  mediaSessionSetProperties(mediaSession: NativeMediaSessionHdl, properties: NativeMediaProperties): void;
 }
 
+// Seconds, for media keys that seek without saying how far.
+const SEEK_STEP = 10;
+
+// Cached songs are named after their title, only these extensions say what a file holds.
+const AUDIO_EXTENSIONS = new Set("aac aiff ape flac m4a mp3 mp4 oga ogg opus wav wma".split(" "));
+
 function prefetch(next: { id?: number; name?: string }): void {
   const { id, name } = next || {};
   if (!id || !name) return;
@@ -111,12 +235,16 @@ abstract class PlayerBase {
 
     try {
       const path = "url" in data && data.url ? data.url : await this._getPath(data.item.id, data.item.name);
+      if (this._supports && !this._supports(path)) throw Error(`Unsupported format: ${path}`);
       this.#loadtime = loadtime;
-      this._load(path, data.play, data.item, data.seek);
+      await this._load(path, data.play, data.item, data.seek);
     } catch (err) {
       logError(err);
       return IPC_SRV.sendToMaster({ t: IPCPlayer.end, fail: true });
     }
+    // Another track was loaded while this one was opening.
+    if (this.#loadtime !== loadtime) return;
+    this._enqueue?.(data.next);
 
     if (data.item.id) {
       lyric(data.item.id)
@@ -147,8 +275,18 @@ abstract class PlayerBase {
   abstract speed(speed: number): void;
   abstract volume(level: number): void;
   abstract seek(seekOffset: number): void;
-  protected abstract _load(path: string, play: boolean, item: NeteaseTypings.SongsItem, seek?: number): void;
+  abstract device(name: string): void;
+  abstract devices(): { name: string; isDefault: boolean }[];
+  repeat?(r: boolean): void;
+  protected abstract _load(
+    path: string,
+    play: boolean,
+    item: NeteaseTypings.SongsItem,
+    seek?: number,
+  ): void | Promise<void>;
   protected abstract _loaded?(): void; // WASM is sent from webview
+  protected abstract _supports?(path: string): boolean;
+  protected abstract _enqueue?(next?: { id: number; name: string }): void;
   protected abstract _setPlaying?(playing: boolean): void;
   protected abstract wasmOpen?(): void;
 }
@@ -160,6 +298,10 @@ class WasmPlayer extends PlayerBase {
 
   protected readonly _setPlaying = undefined;
 
+  protected readonly _supports = undefined;
+
+  protected readonly _enqueue = undefined;
+
   pause() {
     IPC_SRV.sendToMaster({ t: IPCWasm.pause });
   }
@@ -184,6 +326,14 @@ class WasmPlayer extends PlayerBase {
     IPC_SRV.sendToMaster({ t: IPCWasm.seek, seekOffset });
   }
 
+  device() {
+    // The webview plays on whatever output the browser chose.
+  }
+
+  devices() {
+    return [];
+  }
+
   wasmOpen() {
     setTimeout(() => IPC_SRV.sendToMaster({ t: IPCPlayer.end, pause: !this.playing, reloadNseek: this.lastPos }), 1024);
   }
@@ -196,14 +346,36 @@ class WasmPlayer extends PlayerBase {
 class NativePlayer extends PlayerBase {
   readonly wasmOpen = undefined;
 
-  protected readonly _getPath = getMusicPath;
+  // The track that already started after the previous one is not opened again.
+  protected readonly _getPath = (id: number, name: string) =>
+    this.#started?.id === id ? Promise.resolve(this.#started.path) : getMusicPath(id, name);
+
+  protected readonly _setPlaying = undefined;
 
   readonly #native: NativeModule;
 
   readonly #player: NativePlayerHdl;
 
+  readonly #formats: ReadonlySet<string>;
+
   readonly #mediaSession: NativeMediaSessionHdl;
 
+  // Set again after the media controls are recreated.
+  #metadata?: Parameters<NativeModule["mediaSessionSetMetadata"]>;
+
+  #properties: NativeMediaProperties = {};
+
+  #mediaIncompatible = false;
+
+  #repeat = false;
+
+  // Bumped by every `_enqueue`, a slower earlier one is dropped.
+  #enqueues = 0;
+
+  #enqueued?: { id: number; path: string };
+
+  #started?: { id: number; path: string };
+
   constructor() {
     super();
     const module = <string>process.env["CM_NATIVE_MODULE"];
@@ -211,53 +383,114 @@ class NativePlayer extends PlayerBase {
 
     // eslint-disable-next-line @typescript-eslint/no-var-requires
     this.#native = <NativeModule>require(buildPath);
-    this.#player = this.#native.playerNew();
+    this.#formats = new Set(this.supportedFormats);
+    this.#player = this.#native.playerNew((event) => {
+      switch (event.type) {
+        case "ended":
+          if (!this.playing) return;
+          this.playing = false;
+          return IPC_SRV.sendToMaster({ t: IPCPlayer.end });
+        case "position":
+          return posHandler(event.position);
+        case "trackChanged":
+          // The queue moves on to the track that is already playing.
+          this.#started = this.#enqueued;
+          this.#enqueued = undefined;
+          return IPC_SRV.sendToMaster({ t: IPCPlayer.next });
+        case "deviceChanged":
+          if (event.paused) this.playing = false;
+          return;
+        case "error": {
+          const codec = this.streamInfo?.codec;
+          return logError(`${event.kind}: ${event.message}${codec ? ` (${codec})` : ""}`);
+        }
+        case "stateChanged":
+          // Media keys act on the player without JS.
+          if (event.state === "playing" || event.state === "paused") this.playing = event.state === "playing";
+          else if (event.state === "idle") this.playing = false;
+          return;
+      }
+    }, 0.8);
+    DOWNLOAD_HOOKS.start = (path, total) => this.#native.downloadStart(path, total);
+    DOWNLOAD_HOOKS.finish = (path, success) => this.#native.downloadFinish(path, success);
     const volume = parseInt(process.env["CM_VOLUME"] || "85", 10);
     const speed = parseFloat(process.env["CM_SPEED"] || "1");
     this.#native.playerSetVolume(this.#player, volume);
     this.#native.playerSetSpeed(this.#player, speed);
+    const device = process.env["CM_DEVICE"];
+    if (device) this.device(device);
 
     /* let hwnd = "";
     if (process.platform === "win32" && pid)
       hwnd = this.#native.mediaSessionHwnd(pid);
     if (init || hwnd) {
     } */
-    const enum Type {
-      play,
-      pause,
-      toggle,
-      next,
-      previous,
-      stop,
-    }
+    // On Linux MPRIS is served in process, the helper only takes over when that fails.
+    const helperPath = buildPath.replace(".node", "-media");
+    const mediaPath = process.platform === "linux" && !existsSync(helperPath) ? "" : helperPath;
     this.#mediaSession = this.#native.mediaSessionNew(
-      (type: Type) => {
-        switch (type) {
-          case Type.play:
+      (event) => {
+        if ("handled" in event && event.handled) return;
+        switch (event.type) {
+          case "play":
             return this.play();
-          case Type.pause:
+          case "pause":
             return this.pause();
-          case Type.toggle:
+          case "toggle":
             return this.toggle();
-          case Type.next:
+          case "next":
             return IPC_SRV.sendToMaster({ t: IPCPlayer.next });
-          case Type.previous:
+          case "previous":
             return IPC_SRV.sendToMaster({ t: IPCPlayer.previous });
-          case Type.stop:
+          case "stop":
             return this.stop();
+          case "seek":
+            return this.seek(event.direction === "forward" ? SEEK_STEP : -SEEK_STEP);
+          case "seekBy":
+            return this.seek(event.direction === "forward" ? event.offset : -event.offset);
+          case "setPosition":
+            return this.seekTo(event.position);
+          case "setVolume": {
+            const level = Math.round(event.volume * 100);
+            this.volume(level);
+            return IPC_SRV.broadcast({ t: IPCPlayer.volume, level });
+          }
+          case "openUri": {
+            const path = event.uri.startsWith("file:") ? fileURLToPath(event.uri) : event.uri;
+            const item = <NeteaseTypings.SongsItem>{ id: 0, name: basename(path), dt: 0 };
+            return void this.load({ url: path, item, play: true }).catch(logError);
+          }
+          case "raise": // There is no window of our own to raise.
+            return;
+          case "quit":
+            this.stop();
+            return IPC_SRV.sendToMaster({ t: IPCPlayer.stop });
+          case "setRate":
+            this.speed(event.rate);
+            return IPC_SRV.broadcast({ t: IPCPlayer.speed, speed: event.rate });
+          case "setShuffle":
+            // The queue is shuffled once, there is no shuffle mode to keep.
+            this.#setProperties({ shuffle: false });
+            if (event.shuffle) IPC_SRV.sendToMaster({ t: IPCQueue.random });
+            return;
+          case "setLoopStatus":
+            return IPC_SRV.sendToMaster({ t: IPCPlayer.repeat, r: event.loopStatus !== "None" });
         }
       },
-      buildPath.replace(".node", "-media"),
+      mediaPath,
     );
+    // The playback state and position are pushed by the player itself.
+    this.#native.mediaSessionAttach(this.#mediaSession, this.#player);
+  }
 
-    setInterval(() => {
-      if (!this.playing) return;
-      if (this.#native.playerEmpty(this.#player)) {
-        this.playing = false;
-        return IPC_SRV.sendToMaster({ t: IPCPlayer.end });
-      }
-      posHandler(this.#native.playerPosition(this.#player));
-    }, 800);
+  /** Of the track that is playing, from the decoder rather than NetEase. */
+  get streamInfo(): NativeStreamInfo | undefined {
+    return this.#native.playerStreamInfo(this.#player);
+  }
+
+  /** File extensions the native decoder can play. */
+  get supportedFormats(): string[] {
+    return this.#native.playerSupportedFormats();
   }
 
   pause() {
@@ -272,6 +505,7 @@ class NativePlayer extends PlayerBase {
   stop() {
     this.#native.playerStop(this.#player);
     this.playing = false;
+    this.#enqueued = undefined;
   }
 
   speed(speed: number) {
@@ -280,33 +514,135 @@ class NativePlayer extends PlayerBase {
 
   volume(level: number) {
     this.#native.playerSetVolume(this.#player, level);
+    this.#setProperties({ volume: level / 100 });
   }
 
   seek(seekOffset: number) {
-    this.#native.playerSeek(this.#player, seekOffset);
+    this.seekTo(this.#native.playerPosition(this.#player) + seekOffset);
   }
 
-  protected _load(path: string, play: boolean, item: NeteaseTypings.SongsItem) {
-    if (!this.#native.playerLoad(this.#player, path, play)) throw Error(`Failed to load ${path}`);
+  /** Stays on the default device when `name` is not plugged in. */
+  device(name: string) {
+    if (!this.devices().some((device) => device.name === name)) return;
+    this.#native.playerSetDevice(this.#player, name).catch(logError);
+  }
+
+  devices() {
+    try {
+      return this.#native.playerListDevices();
+    } catch (err) {
+      logError(err);
+      return [];
+    }
+  }
+
+  seekTo(seconds: number) {
+    this.#native.playerSeekTo(this.#player, Math.max(0, seconds));
+  }
+
+  repeat(r: boolean) {
+    this.#repeat = r;
+    this.#setProperties({ loopStatus: r ? "Track" : "None" });
+  }
+
+  protected async _load(path: string, play: boolean, item: NeteaseTypings.SongsItem, seek?: number) {
+    const started = this.#started?.id === item.id && this.#started.path === path;
+    this.#started = undefined;
+    try {
+      if (!started) await this.#native.playerLoad(this.#player, path, play, seek);
+    } catch (err) {
+      const { kind, message } = <NativeError>err;
+      // Another track took over, which `load` notices by itself.
+      if (kind === "Cancelled") return;
+      throw Error(`Failed to load ${path}: ${kind} ${message}`);
+    }
     this.playing = play;
 
-    this.#native.mediaSessionSetMetadata(
+    const metadata: Parameters<NativeModule["mediaSessionSetMetadata"]> = [
       this.#mediaSession,
       item.name || "",
       item.al?.name || "",
       item.ar?.map(({ name }) => name).join("/") || "",
       item.al?.picUrl || "",
-      item.dt / 1000,
-    );
+      this.#native.playerDuration(this.#player) ?? item.dt / 1000,
+    ];
+    this.#metadata = metadata;
+    this.#media(() => this.#native.mediaSessionSetMetadata(...metadata));
+
+    // Embedded artwork also shows up offline and for local files, it replaces the cover once read.
+    if (!/^https?:/.test(path)) {
+      this.#native
+        .readMetadata(path, COVER_CACHE_DIR)
+        .then(({ cover }) => {
+          if (!cover || this.#metadata !== metadata) return;
+          metadata[4] = cover;
+          this.#media(() => this.#native.mediaSessionSetMetadata(...metadata));
+        })
+        .catch(() => undefined);
+    }
+  }
+
+  /** Sources that do not name an audio format are left to the decoder. */
+  protected _supports(path: string) {
+    const name = /^https?:/.test(path) ? new URL(path).pathname : path;
+    const ext = extname(name).slice(1).toLowerCase();
+    return !AUDIO_EXTENSIONS.has(ext) || this.#formats.has(ext);
+  }
+
+  /** Queues the next track right away, so it follows without a gap. */
+  protected _enqueue(next?: { id: number; name: string }) {
+    const enqueues = ++this.#enqueues;
+    this.#enqueued = undefined;
+    // Repeating loads this track again once it ended.
+    if (!next?.id || !next.name || this.#repeat) return;
+
+    const { id, name } = next;
+    this._getPath(id, name)
+      .then(async (path) => {
+        if (enqueues !== this.#enqueues || !this._supports(path)) return;
+        await this.#native.playerEnqueue(this.#player, path);
+        if (enqueues === this.#enqueues) this.#enqueued = { id, path };
+      })
+      .catch((err) => {
+        if ((<NativeError>err).kind !== "Cancelled") logError(err);
+      });
   }
 
   protected _loaded() {
     setTimeout(() => IPC_SRV.broadcast({ t: IPCPlayer.loaded }), 16);
   }
 
-  protected _setPlaying(playing: boolean) {
-    const pos = this.#native.playerPosition(this.#player);
-    this.#native.mediaSessionSetPlayback(this.#mediaSession, playing, pos);
+  #setProperties(properties: NativeMediaProperties) {
+    Object.assign(this.#properties, properties);
+    this.#media(() => this.#native.mediaSessionSetProperties(this.#mediaSession, properties));
+  }
+
+  /** Runs `update`, the media controls are recreated once their connection is gone. */
+  #media(update: () => void) {
+    try {
+      update();
+    } catch (err) {
+      const { kind, message } = <NativeMediaError>err;
+      if (kind === "Disconnected") this.#recreateMedia();
+      // Reported once, recreating would fail the same way.
+      else if (kind === "Incompatible") {
+        if (!this.#mediaIncompatible) logError(`Media session: ${kind} ${message}`);
+        this.#mediaIncompatible = true;
+      }
+      // There are no media controls on this system.
+      else if (kind !== "Unavailable") logError(`Media session: ${kind} ${message}`);
+    }
+  }
+
+  #recreateMedia() {
+    try {
+      this.#native.mediaSessionRecreate(this.#mediaSession);
+      if (this.#metadata) this.#native.mediaSessionSetMetadata(...this.#metadata);
+      this.#native.mediaSessionSetProperties(this.#mediaSession, this.#properties);
+    } catch (err) {
+      // The helper has no properties.
+      if ((<NativeMediaError>err).kind !== "Unavailable") logError(err);
+    }
   }
 }
 
@@ -178,6 +178,8 @@ class IPCServer {
         return void (PLAYER.playing = data.playing);
       case IPCPlayer.position:
         return posHandler(data.pos);
+      case IPCPlayer.repeat:
+        return PLAYER.repeat?.(data.r);
       case IPCPlayer.toggle:
         return PLAYER.toggle();
       case IPCPlayer.stop:
@@ -191,6 +193,10 @@ class IPCServer {
         return this.broadcast(data);
       case IPCPlayer.seek:
         return PLAYER.seek(data.seekOffset);
+      case IPCPlayer.device:
+        return PLAYER.device(data.name);
+      case IPCPlayer.devices:
+        return this.send(socket, { t: IPCPlayer.devices, devices: PLAYER.devices() });
       case IPCQueue.fm:
         return this.broadcast(data);
     }
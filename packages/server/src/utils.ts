@@ -18,6 +18,12 @@ export const logError = (err: unknown): void => {
 
 const gotConfig = <const>{ isStream: true, http2: true, timeout: { request: 80000 } };
 
+/** Lets the native player read a song while it is being downloaded. */
+export const DOWNLOAD_HOOKS: {
+  start?: (path: string, total?: number) => void;
+  finish?: (path: string, success: boolean) => void;
+} = {};
+
 export async function getMusicPath(id: number, name: string): Promise<string> {
   const idS = `${id}`;
   const cachaUrl = MUSIC_CACHE.get(idS);
@@ -26,10 +32,16 @@ export async function getMusicPath(id: number, name: string): Promise<string> {
   const { url, md5 } = await songUrl(id);
   if (!url) throw Error();
   const tmpUri = resolve(TMP_DIR, idS);
-  const download = got(url, gotConfig).once("end", () => void MUSIC_CACHE.put(idS, `${name}-${idS}`, tmpUri, md5));
+  const download = got(url, gotConfig)
+    .once("response", ({ headers }: { headers: Record<string, string | string[] | undefined> }) => {
+      const total = parseInt(`${headers["content-length"] ?? ""}`, 10);
+      DOWNLOAD_HOOKS.start?.(tmpUri, Number.isNaN(total) ? undefined : total);
+    })
+    .once("end", () => void MUSIC_CACHE.put(idS, `${name}-${idS}`, tmpUri, md5));
 
   return new Promise((resolve, reject) => {
-    const file = createWriteStream(tmpUri);
+    // Only flushed data can be read by the native player.
+    const file = createWriteStream(tmpUri).once("finish", () => DOWNLOAD_HOOKS.finish?.(tmpUri, true));
     let len = 0;
     const onData = ({ length }: { length: number }) => {
       len += length;
@@ -40,6 +52,7 @@ export async function getMusicPath(id: number, name: string): Promise<string> {
     };
     download
       .once("error", (err) => {
+        DOWNLOAD_HOOKS.finish?.(tmpUri, false);
         rm(tmpUri, { force: true }).catch(() => undefined);
         reject(err);
       })
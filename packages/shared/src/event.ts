@@ -37,6 +37,8 @@ export const enum IPCPlayer {
   previous = "215",
   speed = "216",
   seek = "217",
+  device = "218",
+  devices = "219",
 }
 
 // 3xx
@@ -49,6 +51,7 @@ export const enum IPCQueue {
   play = "305",
   new = "306",
   shift = "307",
+  random = "308",
 }
 
 // 4xx
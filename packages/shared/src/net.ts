@@ -41,11 +41,14 @@ export type IPCClientMsg =
   | IPCMsg<IPCPlayer.lyricDelay, { delay: number }>
   | IPCMsg<IPCPlayer.playing, { playing: boolean }>
   | IPCMsg<IPCPlayer.position, { pos: number }>
+  | IPCMsg<IPCPlayer.repeat, { r: boolean }>
   | IPCMsg<IPCPlayer.stop>
   | IPCMsg<IPCPlayer.toggle>
   | IPCMsg<IPCPlayer.volume, { level: number }>
   | IPCMsg<IPCPlayer.speed, { speed: number }>
   | IPCMsg<IPCPlayer.seek, { seekOffset: number }>
+  | IPCMsg<IPCPlayer.device, { name: string }>
+  | IPCMsg<IPCPlayer.devices>
   | IPCMsg<IPCQueue.fm, { uid: number }>;
 
 export type IPCServerMsg =
@@ -64,7 +67,10 @@ export type IPCServerMsg =
   | IPCMsg<IPCPlayer.next>
   | IPCMsg<IPCPlayer.previous>
   | IPCMsg<IPCPlayer.speed, { speed: number }>
+  | IPCMsg<IPCPlayer.repeat, { r: boolean }>
+  | IPCMsg<IPCPlayer.devices, { devices: { name: string; isDefault: boolean }[] }>
   | IPCMsg<IPCQueue.fm, { uid: number }>
+  | IPCMsg<IPCQueue.random>
   | IPCMsg<IPCWasm.load, { path: string; play: boolean; seek?: number }>
   | IPCMsg<IPCWasm.pause>
   | IPCMsg<IPCWasm.play>
@@ -21,10 +21,8 @@ const next = () => {
   vscode.postMessage(msg);
 };
 const seek = ({ action, seekTime, seekOffset }: MediaSessionActionDetails) => {
-  if (seekTime) {
-    const pos = Controller.position();
-    if (pos) Controller.seek(seekTime - pos);
-  } else if (seekOffset) Controller.seek(seekOffset);
+  if (seekTime !== undefined) Controller.seekTo(seekTime);
+  else if (seekOffset) Controller.seek(seekOffset);
   else {
     if (action === "seekbackward") Controller.seek(-15);
     else Controller.seek(15);
@@ -84,7 +82,7 @@ class WebAudioPlayer {
   async load(data: Uint8Array, play: boolean, seek?: number): Promise<boolean> {
     this._audio.src = URL.createObjectURL(new Blob([data.buffer]));
     try {
-      if (seek) this._audio.currentTime += seek;
+      if (seek) this._audio.currentTime = seek;
       if (play) await this._audio.play();
       return true;
     } catch {}
@@ -136,6 +134,11 @@ class WebAudioPlayer {
     // this._audio.fastSeek(seekOffset);
     this._audio.currentTime += seekOffset;
   }
+
+  // eslint-disable-next-line @typescript-eslint/naming-convention
+  seek_to(seconds: number): void {
+    this._audio.currentTime = seconds;
+  }
 }
 
 class Controller {
@@ -239,6 +242,13 @@ class Controller {
     }
   }
 
+  static seekTo(seconds: number) {
+    if (this._player) {
+      this._player.seek_to(seconds);
+      Controller.setStatus(this._player.position());
+    }
+  }
+
   static position() {
     return this._player?.position();
   }
@@ -62,7 +62,7 @@ for (const { name, base } of artifacts) {
   await Deno.mkdir(buildPath, { recursive: true });
   await Deno.copyFile(resolve(artifactPath, name), resolve(buildPath, name));
 
-  if (base.startsWith("darwin-")) {
+  if (base.startsWith("darwin-") || base.startsWith("linux-")) {
     const name = `${base}-media`;
     await Deno.copyFile(resolve(mediaArtifactPath, name), resolve(buildPath, name));
   }